@@ -19,16 +19,16 @@ impl Iterator for SquareImage {
     fn next(&mut self) -> Option<(i32, i32)> {
         let ret = (self.cur_x, self.cur_y);
 
-        self.cur_y = self.cur_y + 1;
+        self.cur_y += 1;
 
         if self.cur_y == self.size {
-            self.cur_x = self.cur_x + 1;
+            self.cur_x += 1;
             self.cur_y = 0;
         }
         if self.cur_x == self.size {
-            return None;
+            None
         } else {
-            return Some(ret);
+            Some(ret)
         }
     }
 }
@@ -59,7 +59,7 @@ fn calculate_pixel(x: i32, y: i32, step: f64, init_a: f64, init_b: f64) -> i32 {
         a = a2 - b2 + cr;
         k = i;
     }
-    return k;
+    k
 }
 
 struct CalculatePixelIterations {
@@ -67,19 +67,18 @@ struct CalculatePixelIterations {
 }
 impl InOut<(i32, i32), (i32, i32, i32)> for CalculatePixelIterations {
     fn process(&mut self, position: (i32, i32)) -> Option<(i32, i32, i32)> {
-        match position {
-            (x, y) => Some((
+        let (x, y) = position;
+        Some((
+            x,
+            y,
+            calculate_pixel(
                 x,
                 y,
-                calculate_pixel(
-                    x,
-                    y,
-                    self.params.step,
-                    self.params.init_a,
-                    self.params.init_b,
-                ),
-            )),
-        }
+                self.params.step,
+                self.params.init_a,
+                self.params.init_b,
+            ),
+        ))
     }
 }
 
@@ -87,30 +86,43 @@ struct Renderer {}
 impl In<(i32, i32, i32), (usize, usize, u8)> for Renderer {
     fn process(&mut self, data: (i32, i32, i32), _order: u64) -> (usize, usize, u8) {
         let iterations = 10000;
-        match data {
-            (x, y, k) => (
-                x as usize,
-                y as usize,
-                (255 as f64 - ((k as f64) * 255 as f64 / (iterations as f64))) as u8,
-            ),
-        }
+        let (x, y, k) = data;
+        (
+            x as usize,
+            y as usize,
+            (255_f64 - ((k as f64) * 255_f64 / (iterations as f64))) as u8,
+        )
     }
 }
 
-fn mandelbrot_rustspp(size: usize, threads: i32) {
+//Like lock_free!, but picks the queue at run time
+macro_rules! with_queue {
+    ($stage:expr, $queue:expr) => {{
+        let (config, factory) = $stage;
+        (
+            config.configure(|config| config.with_queue($queue)),
+            factory,
+        )
+    }};
+}
+
+fn mandelbrot_rustspp(size: usize, threads: i32, queue: QueueKind) {
     {
         let mut pipeline = pipeline![
-            parallel!(
-                CalculatePixelIterations {
-                    params: Parameters {
-                        init_a: -2.125,
-                        init_b: -1.5,
-                        step: 3.0 / (size as f64),
-                    }
-                },
-                threads
+            with_queue!(
+                parallel!(
+                    CalculatePixelIterations {
+                        params: Parameters {
+                            init_a: -2.125,
+                            init_b: -1.5,
+                            step: 3.0 / (size as f64),
+                        }
+                    },
+                    threads
+                ),
+                queue
             ),
-            sequential!(Renderer {})
+            with_queue!(sequential!(Renderer {}), queue)
         ];
 
         for i in 0..size {
@@ -118,7 +130,7 @@ fn mandelbrot_rustspp(size: usize, threads: i32) {
                 pipeline.post((i as i32, j as i32)).unwrap();
             }
         }
-        pipeline.end_and_wait();
+        pipeline.end_and_wait().unwrap();
     }
 }
 
@@ -154,7 +166,7 @@ fn mandelbrot_rayon(size: usize, thread_pool: Rc<rayon::ThreadPool>) {
                 let mut buf = buf.lock().unwrap();
                 let iterations = 10000;
                 buf[x as usize][y as usize] =
-                    (255 as f64 - ((k as f64) * 255 as f64 / (iterations as f64))) as u8;
+                    (255_f64 - ((k as f64) * 255_f64 / (iterations as f64))) as u8;
             });
     });
 }
@@ -168,15 +180,23 @@ fn criterion_benchmark(c: &mut Criterion) {
     group.throughput(criterion::Throughput::Elements(1000 * 1000));
     for threads in threads_to_run {
         group.bench_with_input(
-            &format!("rust_ssp {threads} worker threads"),
+            format!("rust_ssp {threads} worker threads"),
+            &threads,
+            |b, &threads| {
+                b.iter(|| mandelbrot_rustspp(1000, threads, QueueKind::Blocking));
+            },
+        );
+
+        group.bench_with_input(
+            format!("rust_ssp lock-free {threads} worker threads"),
             &threads,
             |b, &threads| {
-                b.iter(|| mandelbrot_rustspp(1000, threads));
+                b.iter(|| mandelbrot_rustspp(1000, threads, QueueKind::LockFree));
             },
         );
 
         group.bench_with_input(
-            &format!("rayon {threads} worker threads"),
+            format!("rayon {threads} worker threads"),
             &threads,
             |b, &threads| {
                 let pool = Rc::new(
@@ -1,5 +1,3 @@
-#![feature(async_closure)]
-
 #[macro_use]
 extern crate criterion;
 
@@ -11,15 +9,16 @@ use rust_spp::*;
 use std::rc::Rc;
 
 use futures::future::lazy;
+#[allow(dead_code)]
 struct ImageLine {
     line_index: usize,
     line_buffer: Vec<u8>,
 }
 
 fn render_line(size: usize, line: usize) -> Option<ImageLine> {
-    let init_a = -2.125 as f64;
-    let init_b = -1.5 as f64;
-    let range = 3.0 as f64;
+    let init_a = -2.125_f64;
+    let init_b = -1.5_f64;
+    let range = 3.0_f64;
     let step = range / (size as f64);
 
     let mut m: Vec<u8> = vec![0; size];
@@ -29,7 +28,7 @@ fn render_line(size: usize, line: usize) -> Option<ImageLine> {
     let im = init_b + (step * (i as f64));
     let iterations = 10000;
 
-    for j in 0..size {
+    for (j, pixel) in m.iter_mut().enumerate() {
         let mut a = init_a + step * j as f64;
         let cr = a;
 
@@ -46,12 +45,12 @@ fn render_line(size: usize, line: usize) -> Option<ImageLine> {
             a = a2 - b2 + cr;
             k = ii;
         }
-        m[j] = (255 as f64 - ((k as f64) * 255 as f64 / (iterations as f64))) as u8;
+        *pixel = (255_f64 - ((k as f64) * 255_f64 / (iterations as f64))) as u8;
     }
-    return Some(ImageLine {
-        line_index: line as usize,
+    Some(ImageLine {
+        line_index: line,
         line_buffer: m,
-    });
+    })
 }
 
 struct ComputeLine {
@@ -59,7 +58,7 @@ struct ComputeLine {
 }
 impl ComputeLine {
     fn new(size: usize) -> ComputeLine {
-        ComputeLine { size: size }
+        ComputeLine { size }
     }
 }
 impl InOut<usize, ImageLine> for ComputeLine {
@@ -68,6 +67,7 @@ impl InOut<usize, ImageLine> for ComputeLine {
     }
 }
 
+#[allow(dead_code)]
 struct RenderLine {}
 impl In<ImageLine, ImageLine> for RenderLine {
     fn process(&mut self, image_line: ImageLine, _order: u64) -> ImageLine {
@@ -75,9 +75,9 @@ impl In<ImageLine, ImageLine> for RenderLine {
     }
 }
 
+#[allow(dead_code)]
 fn mandelbrot_sequential(size: usize) -> Vec<Option<ImageLine>> {
     (0..size)
-        .into_iter()
         .map(|image_line| render_line(size, image_line))
         .collect()
 }
@@ -92,9 +92,9 @@ fn mandelbrot_rustspp(size: usize, threads: usize) {
     ];
 
     for i in 0..size {
-        pipeline.post(i as usize).unwrap();
+        pipeline.post(i).unwrap();
     }
-    let rendered_image = pipeline.collect();
+    let rendered_image = pipeline.collect().unwrap();
 
     let mut bytes = 0usize;
     for line in rendered_image {
@@ -110,9 +110,9 @@ fn mandelbrot_rustspp_ordered(size: usize, threads: usize) {
     ];
 
     for i in 0..size {
-        pipeline.post(i as usize).unwrap();
+        pipeline.post(i).unwrap();
     }
-    let lines = pipeline.collect();
+    let lines = pipeline.collect().unwrap();
     let mut bytes = 0usize;
     for line in lines {
         bytes += line.line_buffer.len()
@@ -144,7 +144,7 @@ fn mandelbrot_rayon(size: usize, thread_pool: Rc<rayon::ThreadPool>) -> Vec<Imag
             .map(|image_line| render_line(size, image_line).unwrap())
             .collect_into_vec(&mut b);
     });
-    return b;
+    b
 }
 
 #[tokio::main]
@@ -169,7 +169,7 @@ fn mandelbrot_benches(c: &mut Criterion) {
     group.sample_size(10);
     for threads in threads_to_run {
         group.bench_with_input(
-            &format!("rust_ssp unordered {threads} worker threads"),
+            format!("rust_ssp unordered {threads} worker threads"),
             &threads,
             |b, &threads| {
                 b.iter(|| mandelbrot_rustspp(1000, threads));
@@ -177,7 +177,7 @@ fn mandelbrot_benches(c: &mut Criterion) {
         );
 
         group.bench_with_input(
-            &format!("rust_ssp ordered {threads} worker threads"),
+            format!("rust_ssp ordered {threads} worker threads"),
             &threads,
             |b, &threads| {
                 b.iter(|| mandelbrot_rustspp_ordered(1000, threads));
@@ -185,12 +185,12 @@ fn mandelbrot_benches(c: &mut Criterion) {
         );
 
         group.bench_with_input(
-            &format!("rayon {threads} worker threads"),
+            format!("rayon {threads} worker threads"),
             &threads,
             |b, &threads| {
                 let pool = Rc::new(
                     ThreadPoolBuilder::new()
-                        .num_threads(threads as usize)
+                        .num_threads(threads)
                         .build()
                         .unwrap(),
                 );
@@ -199,7 +199,7 @@ fn mandelbrot_benches(c: &mut Criterion) {
         );
 
         group.bench_with_input(
-            &format!("mandelbrot tokio ordered {threads} worker threads"),
+            format!("mandelbrot tokio ordered {threads} worker threads"),
             &threads,
             |b, &threads| {
                 b.iter(|| mandelbrot_tokio(1000, threads));
@@ -207,7 +207,7 @@ fn mandelbrot_benches(c: &mut Criterion) {
         );
 
         group.bench_with_input(
-            &format!("mandelbrot tokio unordered {threads} worker threads"),
+            format!("mandelbrot tokio unordered {threads} worker threads"),
             &threads,
             |b, &threads| {
                 b.iter(|| mandelbrot_tokio_unordered(1000, threads));
@@ -0,0 +1,91 @@
+#[macro_use]
+extern crate criterion;
+
+use criterion::{BenchmarkId, Criterion, Throughput};
+use rust_spp::*;
+use std::thread;
+
+const ITEMS: u64 = 100_000;
+
+//Producers post ITEMS between them, consumers take them until they see
+//their Stop
+fn run(queue: WorkQueue<u64>, producers: u64, consumers: usize) {
+    thread::scope(|scope| {
+        for _ in 0..consumers {
+            let queue = queue.clone();
+            scope.spawn(move || loop {
+                if let TimestampedWorkItem(WorkItem::Stop, ..) = queue.wait_and_dequeue() {
+                    break;
+                }
+            });
+        }
+        let posting: Vec<_> = (0..producers)
+            .map(|_| {
+                let queue = queue.clone();
+                scope.spawn(move || {
+                    for item in 0..ITEMS / producers {
+                        queue.enqueue(WorkItem::Value(item));
+                    }
+                })
+            })
+            .collect();
+        for producer in posting {
+            producer.join().unwrap();
+        }
+        for _ in 0..consumers {
+            queue.enqueue(WorkItem::Stop);
+        }
+    });
+}
+
+fn queue_benches(c: &mut Criterion) {
+    let mut group = c.benchmark_group("queues");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(ITEMS));
+    let threads = num_cpus::get().max(2);
+    for capacity in [None, Some(1024)] {
+        for kind in [QueueKind::Blocking, QueueKind::LockFree] {
+            for (producers, consumers) in [(1, 1), (1, threads), (threads, threads)] {
+                let name = match capacity {
+                    Some(capacity) => format!("{kind:?} bounded {capacity}"),
+                    None => format!("{kind:?} unbounded"),
+                };
+                group.bench_with_input(
+                    BenchmarkId::new(name, format!("{producers} to {consumers}")),
+                    &(producers, consumers),
+                    |b, &(producers, consumers)| {
+                        b.iter(|| run(WorkQueue::new(kind, capacity), producers as u64, consumers));
+                    },
+                );
+            }
+        }
+    }
+    group.finish();
+}
+
+//A chain of sequential stages, where every link has one producer and one
+//consumer
+fn chain(kind: QueueKind) {
+    let stage = || {
+        let (config, factory) = sequential!(|item: u64| Some(item));
+        (config.configure(|config| config.with_queue(kind)), factory)
+    };
+    let pipeline = pipeline![stage(), stage(), stage(), stage(), collect!()];
+    for item in 0..ITEMS {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.collect().unwrap();
+}
+
+fn chain_benches(c: &mut Criterion) {
+    let mut group = c.benchmark_group("chain");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(ITEMS));
+    for kind in [QueueKind::Auto, QueueKind::Blocking, QueueKind::LockFree] {
+        group.bench_function(format!("{kind:?}"), |b| b.iter(|| chain(kind)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, queue_benches, chain_benches);
+criterion_main!(benches);
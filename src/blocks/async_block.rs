@@ -0,0 +1,319 @@
+use crate::blocks::*;
+use crate::work_storage::*;
+use std::collections::BTreeMap;
+use std::future::{poll_fn, Future};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use std::{marker::PhantomData, sync::Arc};
+use tokio::runtime::{Handle, RuntimeFlavor};
+
+// Public API: A Input-Output node that can await, such as loading a file or
+// calling a service. Wrap the stage with on_runtime! so its replicas run as
+// tasks on a tokio runtime instead of one thread each.
+pub trait AsyncInOut<TInput, TOutput> {
+    fn process(&mut self, input: TInput) -> impl Future<Output = Option<TOutput>> + Send;
+
+    //Same as InOut::flush
+    fn flush(&mut self) -> Vec<TOutput> {
+        vec![]
+    }
+}
+
+impl<TInput, TOutput, TFuture, F> AsyncInOut<TInput, TOutput> for F
+where
+    F: FnMut(TInput) -> TFuture,
+    TFuture: Future<Output = Option<TOutput>> + Send,
+{
+    fn process(&mut self, input: TInput) -> impl Future<Output = Option<TOutput>> + Send {
+        (*self)(input)
+    }
+}
+
+//Construction options for a stage that runs on a tokio runtime
+pub struct AsyncConfig<T> {
+    pub config: BlockConfig<T>,
+    pub runtime: Handle,
+}
+
+impl<T> AsyncConfig<T> {
+    //Replicas are spawned and left to run on their own, which a current
+    //thread runtime only does while something blocks on it, so only
+    //multi-threaded runtimes are accepted
+    pub fn new(config: impl Into<BlockConfig<T>>, runtime: Handle) -> AsyncConfig<T> {
+        assert!(
+            runtime.runtime_flavor() == RuntimeFlavor::MultiThread,
+            "async stages need a multi-threaded tokio runtime"
+        );
+        AsyncConfig {
+            config: config.into(),
+            runtime,
+        }
+    }
+
+    pub fn replicas(&self) -> usize {
+        self.config.replicas()
+    }
+}
+
+impl<T> Configure<T> for AsyncConfig<T> {
+    type Config = AsyncConfig<T>;
+
+    fn configure(self, change: impl FnOnce(BlockConfig<T>) -> BlockConfig<T>) -> AsyncConfig<T> {
+        AsyncConfig {
+            config: change(self.config),
+            runtime: self.runtime,
+        }
+    }
+}
+
+//Internals: Processing queue for async blocks. Replicas are tasks that
+//wait on the input queue without holding a thread. Sequential ordered
+//stages put their input back in order themselves.
+pub struct AsyncInOutBlock<TInput, TOutput, TCollected, TStage, TFactory, TNextStep> {
+    input: Arc<BlockInput<TInput>>,
+    next_step: Arc<TNextStep>,
+    transformer_factory: TFactory,
+    replicas: i32,
+    ordered_input: bool,
+    ordered_output: bool,
+    mode: BlockMode,
+    name: Option<String>,
+    runtime: Handle,
+    _params: PhantomData<(TOutput, TCollected)>,
+    _stage: PhantomData<fn() -> TStage>,
+}
+
+impl<TInput, TOutput, TCollected, TStage, TFactory, TNextStep> PipelineBlock<TInput, TCollected>
+    for AsyncInOutBlock<TInput, TOutput, TCollected, TStage, TFactory, TNextStep>
+where
+    TNextStep: PipelineBlock<TOutput, TCollected>,
+{
+    //used by the public API
+    fn process(&self, input: WorkItem<TInput>) {
+        self.input.process(input);
+    }
+
+    //Used internally
+    fn process_timestamped(&self, input: TimestampedWorkItem<TInput>) {
+        self.input.process_timestamped(input);
+    }
+
+    fn try_process_timestamped(
+        &self,
+        input: TimestampedWorkItem<TInput>,
+    ) -> Result<(), TimestampedWorkItem<TInput>> {
+        self.input.try_process_timestamped(input)
+    }
+
+    fn poll_space(&self, input: &TimestampedWorkItem<TInput>, cx: &mut Context<'_>) -> Poll<()> {
+        self.input.poll_space_timestamped(input, cx)
+    }
+
+    fn output(&self) -> SinkOutput<TCollected> {
+        self.next_step.output()
+    }
+}
+
+impl<TInput, TOutput, TCollected, TStage, TFactory, TNextStep>
+    AsyncInOutBlock<TInput, TOutput, TCollected, TStage, TFactory, TNextStep>
+{
+    pub fn new(
+        next_step: TNextStep,
+        config: AsyncConfig<TInput>,
+        transformer_factory: TFactory,
+    ) -> AsyncInOutBlock<TInput, TOutput, TCollected, TStage, TFactory, TNextStep> {
+        let AsyncConfig {
+            mut config,
+            runtime,
+        } = config;
+        //the ordered input of an async stage is put back in order by its
+        //replica, which has no storage to replace or window to bound
+        assert!(
+            config.reorder_window.is_none() && config.ordered_storage.is_none(),
+            "windowed! and stored_ordered! do not apply to async stages"
+        );
+        let (mode, name) = (config.mode, config.name.take());
+        let (ordered_input, replicas, ordered_output) = match config.mode {
+            BlockMode::Sequential(OrderingMode::Ordered) => (true, 1, false),
+            BlockMode::Sequential(OrderingMode::Unordered) => (false, 1, false),
+            BlockMode::Parallel(replicas) => (false, replicas, false),
+            BlockMode::OrderedParallel(replicas) => (false, replicas, true),
+        };
+        AsyncInOutBlock {
+            input: Arc::new(BlockInput::for_farm(replicas as usize, config)),
+            next_step: Arc::new(next_step),
+            transformer_factory,
+            replicas,
+            ordered_input,
+            ordered_output,
+            mode,
+            name,
+            runtime,
+            _params: PhantomData,
+            _stage: PhantomData,
+        }
+    }
+}
+
+impl<TInput, TOutput, TCollected, TStage, TFactory, TNextStep> StageBlock<TInput>
+    for AsyncInOutBlock<TInput, TOutput, TCollected, TStage, TFactory, TNextStep>
+where
+    TInput: 'static + Send,
+    TOutput: 'static + Send,
+    TCollected: 'static,
+    TStage: AsyncInOut<TInput, TOutput> + Send + 'static,
+    TFactory: FnMut() -> TStage,
+    TNextStep: PipelineBlock<TOutput, TCollected> + Send + Sync + 'static,
+{
+    fn input(&self) -> Arc<BlockInput<TInput>> {
+        self.input.clone()
+    }
+
+    fn monitor_posts(&mut self, context: &StageContext) -> Vec<MonitorLoop> {
+        let context = &context.named(self.name.as_deref());
+        context.describe(self.mode, &self.input, false, true);
+        self.input.attach(context);
+        let counters = context.counters(self.replicas as usize, &self.input);
+        let emitter: Emitter<TOutput, TCollected, TNextStep> = Emitter::new(
+            self.next_step.clone(),
+            context.pipeline().clone(),
+            counters.clone(),
+            self.ordered_output,
+        );
+        let shared = Arc::new(ReplicaSet {
+            alive_threads: AtomicUsize::new(self.replicas as usize),
+            flushed: Flushed::new(self.replicas as usize),
+            counters,
+            emitter,
+        });
+
+        (0..self.replicas as usize)
+            .map(|replica| {
+                let task = run_replica(
+                    context.clone(),
+                    replica,
+                    self.input.queue(replica),
+                    shared.clone(),
+                    (self.transformer_factory)(),
+                    self.ordered_input,
+                );
+                MonitorLoop::task(task, self.runtime.clone())
+            })
+            .collect()
+    }
+}
+
+impl<TInput, TOutput, TCollected, TStage, TFactory, TNextStep>
+    IntoBlock<TInput, TOutput, TCollected, TFactory, TNextStep, ()> for AsyncConfig<TInput>
+where
+    TInput: 'static + Send,
+    TOutput: 'static + Send,
+    TCollected: 'static,
+    TStage: AsyncInOut<TInput, TOutput> + Send + 'static,
+    TFactory: FnMut() -> TStage,
+    TNextStep: PipelineBlock<TOutput, TCollected> + Send + Sync + 'static,
+{
+    type Block = AsyncInOutBlock<TInput, TOutput, TCollected, TStage, TFactory, TNextStep>;
+
+    fn into_block(self, next_step: TNextStep, factory: TFactory) -> Self::Block {
+        AsyncInOutBlock::new(next_step, self, factory)
+    }
+}
+
+//What the replicas of a block share
+struct ReplicaSet<TOutput, TCollected, TNextStep> {
+    alive_threads: AtomicUsize,
+    flushed: Arc<Flushed<TOutput>>,
+    counters: Arc<StageCounters>,
+    emitter: Emitter<TOutput, TCollected, TNextStep>,
+}
+
+async fn run_replica<TInput, TOutput, TCollected, TStage, TNextStep>(
+    context: StageContext,
+    replica: usize,
+    queue: ReplicaQueue<TInput>,
+    shared: Arc<ReplicaSet<TOutput, TCollected, TNextStep>>,
+    mut transformer: TStage,
+    ordered_input: bool,
+) where
+    TStage: AsyncInOut<TInput, TOutput>,
+    TNextStep: PipelineBlock<TOutput, TCollected>,
+{
+    let mut pending = BTreeMap::new();
+    let mut next_item = Order::new(0);
+    let counters = &shared.counters;
+    loop {
+        let waiting = Instant::now();
+        let item = poll_fn(|cx| queue.poll_dequeue(cx)).await;
+        counters.idle_since(replica, waiting, item.1.item);
+        counters.queue_len(queue.len() + 1);
+
+        //items of an ordered stage wait here until their turn comes
+        let ready = if ordered_input {
+            pending.insert(item.1.clone(), item);
+            let mut ready = vec![];
+            while let Some(item) = pending.remove(&next_item) {
+                next_item = item.1.next();
+                ready.push(item);
+            }
+            counters.reorder_buffer(pending.len());
+            ready
+        } else {
+            vec![item]
+        };
+
+        for item in ready {
+            match item {
+                TimestampedWorkItem(WorkItem::Value(value), order, ingress) => {
+                    let started = Instant::now();
+                    let output = context
+                        .guard_async(replica, transformer.process(value))
+                        .await
+                        .flatten();
+                    counters.busy_since(replica, started, order.item);
+                    let work_item = match output {
+                        Some(output) => WorkItem::Value(Outputs::One(output)),
+                        None => WorkItem::Dropped,
+                    };
+                    counters.processed(&work_item);
+                    shared
+                        .emitter
+                        .emit_async(TimestampedWorkItem(work_item, order, ingress))
+                        .await;
+                }
+                TimestampedWorkItem(WorkItem::Dropped, order, ingress) => {
+                    shared
+                        .emitter
+                        .emit_async(TimestampedWorkItem(WorkItem::Dropped, order, ingress))
+                        .await;
+                }
+                TimestampedWorkItem(WorkItem::Failed(failure), order, ingress) => {
+                    shared
+                        .emitter
+                        .emit_async(TimestampedWorkItem(
+                            WorkItem::Failed(failure),
+                            order,
+                            ingress,
+                        ))
+                        .await;
+                }
+                TimestampedWorkItem(WorkItem::Stop, order, _) => {
+                    let items = context.guard(replica, || transformer.flush());
+                    shared.flushed.store(replica, items.unwrap_or_default());
+
+                    if shared.alive_threads.fetch_sub(1, Ordering::SeqCst) == 1 {
+                        shared
+                            .emitter
+                            .emit_end_async(order.clone(), shared.flushed.take())
+                            .await;
+                    }
+
+                    //other replicas need to see it too
+                    queue.pass_stop(order);
+                    return;
+                }
+            }
+        }
+    }
+}
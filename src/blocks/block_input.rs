@@ -0,0 +1,586 @@
+use crate::blocks::*;
+use crate::work_storage::parking::Parking;
+use crate::work_storage::*;
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+//Internals: the receiving side of a block. Unordered blocks take items
+//from a FIFO queue, ordered blocks from a set that hands them out by
+//timestamp. Items posted through the public API get their timestamp here,
+//and their ingress time when the pipeline measures latency.
+//Ordered blocks count the posted items themselves, under a lock so items
+//refused by a custom ordered storage do not leave gaps in the sequence.
+//Keyed farms and farms with a balancing policy other than Shared take
+//items from one queue per replica instead.
+pub struct BlockInput<T> {
+    work_queue: WorkQueue<T>,
+    ordered_work: OrderedWorkQueue<T>,
+    ordering: OrderingMode,
+    posted: Mutex<u64>,
+    //whether posted values are stamped with their ingress time
+    stamps: AtomicBool,
+    partitions: Option<Partitions<T>>,
+    //more than one replica takes from the work queue
+    shared: bool,
+}
+
+//How partitions pick the queue of a value
+enum Route<T> {
+    Key(KeyHasher<T>),
+    RoundRobin,
+    LeastLoaded,
+}
+
+//One queue per replica of a farm. Values are routed by the policy, items
+//without a value by their timestamp, and Stop goes to every queue. The
+//lock is never held while a queue blocks: posts take their timestamp under
+//it and queue the item after, and the ones that can be refused only take
+//it once a queue accepted them without blocking, so refused items leave
+//no gaps in the sequence.
+struct Partitions<T> {
+    queues: Vec<WorkQueue<T>>,
+    route: Route<T>,
+    steal: bool,
+    posted: Mutex<u64>,
+    stamps: AtomicBool,
+    //values routed so far, for round robin. Timestamps would not do: the
+    //outputs of a flat map all carry the timestamp of their input.
+    routed: AtomicU64,
+    //set once the stage starts
+    stage: OnceLock<StageContext>,
+    //replicas of a work stealing farm with nothing to take wait here for
+    //an item to come into any of the queues
+    arrivals: Arc<Parking>,
+}
+
+impl<T> Partitions<T> {
+    //Items whose key could not be taken go on as Dropped
+    fn route(&self, item: TimestampedWorkItem<T>) -> (&WorkQueue<T>, TimestampedWorkItem<T>) {
+        let queue = match (&self.route, &item) {
+            (Route::Key(key), TimestampedWorkItem(WorkItem::Value(value), ..)) => {
+                match self.key_of(key, value) {
+                    Some(hash) => self.by_hash(hash),
+                    None => {
+                        let TimestampedWorkItem(_, order, ingress) = item;
+                        let dropped = TimestampedWorkItem(WorkItem::Dropped, order, ingress);
+                        return (self.by_hash(dropped.1.item), dropped);
+                    }
+                }
+            }
+            (Route::RoundRobin, TimestampedWorkItem(WorkItem::Value(_), ..)) => {
+                self.by_hash(self.routed.fetch_add(1, Ordering::Relaxed))
+            }
+            (Route::LeastLoaded, TimestampedWorkItem(WorkItem::Value(_), ..)) => {
+                self.least_loaded()
+            }
+            (_, TimestampedWorkItem(_, order, _)) => self.by_hash(order.item),
+        };
+        (queue, item)
+    }
+
+    //The key function runs on the thread that posts the item, so a panic
+    //in it is caught here and recorded against the keyed stage. It runs
+    //before the item reaches any replica, the error names replica 0.
+    fn key_of(&self, key: &KeyHasher<T>, value: &T) -> Option<u64> {
+        match self.stage.get() {
+            Some(stage) => stage.guard(0, || key(value)),
+            None => Some(key(value)),
+        }
+    }
+
+    fn by_hash(&self, hash: u64) -> &WorkQueue<T> {
+        &self.queues[(hash % self.queues.len() as u64) as usize]
+    }
+
+    fn least_loaded(&self) -> &WorkQueue<T> {
+        self.queues
+            .iter()
+            .min_by_key(|queue| queue.len())
+            .expect("farm without replicas")
+    }
+
+    //The queue the value would go to if it was posted now, None if its
+    //key could not be taken
+    fn next_queue(&self, value: &T) -> Option<&WorkQueue<T>> {
+        match &self.route {
+            Route::Key(key) => self.key_of(key, value).map(|hash| self.by_hash(hash)),
+            Route::RoundRobin => Some(self.by_hash(self.routed.load(Ordering::Relaxed))),
+            Route::LeastLoaded => Some(self.least_loaded()),
+        }
+    }
+
+    fn enqueue(&self, item: TimestampedWorkItem<T>) {
+        match item {
+            TimestampedWorkItem(WorkItem::Stop, order, _) => {
+                for queue in &self.queues {
+                    queue.enqueue_timestamped(TimestampedWorkItem::stop(order.clone()));
+                    self.arrived();
+                }
+            }
+            item => {
+                let (queue, item) = self.route(item);
+                queue.enqueue_timestamped(item);
+                self.arrived();
+            }
+        }
+    }
+
+    //A value whose key panicked is queued as Dropped even if it has to
+    //wait: there is no value left to hand back
+    fn try_enqueue(&self, item: TimestampedWorkItem<T>) -> Result<(), TimestampedWorkItem<T>> {
+        let was_value = matches!(item.0, WorkItem::Value(_));
+        let (queue, item) = self.route(item);
+        if was_value && matches!(item.0, WorkItem::Dropped) {
+            queue.enqueue_timestamped(item);
+        } else {
+            queue.try_enqueue_timestamped(item)?;
+        }
+        self.arrived();
+        Ok(())
+    }
+
+    //Called after an item went into a queue
+    fn arrived(&self) {
+        if self.steal {
+            self.arrivals.unpark();
+        }
+    }
+
+    //Blocking enqueues always succeed, so the timestamp can be taken
+    //before the item is queued
+    fn post(&self, item: WorkItem<T>) {
+        let item = {
+            let mut posted = self.posted.lock();
+            *posted += 1;
+            TimestampedWorkItem::numbered(item, *posted - 1, self.stamps.load(Ordering::Relaxed))
+        };
+        self.enqueue(item);
+    }
+
+    //Gives the item back if the queue it goes to is full
+    fn try_post(&self, item: WorkItem<T>) -> Result<(), WorkItem<T>> {
+        let mut posted = self.posted.lock();
+        let stamp = self.stamps.load(Ordering::Relaxed);
+        match self.try_enqueue(TimestampedWorkItem::numbered(item, *posted, stamp)) {
+            Ok(()) => {
+                *posted += 1;
+                Ok(())
+            }
+            Err(TimestampedWorkItem(item, ..)) => Err(item),
+        }
+    }
+
+    //Tries again each time the queue the item goes to has room, until
+    //the timeout, without holding the lock in between
+    fn post_timeout(&self, item: WorkItem<T>, timeout: Duration) -> Result<(), WorkItem<T>> {
+        let deadline = Instant::now() + timeout;
+        let mut item = item;
+        loop {
+            item = match self.try_post(item) {
+                Ok(()) => return Ok(()),
+                Err(item) => item,
+            };
+            let queue = match &item {
+                WorkItem::Value(value) => self.next_queue(value),
+                _ => None,
+            };
+            let in_time = match queue {
+                Some(queue) => queue.wait_for_space(deadline),
+                //the key panicked, the next try queues it as Dropped
+                None => true,
+            };
+            if !in_time {
+                return self.try_post(item);
+            }
+        }
+    }
+}
+
+//Internals: where a replica takes its items from. Replicas of a work
+//stealing farm look into the queues of the others when theirs is empty,
+//and only take their Stop once there is nothing left to steal. When all
+//of them are empty, they wait for the farm to get another item.
+pub struct ReplicaQueue<T> {
+    own: WorkQueue<T>,
+    peers: Vec<WorkQueue<T>>,
+    arrivals: Option<Arc<Parking>>,
+    //other replicas take from the same queue
+    shared: bool,
+}
+
+impl<T> ReplicaQueue<T> {
+    //Items waiting in the replica's own queue
+    pub fn len(&self) -> usize {
+        self.own.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.own.is_empty()
+    }
+
+    fn try_dequeue(&self) -> Option<TimestampedWorkItem<T>> {
+        self.own
+            .steal()
+            .or_else(|| self.peers.iter().find_map(|peer| peer.steal()))
+            .or_else(|| self.own.try_dequeue())
+    }
+
+    //A Stop only sits in the queue of a peer once it is in every queue,
+    //so a replica woken up by one takes its own
+    fn has_items(&self) -> bool {
+        !self.own.is_empty() || self.peers.iter().any(|peer| !peer.is_empty())
+    }
+
+    pub fn wait_and_dequeue(&self) -> TimestampedWorkItem<T> {
+        let arrivals = match &self.arrivals {
+            Some(arrivals) => arrivals,
+            None => return self.own.wait_and_dequeue(),
+        };
+        loop {
+            if let Some(item) = self.try_dequeue() {
+                return item;
+            }
+            arrivals.park(None, || self.has_items());
+        }
+    }
+
+    pub fn poll_dequeue(&self, cx: &mut Context<'_>) -> Poll<TimestampedWorkItem<T>> {
+        let arrivals = match &self.arrivals {
+            Some(arrivals) => arrivals,
+            None => return self.own.poll_dequeue(cx),
+        };
+        if let Some(item) = self.try_dequeue() {
+            return Poll::Ready(item);
+        }
+        arrivals.register(cx);
+        match self.try_dequeue() {
+            Some(item) => Poll::Ready(item),
+            None => Poll::Pending,
+        }
+    }
+
+    //Puts a Stop back for the other replicas that share the queue. A queue
+    //with a single consumer can also have a single producer, which the
+    //replica would be racing with, so nothing goes back in it.
+    pub fn pass_stop(&self, order: Order) {
+        if self.shared {
+            self.own
+                .enqueue_timestamped(TimestampedWorkItem::stop(order));
+        }
+    }
+}
+
+impl<T> BlockInput<T> {
+    pub fn new(ordering: OrderingMode, capacity: Option<usize>) -> BlockInput<T> {
+        BlockInput {
+            work_queue: WorkQueue::new(QueueKind::Blocking, capacity),
+            ordered_work: OrderedWorkQueue::Blocking(BlockingOrderedSet::new()),
+            ordering,
+            posted: Mutex::new(0),
+            stamps: AtomicBool::new(false),
+            partitions: None,
+            shared: true,
+        }
+    }
+
+    //Input of an ordered sequential stage
+    pub fn ordered(config: BlockConfig<T>) -> BlockInput<T> {
+        let ordered_work = match &config.ordered_storage {
+            Some(storage) => OrderedWorkQueue::Custom(storage()),
+            None => OrderedWorkQueue::new(config.reorder_window),
+        };
+        BlockInput {
+            ordered_work,
+            ..BlockInput::new(OrderingMode::Ordered, config.capacity)
+        }
+    }
+
+    //A queue taken from by the given number of replicas
+    fn link_queue(config: &BlockConfig<T>, consumers: usize) -> WorkQueue<T> {
+        match &config.storage {
+            Some(storage) => WorkQueue::Custom(storage(config.capacity)),
+            None => WorkQueue::for_link(config.queue, config.producers, consumers, config.capacity),
+        }
+    }
+
+    //Every replica's queue gets the given capacity
+    fn partitioned(
+        replicas: usize,
+        config: &BlockConfig<T>,
+        route: Route<T>,
+        steal: bool,
+    ) -> BlockInput<T> {
+        let capacity = config.capacity;
+        //any replica takes from any queue when they steal
+        let consumers = if steal { replicas } else { 1 };
+        BlockInput {
+            partitions: Some(Partitions {
+                queues: (0..replicas)
+                    .map(|_| BlockInput::link_queue(config, consumers))
+                    .collect(),
+                route,
+                steal,
+                posted: Mutex::new(0),
+                stamps: AtomicBool::new(false),
+                routed: AtomicU64::new(0),
+                stage: OnceLock::new(),
+                arrivals: Arc::new(Parking::new()),
+            }),
+            ..BlockInput::new(OrderingMode::Unordered, capacity)
+        }
+    }
+
+    //Items waiting to be taken by a replica, in every queue of the stage
+    pub fn len(&self) -> usize {
+        match (&self.ordering, &self.partitions) {
+            (OrderingMode::Ordered, _) => self.ordered_work.len(),
+            (_, Some(partitions)) => partitions.queues.iter().map(|queue| queue.len()).sum(),
+            (_, None) => self.work_queue.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    //Where the replicas take their items from, for Pipeline::describe
+    pub fn describe(&self) -> String {
+        match (&self.ordering, &self.partitions) {
+            (OrderingMode::Ordered, _) => self.ordered_work.describe(),
+            (_, Some(partitions)) => {
+                let route = match (&partitions.route, partitions.steal) {
+                    (Route::Key(_), _) => "by key",
+                    (_, true) => "work stealing",
+                    (Route::RoundRobin, false) => "round robin",
+                    (Route::LeastLoaded, false) => "least loaded",
+                };
+                format!("{} per replica, {}", partitions.queues[0].describe(), route)
+            }
+            (_, None) => self.work_queue.describe(),
+        }
+    }
+
+    //Of each queue, when there is one per replica. Ordered sequential
+    //stages bypass the queue, so they have none.
+    pub fn capacity(&self) -> Option<usize> {
+        match (&self.ordering, &self.partitions) {
+            (OrderingMode::Ordered, _) => None,
+            (_, Some(partitions)) => partitions.queues[0].capacity(),
+            (_, None) => self.work_queue.capacity(),
+        }
+    }
+
+    //Builds the input for a farm from its configuration
+    pub fn for_farm(replicas: usize, config: BlockConfig<T>) -> BlockInput<T> {
+        match (config.key.clone(), config.balancing) {
+            (Some(key), _) => BlockInput::partitioned(replicas, &config, Route::Key(key), false),
+            (None, LoadBalancing::Shared) => BlockInput {
+                work_queue: BlockInput::link_queue(&config, replicas),
+                shared: replicas > 1,
+                ..BlockInput::new(OrderingMode::Unordered, config.capacity)
+            },
+            (None, LoadBalancing::RoundRobin) => {
+                BlockInput::partitioned(replicas, &config, Route::RoundRobin, false)
+            }
+            (None, LoadBalancing::LeastLoaded) => {
+                BlockInput::partitioned(replicas, &config, Route::LeastLoaded, false)
+            }
+            (None, LoadBalancing::WorkStealing) => {
+                BlockInput::partitioned(replicas, &config, Route::RoundRobin, true)
+            }
+        }
+    }
+
+    //Lets the input record panics of a key function against the stage
+    pub fn attach(&self, context: &StageContext) {
+        if let Some(partitions) = &self.partitions {
+            let _ = partitions.stage.set(context.clone());
+        }
+    }
+
+    //Stamps posted values with their ingress time from here on, for
+    //pipelines that measure latency
+    pub fn stamp_ingress(&self) {
+        self.stamps.store(true, Ordering::Relaxed);
+        self.work_queue.stamp_ingress();
+        if let Some(partitions) = &self.partitions {
+            partitions.stamps.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub fn ordering(&self) -> OrderingMode {
+        self.ordering
+    }
+
+    //The queue a replica takes its items from
+    pub fn queue(&self, replica: usize) -> ReplicaQueue<T> {
+        match &self.partitions {
+            Some(partitions) => {
+                let queues = &partitions.queues;
+                //starting from the next replica, so they do not all go
+                //for the same one
+                let (peers, arrivals) = if partitions.steal {
+                    let peers = (1..queues.len())
+                        .map(|offset| queues[(replica + offset) % queues.len()].clone())
+                        .collect();
+                    (peers, Some(partitions.arrivals.clone()))
+                } else {
+                    (vec![], None)
+                };
+                ReplicaQueue {
+                    own: queues[replica].clone(),
+                    peers,
+                    arrivals,
+                    shared: false,
+                }
+            }
+            None => ReplicaQueue {
+                own: self.work_queue.clone(),
+                peers: vec![],
+                arrivals: None,
+                shared: self.shared,
+            },
+        }
+    }
+
+    pub fn ordered_set(&self) -> OrderedWorkQueue<T> {
+        self.ordered_work.clone()
+    }
+
+    //used by the public API
+    pub fn process(&self, input: WorkItem<T>) {
+        if let Some(partitions) = &self.partitions {
+            partitions.post(input);
+            return;
+        }
+        match self.ordering {
+            //For the unordered case, just enqueue it
+            OrderingMode::Unordered => {
+                self.work_queue.enqueue(input);
+            }
+            //For the ordered case the queue is bypassed, so keep
+            //our own count of the items posted so far
+            OrderingMode::Ordered => {
+                let _ = self.post_ordered(input, |set, item| {
+                    set.enqueue(item);
+                    Ok(())
+                });
+            }
+        };
+    }
+
+    //Timestamps a posted item and hands it to insert, which gives it back
+    //if it could not be queued
+    fn post_ordered(
+        &self,
+        item: WorkItem<T>,
+        insert: impl FnOnce(
+            &OrderedWorkQueue<T>,
+            TimestampedWorkItem<T>,
+        ) -> Result<(), TimestampedWorkItem<T>>,
+    ) -> Result<(), WorkItem<T>> {
+        let mut posted = self.posted.lock();
+        match insert(
+            &self.ordered_work,
+            TimestampedWorkItem::numbered(item, *posted, self.stamps.load(Ordering::Relaxed)),
+        ) {
+            Ok(()) => {
+                *posted += 1;
+                Ok(())
+            }
+            Err(TimestampedWorkItem(item, ..)) => Err(item),
+        }
+    }
+
+    //Used internally
+    pub fn process_timestamped(&self, input: TimestampedWorkItem<T>) {
+        if let Some(partitions) = &self.partitions {
+            partitions.enqueue(input);
+            return;
+        }
+        match self.ordering {
+            OrderingMode::Unordered => self.work_queue.enqueue_timestamped(input),
+            OrderingMode::Ordered => self.ordered_work.enqueue(input),
+        };
+    }
+
+    //Like process_timestamped, but gives the item back if its queue is
+    //full. Ordered inputs never make producers wait, and neither do Stop
+    //tokens, so they are always queued.
+    pub fn try_process_timestamped(
+        &self,
+        input: TimestampedWorkItem<T>,
+    ) -> Result<(), TimestampedWorkItem<T>> {
+        if matches!(input.0, WorkItem::Stop) || matches!(self.ordering, OrderingMode::Ordered) {
+            self.process_timestamped(input);
+            return Ok(());
+        }
+        match &self.partitions {
+            Some(partitions) => partitions.try_enqueue(input),
+            None => self.work_queue.try_enqueue_timestamped(input),
+        }
+    }
+
+    //Ordered inputs only refuse items when a custom storage does. Reorder
+    //windows hold posts back with live tokens before they get here.
+    pub fn try_process(&self, input: WorkItem<T>) -> Result<(), WorkItem<T>> {
+        if let Some(partitions) = &self.partitions {
+            return partitions.try_post(input);
+        }
+        match self.ordering {
+            OrderingMode::Unordered => self.work_queue.try_enqueue(input).map(|_| ()),
+            OrderingMode::Ordered => self.post_ordered(input, |set, item| set.try_enqueue(item)),
+        }
+    }
+
+    pub fn process_timeout(
+        &self,
+        input: WorkItem<T>,
+        timeout: Duration,
+    ) -> Result<(), WorkItem<T>> {
+        if let Some(partitions) = &self.partitions {
+            return partitions.post_timeout(input, timeout);
+        }
+        match self.ordering {
+            OrderingMode::Unordered => self.work_queue.enqueue_timeout(input, timeout).map(|_| ()),
+            OrderingMode::Ordered => {
+                self.post_ordered(input, |set, item| set.enqueue_timeout(item, timeout))
+            }
+        }
+    }
+
+    //Ready once the item would fit, for async stages
+    pub fn poll_space_timestamped(
+        &self,
+        input: &TimestampedWorkItem<T>,
+        cx: &mut Context<'_>,
+    ) -> Poll<()> {
+        match (&self.partitions, input) {
+            (Some(partitions), TimestampedWorkItem(WorkItem::Dropped, order, _))
+            | (Some(partitions), TimestampedWorkItem(WorkItem::Failed(_), order, _)) => {
+                partitions.by_hash(order.item).poll_space(cx)
+            }
+            _ => self.poll_space(&input.0, cx),
+        }
+    }
+
+    //Ready once the item would fit, for async posts. Ordered inputs are
+    //always ready, a reorder window holds posts back with live tokens
+    pub fn poll_space(&self, input: &WorkItem<T>, cx: &mut Context<'_>) -> Poll<()> {
+        match (&self.partitions, input) {
+            (Some(partitions), WorkItem::Value(value)) => match partitions.next_queue(value) {
+                Some(queue) => queue.poll_space(cx),
+                //the item gets dropped, so it always fits
+                None => Poll::Ready(()),
+            },
+            (Some(_), _) => Poll::Ready(()),
+            (None, _) => match self.ordering {
+                OrderingMode::Unordered => self.work_queue.poll_space(cx),
+                OrderingMode::Ordered => Poll::Ready(()),
+            },
+        }
+    }
+}
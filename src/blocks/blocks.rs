@@ -1,4 +1,19 @@
-use crate::work_storage::{TimestampedWorkItem, WorkItem};
+use crate::blocks::{BlockInput, SinkOutput, StageContext};
+use crate::work_storage::{
+    OrderedWorkStorage, QueueKind, SharedOrderedStorage, SharedStorage, TimestampedWorkItem,
+    WorkItem, WorkStorage,
+};
+use std::collections::hash_map::DefaultHasher;
+#[cfg(feature = "tokio")]
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+#[cfg(feature = "tokio")]
+use std::pin::Pin;
+#[cfg(feature = "tokio")]
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread::{self, JoinHandle};
 
 //Base trait for all blocks in the pipeline
 //Used by the internals. Should be able to detal with
@@ -6,36 +21,315 @@ use crate::work_storage::{TimestampedWorkItem, WorkItem};
 pub trait PipelineBlock<TInput, TCollected> {
     fn process(&self, input: WorkItem<TInput>);
     fn process_timestamped(&self, input: TimestampedWorkItem<TInput>);
-    fn collect(self) -> Vec<TCollected>;
+    //For async stages, which cannot block: gives the item back if there
+    //is no room for it, and poll_space tells when there is
+    fn try_process_timestamped(
+        &self,
+        input: TimestampedWorkItem<TInput>,
+    ) -> Result<(), TimestampedWorkItem<TInput>>;
+    fn poll_space(&self, input: &TimestampedWorkItem<TInput>, cx: &mut Context<'_>) -> Poll<()>;
+    //Where the sink at the end of the pipeline puts what it collected
+    fn output(&self) -> SinkOutput<TCollected>;
 }
 
-#[derive(Clone, Copy)]
+//Internals: a block that runs a stage in the middle of the pipeline
+pub trait StageBlock<TInput> {
+    //Shared with the senders of the pipeline when this is the first block
+    fn input(&self) -> Arc<BlockInput<TInput>>;
+    fn monitor_posts(&mut self, context: &StageContext) -> Vec<MonitorLoop>;
+}
+
+//Internals: builds the block for a middle stage out of what the stage macros
+//produce, so the pipeline macros work the same for every kind of stage.
+//TKind is the kind of Transform the factory makes, () for async stages.
+pub trait IntoBlock<TInput, TOutput, TCollected, TFactory, TNextStep, TKind> {
+    type Block: StageBlock<TInput> + PipelineBlock<TInput, TCollected>;
+    fn into_block(self, next_step: TNextStep, factory: TFactory) -> Self::Block;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum OrderingMode {
     Unordered,
     Ordered,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum BlockMode {
     Sequential(OrderingMode),
     Parallel(i32),
+    //A farm whose output is put back in sequence before the next stage
+    OrderedParallel(i32),
+}
+
+impl BlockMode {
+    pub fn replicas(&self) -> usize {
+        match self {
+            BlockMode::Sequential(_) => 1,
+            BlockMode::Parallel(replicas) | BlockMode::OrderedParallel(replicas) => {
+                *replicas as usize
+            }
+        }
+    }
+}
+
+//Hash of the key of an item, used to pick the replica of a keyed farm
+pub type KeyHasher<T> = Arc<dyn Fn(&T) -> u64 + Send + Sync>;
+
+//Builds the storage of a stage out of its capacity. Farms with a queue
+//per replica call it once for every replica.
+pub type StorageFactory<T> = Arc<dyn Fn(Option<usize>) -> SharedStorage<T> + Send + Sync>;
+pub type OrderedStorageFactory<T> = Arc<dyn Fn() -> SharedOrderedStorage<T> + Send + Sync>;
+
+//How a farm hands its items out to the replicas. Shared puts them all in
+//one queue. The others give every replica its own queue and pick one per
+//item: the next one in turn, the one with the fewest items waiting, or the
+//next one in turn while replicas that run out take items from the others.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LoadBalancing {
+    #[default]
+    Shared,
+    RoundRobin,
+    LeastLoaded,
+    WorkStealing,
+}
+
+//Construction options for a block, set through the stage macros
+pub struct BlockConfig<T> {
+    //how the stage runs
+    pub mode: BlockMode,
+    //bounds the input queue, or every replica's queue when they have one
+    //each. None means unbounded.
+    pub capacity: Option<usize>,
+    //gives every replica its own queue, and items with the same key always
+    //go to the same replica
+    pub key: Option<KeyHasher<T>>,
+    //picks the replica when there is no key
+    pub balancing: LoadBalancing,
+    //ignored by ordered sequential stages, which do not use a queue
+    pub queue: QueueKind,
+    //replicas of the stage before, None for the first stage, which is fed
+    //by the pipeline
+    pub producers: Option<usize>,
+    //replaces the queues of the crate
+    pub storage: Option<StorageFactory<T>>,
+    //replaces the set of ordered sequential stages
+    pub ordered_storage: Option<OrderedStorageFactory<T>>,
+    //how many items an ordered sequential stage keeps ahead of the one it
+    //waits for, by holding posts back
+    pub reorder_window: Option<usize>,
+    //shows up in thread names, stats, traces, metrics and errors
+    pub name: Option<String>,
+}
+
+impl<T> BlockConfig<T> {
+    pub fn new(mode: BlockMode) -> BlockConfig<T> {
+        BlockConfig {
+            mode,
+            capacity: None,
+            key: None,
+            balancing: LoadBalancing::Shared,
+            queue: QueueKind::Auto,
+            producers: None,
+            storage: None,
+            ordered_storage: None,
+            reorder_window: None,
+            name: None,
+        }
+    }
+
+    pub fn with_capacity(mut self, capacity: usize) -> BlockConfig<T> {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    //Only applies the capacity if the stage did not set one itself
+    pub fn with_default_capacity(mut self, capacity: usize) -> BlockConfig<T> {
+        self.capacity.get_or_insert(capacity);
+        self
+    }
+
+    pub fn with_key<K: Hash, F>(mut self, key: F) -> BlockConfig<T>
+    where
+        F: Fn(&T) -> K + Send + Sync + 'static,
+    {
+        self.key = Some(Arc::new(move |item: &T| {
+            let mut hasher = DefaultHasher::new();
+            key(item).hash(&mut hasher);
+            hasher.finish()
+        }));
+        self
+    }
+
+    //Ignored by keyed farms and sequential stages
+    pub fn with_balancing(mut self, balancing: LoadBalancing) -> BlockConfig<T> {
+        self.balancing = balancing;
+        self
+    }
+
+    pub fn with_queue(mut self, queue: QueueKind) -> BlockConfig<T> {
+        self.queue = queue;
+        self
+    }
+
+    pub fn with_producers(mut self, producers: usize) -> BlockConfig<T> {
+        self.producers = Some(producers);
+        self
+    }
+
+    //Overrides the queue kind
+    pub fn with_storage<S, F>(mut self, storage: F) -> BlockConfig<T>
+    where
+        S: WorkStorage<T> + Send + Sync + 'static,
+        F: Fn(Option<usize>) -> Arc<S> + Send + Sync + 'static,
+    {
+        self.storage = Some(Arc::new(move |capacity| -> SharedStorage<T> {
+            storage(capacity)
+        }));
+        self
+    }
+
+    pub fn with_ordered_storage<S, F>(mut self, storage: F) -> BlockConfig<T>
+    where
+        S: OrderedWorkStorage<T> + Send + Sync + 'static,
+        F: Fn() -> Arc<S> + Send + Sync + 'static,
+    {
+        self.ordered_storage = Some(Arc::new(move || -> SharedOrderedStorage<T> { storage() }));
+        self
+    }
+
+    //Only used by ordered sequential stages that run on threads
+    pub fn with_reorder_window(mut self, window: usize) -> BlockConfig<T> {
+        self.reorder_window = Some(window);
+        self
+    }
+
+    pub fn with_name(mut self, name: impl Into<String>) -> BlockConfig<T> {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn replicas(&self) -> usize {
+        self.mode.replicas()
+    }
+}
+
+impl<T> From<BlockMode> for BlockConfig<T> {
+    fn from(mode: BlockMode) -> BlockConfig<T> {
+        BlockConfig::new(mode)
+    }
+}
+
+//Internals: lets bounded!, keyed!, balanced!, lock_free!, stored!,
+//stored_ordered!, windowed!, named! and pipeline![capacity: ...] change the
+//configuration of a stage, whatever kind of block it runs in
+pub trait Configure<T> {
+    type Config;
+    fn configure(self, change: impl FnOnce(BlockConfig<T>) -> BlockConfig<T>) -> Self::Config;
+}
+
+impl<T> Configure<T> for BlockMode {
+    type Config = BlockConfig<T>;
+
+    fn configure(self, change: impl FnOnce(BlockConfig<T>) -> BlockConfig<T>) -> BlockConfig<T> {
+        change(BlockConfig::new(self))
+    }
+}
+
+impl<T> Configure<T> for BlockConfig<T> {
+    type Config = BlockConfig<T>;
+
+    fn configure(self, change: impl FnOnce(BlockConfig<T>) -> BlockConfig<T>) -> BlockConfig<T> {
+        change(self)
+    }
 }
 
+//Internals: one replica of a block. Runs on its own thread, or as a task
+//on a tokio runtime for async stages
 pub struct MonitorLoop {
-    loop_function: Box<dyn FnOnce() -> () + Send>,
+    kind: MonitorKind,
+    name: Option<String>,
+}
+
+enum MonitorKind {
+    Thread(Box<dyn FnOnce() + Send>),
+    #[cfg(feature = "tokio")]
+    Task(
+        Pin<Box<dyn Future<Output = ()> + Send>>,
+        tokio::runtime::Handle,
+    ),
 }
 
 impl MonitorLoop {
     pub fn new<F>(function: F) -> MonitorLoop
     where
-        F: FnOnce() -> (),
+        F: FnOnce(),
         F: Send + 'static,
     {
         MonitorLoop {
-            loop_function: Box::new(function),
+            kind: MonitorKind::Thread(Box::new(function)),
+            name: None,
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    pub fn task<F>(future: F, runtime: tokio::runtime::Handle) -> MonitorLoop
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        MonitorLoop {
+            kind: MonitorKind::Task(Box::pin(future), runtime),
+            name: None,
         }
     }
 
-    pub fn run(self) {
-        (self.loop_function)()
+    //Name of the thread the replica runs on. Tasks have no name of their own.
+    pub fn named(mut self, name: String) -> MonitorLoop {
+        self.name = Some(name);
+        self
+    }
+
+    pub fn start(self) -> Worker {
+        match self.kind {
+            MonitorKind::Thread(loop_function) => {
+                let mut builder = thread::Builder::new();
+                if let Some(name) = self.name {
+                    builder = builder.name(name);
+                }
+                Worker::Thread(
+                    builder
+                        .spawn(loop_function)
+                        .expect("failed to spawn replica thread"),
+                )
+            }
+            #[cfg(feature = "tokio")]
+            MonitorKind::Task(future, runtime) => {
+                //dropped without sending if the task panics
+                let (done, finished) = mpsc::channel();
+                runtime.spawn(async move {
+                    future.await;
+                    let _ = done.send(());
+                });
+                Worker::Task(finished)
+            }
+        }
+    }
+}
+
+//Internals: a running replica, joined when the pipeline ends
+pub enum Worker {
+    Thread(JoinHandle<()>),
+    #[cfg(feature = "tokio")]
+    Task(mpsc::Receiver<()>),
+}
+
+impl Worker {
+    pub fn join(self) -> thread::Result<()> {
+        match self {
+            Worker::Thread(handle) => handle.join(),
+            #[cfg(feature = "tokio")]
+            Worker::Task(finished) => finished
+                .recv()
+                .map_err(|_| Box::new("async stage task panicked") as Box<_>),
+        }
     }
 }
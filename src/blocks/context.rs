@@ -0,0 +1,432 @@
+use crate::blocks::stats::{PipelineStats, StageCounters};
+use crate::blocks::topology::{StageDescription, Topology};
+use crate::blocks::trace::{self, Tracing};
+use crate::blocks::{BlockInput, BlockMode};
+use crate::work_storage::parking::Parking;
+use parking_lot::{Mutex, RwLock};
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt;
+#[cfg(feature = "tokio")]
+use std::future::Future;
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(feature = "tokio")]
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+//A stage panicked while processing an item. Holds the panic payload
+//and which stage (position in the pipeline, starting at 0, and its name
+//if it has one) and replica it came from.
+pub struct PipelineError {
+    pub stage: usize,
+    pub stage_name: Option<String>,
+    pub replica: usize,
+    pub payload: Box<dyn Any + Send>,
+}
+
+impl PipelineError {
+    //The panic message, when the stage panicked with a string
+    pub fn message(&self) -> Option<&str> {
+        if let Some(message) = self.payload.downcast_ref::<&str>() {
+            Some(message)
+        } else {
+            self.payload.downcast_ref::<String>().map(|s| s.as_str())
+        }
+    }
+}
+
+impl fmt::Debug for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PipelineError")
+            .field("stage", &self.stage)
+            .field("stage_name", &self.stage_name)
+            .field("replica", &self.replica)
+            .field("message", &self.message())
+            .finish()
+    }
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "stage {}", self.stage)?;
+        if let Some(name) = &self.stage_name {
+            write!(f, " \"{}\"", name)?;
+        }
+        write!(
+            f,
+            " (replica {}) panicked: {}",
+            self.replica,
+            self.message().unwrap_or("<non-string payload>")
+        )
+    }
+}
+
+impl std::error::Error for PipelineError {}
+
+//Tokens of a reorder window
+struct WindowTokens {
+    limit: usize,
+    taken: AtomicUsize,
+}
+
+impl WindowTokens {
+    fn has_token(&self) -> bool {
+        self.taken.load(Ordering::SeqCst) < self.limit
+    }
+}
+
+//Counts one more unless the count already reached the limit
+fn take_below(count: &AtomicUsize, limit: usize) -> bool {
+    count
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+            (count < limit).then_some(count + 1)
+        })
+        .is_ok()
+}
+
+//Internals: state shared by every block of a pipeline.
+//Every item inside the pipeline holds a token, from the moment it is
+//posted until the sink took it. Items a stage emits on top of the one it
+//got take a token too, without waiting. Posts wait while every token is
+//taken, so the limit bounds the items in all queues and reorder buffers
+//at once. Each reorder window has tokens of its own, see add_window.
+pub struct PipelineContext {
+    failed: AtomicBool,
+    failure: Mutex<Option<PipelineError>>,
+    live_tokens: AtomicUsize,
+    //usize::MAX when there is no limit
+    max_live_tokens: AtomicUsize,
+    //one per reorder window, the posts its stage has not passed yet
+    windows: RwLock<Vec<WindowTokens>>,
+    returned_tokens: Parking,
+    stages: Mutex<BTreeMap<usize, Arc<StageCounters>>>,
+    topology: Mutex<BTreeMap<usize, StageDescription>>,
+    tracing: Arc<Tracing>,
+}
+
+impl PipelineContext {
+    pub fn new() -> Arc<PipelineContext> {
+        Arc::new(PipelineContext {
+            failed: AtomicBool::new(false),
+            failure: Mutex::new(None),
+            live_tokens: AtomicUsize::new(0),
+            max_live_tokens: AtomicUsize::new(usize::MAX),
+            windows: RwLock::new(vec![]),
+            returned_tokens: Parking::new(),
+            stages: Mutex::new(BTreeMap::new()),
+            topology: Mutex::new(BTreeMap::new()),
+            tracing: Arc::new(Tracing::new()),
+        })
+    }
+
+    pub fn stage(self: &Arc<Self>, index: usize) -> StageContext {
+        StageContext {
+            pipeline: self.clone(),
+            index,
+            name: None,
+        }
+    }
+
+    pub fn has_failed(&self) -> bool {
+        self.failed.load(Ordering::SeqCst)
+    }
+
+    //Only the first failure is kept, later ones are usually a consequence of it
+    fn fail(&self, error: PipelineError) {
+        let mut failure = self.failure.lock();
+        if failure.is_none() {
+            *failure = Some(error);
+        }
+        self.failed.store(true, Ordering::SeqCst);
+    }
+
+    pub fn take_failure(&self) -> Option<PipelineError> {
+        self.failure.lock().take()
+    }
+
+    pub fn set_max_live_tokens(&self, limit: usize) {
+        assert!(limit != 0, "live token limit must be at least 1");
+        self.max_live_tokens.store(limit, Ordering::SeqCst);
+        self.returned_tokens.unpark();
+    }
+
+    //A windowed stage lets in items up to the window ahead of the one it
+    //waits for. Posts are numbered in sequence, and each one also takes a
+    //token of every window, given back once its stage passed the item. With
+    //no more of them out than the window, every item that reaches the
+    //stage fits in it, and the stages after it are not held back.
+    //Returns the index to give to pass_window.
+    pub fn add_window(&self, window: usize) -> usize {
+        let mut windows = self.windows.write();
+        windows.push(WindowTokens {
+            limit: window,
+            taken: AtomicUsize::new(0),
+        });
+        windows.len() - 1
+    }
+
+    //The windowed stage passed a posted item. Items flushed at the end
+    //never took a token, and only come after every posted one.
+    pub fn pass_window(&self, window: usize) {
+        let _ = self.windows.read()[window].taken.fetch_update(
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+            |taken| taken.checked_sub(1),
+        );
+        self.returned_tokens.unpark();
+    }
+
+    pub fn live_tokens(&self) -> usize {
+        self.live_tokens.load(Ordering::SeqCst)
+    }
+
+    fn has_token(&self) -> bool {
+        self.live_tokens() < self.max_live_tokens.load(Ordering::SeqCst)
+            && self.windows.read().iter().all(|window| window.has_token())
+    }
+
+    //Takes a token and one of every window, or none at all
+    pub fn try_take_token(&self) -> bool {
+        let limit = self.max_live_tokens.load(Ordering::SeqCst);
+        if !take_below(&self.live_tokens, limit) {
+            return false;
+        }
+        let windows = self.windows.read();
+        for (taken, window) in windows.iter().enumerate() {
+            if !take_below(&window.taken, window.limit) {
+                for window in &windows[..taken] {
+                    window.taken.fetch_sub(1, Ordering::SeqCst);
+                }
+                self.return_token();
+                return false;
+            }
+        }
+        true
+    }
+
+    //Waits for a token. Returns false if the deadline passed first.
+    pub fn take_token(&self, deadline: Option<Instant>) -> bool {
+        loop {
+            if self.try_take_token() {
+                return true;
+            }
+            if !self.returned_tokens.park(deadline, || self.has_token()) {
+                return self.try_take_token();
+            }
+        }
+    }
+
+    //Ready once a token is free. Another post can still take it first.
+    pub fn poll_token(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.has_token() {
+            return Poll::Ready(());
+        }
+        self.returned_tokens.register(cx);
+        if self.has_token() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    pub fn add_tokens(&self, count: usize) {
+        self.live_tokens.fetch_add(count, Ordering::SeqCst);
+    }
+
+    pub fn return_token(&self) {
+        self.live_tokens.fetch_sub(1, Ordering::SeqCst);
+        self.returned_tokens.unpark();
+    }
+
+    //The first stage refused a post, which gives back every token it took
+    pub fn cancel_token(&self) {
+        for window in self.windows.read().iter() {
+            window.taken.fetch_sub(1, Ordering::SeqCst);
+        }
+        self.return_token();
+    }
+
+    //Stages in pipeline order
+    pub fn stats(&self) -> PipelineStats {
+        PipelineStats {
+            stages: self
+                .stages
+                .lock()
+                .values()
+                .map(|counters| counters.snapshot())
+                .collect(),
+        }
+    }
+
+    //Replicas record their spans from here on
+    pub fn enable_tracing(&self) {
+        self.tracing.enable();
+    }
+
+    pub fn enable_latency(&self) {
+        self.tracing.enable_latency();
+    }
+
+    pub fn write_trace<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        //copied first, so replicas are not held up while the trace is written
+        let stages: Vec<_> = self
+            .stages
+            .lock()
+            .values()
+            .map(|counters| (counters.label(), counters.spans()))
+            .collect();
+        trace::write_chrome_trace(writer, &stages)
+    }
+
+    //Stages in pipeline order, as they described themselves when started
+    pub fn describe(&self) -> Topology {
+        Topology {
+            stages: self.topology.lock().values().cloned().collect(),
+        }
+    }
+}
+
+//Internals: what a block knows about its place in the pipeline
+#[derive(Clone)]
+pub struct StageContext {
+    pipeline: Arc<PipelineContext>,
+    index: usize,
+    name: Option<Arc<str>>,
+}
+
+impl StageContext {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    //The same stage, under the name it was given in its configuration
+    pub fn named(&self, name: Option<&str>) -> StageContext {
+        StageContext {
+            name: name.map(Arc::from),
+            ..self.clone()
+        }
+    }
+
+    //What the thread of a replica is called, the name of the stage or
+    //its position followed by the replica
+    pub fn thread_name(&self, replica: usize) -> String {
+        match self.name() {
+            Some(name) => format!("{}-{}", name, replica),
+            None => format!("stage{}-{}", self.index, replica),
+        }
+    }
+
+    //Records how the stage was built, for PipelineContext::describe
+    pub fn describe<T>(
+        &self,
+        mode: BlockMode,
+        input: &BlockInput<T>,
+        sink: bool,
+        on_runtime: bool,
+    ) {
+        let description = StageDescription {
+            index: self.index,
+            name: self.name().map(str::to_string),
+            mode,
+            replicas: mode.replicas(),
+            sink,
+            on_runtime,
+            queue: input.describe(),
+            capacity: input.capacity(),
+        };
+        self.pipeline
+            .topology
+            .lock()
+            .insert(self.index, description);
+    }
+
+    pub fn pipeline(&self) -> &Arc<PipelineContext> {
+        &self.pipeline
+    }
+
+    //Counters for the stage, shared by its replicas and reported by
+    //PipelineContext::stats, which also looks at the stage's input
+    pub fn counters<T: Send + 'static>(
+        &self,
+        replicas: usize,
+        input: &Arc<BlockInput<T>>,
+    ) -> Arc<StageCounters> {
+        let input = input.clone();
+        let counters = Arc::new(StageCounters::new(
+            self.index,
+            self.name().map(str::to_string),
+            replicas,
+            self.pipeline.tracing.clone(),
+            Box::new(move || input.len()),
+        ));
+        self.pipeline
+            .stages
+            .lock()
+            .insert(self.index, counters.clone());
+        counters
+    }
+
+    //Runs a stage callback, catching panics. Returns None if the callback
+    //panicked or if the pipeline already failed, in which case the item is
+    //not processed at all and the pipeline just drains until Stop.
+    pub fn guard<R, F: FnOnce() -> R>(&self, replica: usize, function: F) -> Option<R> {
+        if self.pipeline.has_failed() {
+            return None;
+        }
+        self.report(replica, panic::catch_unwind(AssertUnwindSafe(function)))
+    }
+
+    //Like guard, for the futures of async stages
+    #[cfg(feature = "tokio")]
+    pub async fn guard_async<R, F: Future<Output = R>>(
+        &self,
+        replica: usize,
+        future: F,
+    ) -> Option<R> {
+        if self.pipeline.has_failed() {
+            return None;
+        }
+        self.report(replica, CatchUnwind(Box::pin(future)).await)
+    }
+
+    fn report<R>(&self, replica: usize, result: std::thread::Result<R>) -> Option<R> {
+        match result {
+            Ok(result) => Some(result),
+            Err(payload) => {
+                self.pipeline.fail(PipelineError {
+                    stage: self.index,
+                    stage_name: self.name().map(str::to_string),
+                    replica,
+                    payload,
+                });
+                None
+            }
+        }
+    }
+}
+
+//Catches a panic in any poll of the future
+#[cfg(feature = "tokio")]
+struct CatchUnwind<F>(Pin<Box<F>>);
+
+#[cfg(feature = "tokio")]
+impl<F: Future> Future for CatchUnwind<F> {
+    type Output = std::thread::Result<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let future = self.0.as_mut();
+        match panic::catch_unwind(AssertUnwindSafe(|| future.poll(cx))) {
+            Ok(Poll::Pending) => Poll::Pending,
+            Ok(Poll::Ready(result)) => Poll::Ready(Ok(result)),
+            Err(payload) => Poll::Ready(Err(payload)),
+        }
+    }
+}
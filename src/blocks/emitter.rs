@@ -0,0 +1,219 @@
+use crate::blocks::*;
+use crate::work_storage::*;
+use std::error::Error;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+//Internals: what a stage produced for one input, see Transform
+pub enum StageOutput<T> {
+    Single(Option<T>),
+    Many(Vec<T>),
+    Error(Box<dyn Error + Send + Sync>),
+}
+
+//Internals: the values produced for one input item
+pub enum Outputs<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+//Internals: sends what the replicas of a block produce to the next stage.
+//With a reorder buffer the items leave in order. The values a stage emits
+//for one input keep its order with their index below it, see Order, so
+//they need no renumbering and unordered stages pass them on as they come.
+//Items that come out of nothing take their token here, see PipelineContext.
+pub struct Emitter<TOutput, TCollected, TNextStep> {
+    next_step: Arc<TNextStep>,
+    context: Arc<PipelineContext>,
+    counters: Arc<StageCounters>,
+    reorder_buffer: Option<Arc<ReorderBuffer<Outputs<TOutput>>>>,
+    _params: PhantomData<fn() -> TCollected>,
+}
+
+impl<TOutput, TCollected, TNextStep> Clone for Emitter<TOutput, TCollected, TNextStep> {
+    fn clone(&self) -> Self {
+        Emitter {
+            next_step: self.next_step.clone(),
+            context: self.context.clone(),
+            counters: self.counters.clone(),
+            reorder_buffer: self.reorder_buffer.clone(),
+            _params: PhantomData,
+        }
+    }
+}
+
+impl<TOutput, TCollected, TNextStep: PipelineBlock<TOutput, TCollected>>
+    Emitter<TOutput, TCollected, TNextStep>
+{
+    pub fn new(
+        next_step: Arc<TNextStep>,
+        context: Arc<PipelineContext>,
+        counters: Arc<StageCounters>,
+        ordered: bool,
+    ) -> Emitter<TOutput, TCollected, TNextStep> {
+        Emitter {
+            next_step,
+            context,
+            counters,
+            reorder_buffer: if ordered {
+                Some(ReorderBuffer::new())
+            } else {
+                None
+            },
+            _params: PhantomData,
+        }
+    }
+
+    pub fn emit(&self, item: TimestampedWorkItem<Outputs<TOutput>>) {
+        match &self.reorder_buffer {
+            Some(buffer) => {
+                let pending = buffer.push(item, |item| self.send(item));
+                self.counters.reorder_buffer(pending);
+            }
+            None => self.send(item),
+        }
+    }
+
+    //Sends what the stage flushed, numbered from the order of the Stop on,
+    //and then the Stop itself. Only called once every item went through emit
+    pub fn emit_end(&self, order: Order, flushed: Vec<TOutput>) {
+        let (flushed, stop) = self.number_flushed(order, flushed);
+        for item in flushed {
+            self.emit(item);
+        }
+        self.send(stop);
+        self.counters.end();
+    }
+
+    //Numbers what the stage flushed from the order of the Stop on, and
+    //gives the Stop that comes after it
+    fn number_flushed(
+        &self,
+        order: Order,
+        flushed: Vec<TOutput>,
+    ) -> (
+        Vec<TimestampedWorkItem<Outputs<TOutput>>>,
+        TimestampedWorkItem<Outputs<TOutput>>,
+    ) {
+        self.context.add_tokens(flushed.len());
+        self.counters.emitted(flushed.len());
+        let start = order.item;
+        let count = flushed.len() as u64;
+        let flushed = (start..)
+            .zip(flushed)
+            .map(|(order, value)| {
+                TimestampedWorkItem(
+                    WorkItem::Value(Outputs::One(value)),
+                    Order::new(order),
+                    None,
+                )
+            })
+            .collect();
+        (
+            flushed,
+            TimestampedWorkItem::stop(Order::new(start + count)),
+        )
+    }
+
+    //Outputs keep the ingress time of their item. A value has left the
+    //stage once the next one took it.
+    fn send(&self, item: TimestampedWorkItem<Outputs<TOutput>>) {
+        self.split(item, |item| {
+            let (is_value, ingress) = (matches!(item.0, WorkItem::Value(_)), item.2);
+            self.next_step.process_timestamped(item);
+            if is_value {
+                self.counters.left(ingress);
+            }
+        });
+    }
+
+    //Hands the items the next stage gets for the item to forward, one per
+    //value
+    fn split(
+        &self,
+        item: TimestampedWorkItem<Outputs<TOutput>>,
+        mut forward: impl FnMut(TimestampedWorkItem<TOutput>),
+    ) {
+        let TimestampedWorkItem(work_item, order, ingress) = item;
+        let forwarded = match work_item {
+            WorkItem::Value(Outputs::One(value)) => WorkItem::Value(value),
+            WorkItem::Value(Outputs::Many(values)) => {
+                //no outputs: the next stages still see the order go by
+                if values.is_empty() {
+                    forward(TimestampedWorkItem(WorkItem::Dropped, order, ingress));
+                    return;
+                }
+                let count = values.len();
+                self.context.add_tokens(count - 1);
+                for (index, value) in values.into_iter().enumerate() {
+                    forward(TimestampedWorkItem(
+                        WorkItem::Value(value),
+                        order.child(index, index == count - 1),
+                        ingress,
+                    ));
+                }
+                return;
+            }
+            WorkItem::Dropped => WorkItem::Dropped,
+            WorkItem::Failed(failure) => WorkItem::Failed(failure),
+            WorkItem::Stop => WorkItem::Stop,
+        };
+        forward(TimestampedWorkItem(forwarded, order, ingress));
+    }
+}
+
+//Async replicas wait for room in the next stage instead of blocking their
+//worker thread, which other tasks may need to free that room
+#[cfg(feature = "tokio")]
+impl<TOutput, TCollected, TNextStep: PipelineBlock<TOutput, TCollected>>
+    Emitter<TOutput, TCollected, TNextStep>
+{
+    pub async fn emit_async(&self, item: TimestampedWorkItem<Outputs<TOutput>>) {
+        let buffer = match &self.reorder_buffer {
+            Some(buffer) => buffer,
+            None => return self.send_async(item).await,
+        };
+        let mut ready = buffer.push_ready(item);
+        self.counters.reorder_buffer(buffer.len());
+        while !ready.is_empty() {
+            for item in ready {
+                self.send_async(item).await;
+            }
+            ready = buffer.take_ready();
+        }
+    }
+
+    //Same as emit_end
+    pub async fn emit_end_async(&self, order: Order, flushed: Vec<TOutput>) {
+        let (flushed, stop) = self.number_flushed(order, flushed);
+        for item in flushed {
+            self.emit_async(item).await;
+        }
+        self.send(stop);
+        self.counters.end();
+    }
+
+    async fn send_async(&self, item: TimestampedWorkItem<Outputs<TOutput>>) {
+        let mut forwarded = vec![];
+        self.split(item, |item| forwarded.push(item));
+        for mut item in forwarded {
+            let (is_value, ingress) = (matches!(item.0, WorkItem::Value(_)), item.2);
+            loop {
+                match self.next_step.try_process_timestamped(item) {
+                    Ok(()) => break,
+                    Err(mut refused) => {
+                        //held mutably, so the future is Send without the
+                        //values being Sync
+                        let waiting = &mut refused;
+                        std::future::poll_fn(move |cx| self.next_step.poll_space(waiting, cx))
+                            .await;
+                        item = refused;
+                    }
+                }
+            }
+            if is_value {
+                self.counters.left(ingress);
+            }
+        }
+    }
+}
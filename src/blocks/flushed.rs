@@ -0,0 +1,28 @@
+use parking_lot::Mutex;
+use std::sync::Arc;
+
+//Internals: what the replicas of a block flushed at the end of the stream.
+//The last replica to stop takes all of it, in replica order, so trailing
+//items get the same sequence numbers on every run.
+pub struct Flushed<T> {
+    replicas: Mutex<Vec<Vec<T>>>,
+}
+
+impl<T> Flushed<T> {
+    pub fn new(replicas: usize) -> Arc<Flushed<T>> {
+        Arc::new(Flushed {
+            replicas: Mutex::new((0..replicas).map(|_| vec![]).collect()),
+        })
+    }
+
+    pub fn store(&self, replica: usize, items: Vec<T>) {
+        self.replicas.lock()[replica] = items;
+    }
+
+    pub fn take(&self) -> Vec<T> {
+        std::mem::take(&mut *self.replicas.lock())
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+}
@@ -1,14 +1,20 @@
 use crate::blocks::*;
 use crate::*;
-use parking_lot::Mutex;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use work_storage::{BlockingOrderedSet, BlockingQueue};
-use work_storage::{TimestampedWorkItem, WorkItem};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use work_storage::{Order, TimestampedWorkItem, WorkItem};
 
 //Public API: An output node, receives values and causes side effects
 pub trait In<TInput, TCollected = ()> {
     fn process(&mut self, input: TInput, order: u64) -> TCollected;
+
+    //Called once after the last item. What it returns is collected
+    //after everything else, replicas in replica order
+    fn flush(&mut self) -> Vec<TCollected> {
+        vec![]
+    }
 }
 
 impl<TInput, TCollected, F> In<TInput, TCollected> for F
@@ -20,14 +26,18 @@ where
     }
 }
 
-//Internals: InBlock processing queue for blocks in the pipeline
+//Internals: InBlock processing queue for blocks in the pipeline.
+//Sinks can be replicated. Replicated sinks run their handlers in no
+//particular order. For an ordered farm (parallel_ordered!) the results are
+//put back in timestamp order before they are handed out, otherwise they
+//come out in the order they were produced.
 pub struct InBlock<TInput, TCollected, TFactory> {
-    work_queue: Arc<BlockingQueue<TInput>>,
-    ordered_work: Arc<BlockingOrderedSet<TInput>>,
-    collected_items: Arc<Mutex<Vec<TCollected>>>,
+    input: Arc<BlockInput<TInput>>,
+    output: SinkOutput<TCollected>,
     handler: TFactory,
-    ordering: OrderingMode,
-    counter: AtomicUsize,
+    replicas: i32,
+    mode: BlockMode,
+    name: Option<String>,
 }
 
 impl<TInput, TCollected, TFactory> PipelineBlock<TInput, TCollected>
@@ -35,45 +45,57 @@ impl<TInput, TCollected, TFactory> PipelineBlock<TInput, TCollected>
 {
     //used by the public API
     fn process(&self, input: WorkItem<TInput>) {
-        match self.ordering {
-            //For the unordered case, just enqueue it
-            OrderingMode::Unordered => {
-                (*self.work_queue).enqueue(input);
-            }
-            //For the ordered case: All InBlocks are single threaded
-            //so we keep a count. Store under an atomic counter
-            //in case we implement a multithreaded outblock
-            OrderingMode::Ordered => {
-                let c = self.counter.load(Ordering::SeqCst);
-                (*self.ordered_work).enqueue(TimestampedWorkItem(input, c as u64));
-                self.counter.store(c + 1, Ordering::SeqCst);
-            }
-        };
-        ()
+        self.input.process(input);
     }
 
     //Used internally
     fn process_timestamped(&self, input: TimestampedWorkItem<TInput>) {
-        match self.ordering {
-            OrderingMode::Unordered => match input {
-                TimestampedWorkItem(work_item, _) => {
-                    (*self.work_queue).enqueue(work_item);
-                }
-            },
-            OrderingMode::Ordered => (*self.ordered_work).enqueue(input),
-        };
+        self.input.process_timestamped(input);
     }
 
-    fn collect(self) -> Vec<TCollected> {
-        match Arc::try_unwrap(self.collected_items) {
-            Ok(result) => result.into_inner(),
-            Err(_) => {
-                panic!("Could not unwrap Arc in call to collect");
-            }
-        }
+    fn try_process_timestamped(
+        &self,
+        input: TimestampedWorkItem<TInput>,
+    ) -> Result<(), TimestampedWorkItem<TInput>> {
+        self.input.try_process_timestamped(input)
+    }
+
+    fn poll_space(&self, input: &TimestampedWorkItem<TInput>, cx: &mut Context<'_>) -> Poll<()> {
+        self.input.poll_space_timestamped(input, cx)
+    }
+
+    fn output(&self) -> SinkOutput<TCollected> {
+        self.output.clone()
     }
 }
 
+//Runs the handler on a value. A panicking handler drops the item.
+//Once the handler is done with it the item has gone all the way through.
+//Handlers are given the posted item the value came from, which values a
+//flat-map stage emitted for the same input share.
+fn consume<TInput, TCollected, THandler: In<TInput, TCollected>>(
+    context: &StageContext,
+    counters: &StageCounters,
+    replica: usize,
+    handler: &mut THandler,
+    value: TInput,
+    order: Order,
+    ingress: Option<Instant>,
+) -> TimestampedWorkItem<TCollected> {
+    let started = Instant::now();
+    let collected = context.guard(replica, || handler.process(value, order.item));
+    counters.busy_since(replica, started, order.item);
+    let work_item = match collected {
+        Some(collected) => WorkItem::Value(collected),
+        None => WorkItem::Dropped,
+    };
+    counters.consumed(&work_item);
+    if let WorkItem::Value(_) = work_item {
+        counters.left(ingress);
+    }
+    TimestampedWorkItem(work_item, order, ingress)
+}
+
 impl<
         TInput: 'static + Send,
         TCollected: 'static + Send,
@@ -81,66 +103,147 @@ impl<
         TFactory: FnMut() -> THandler,
     > InBlock<TInput, TCollected, TFactory>
 {
-    pub fn monitor_posts(&mut self) -> MonitorLoop {
-        match self.ordering {
-            OrderingMode::Ordered => self.monitor_ordered(),
-            OrderingMode::Unordered => self.monitor_unordered(),
+    pub fn monitor_posts(&mut self, context: &StageContext) -> Vec<MonitorLoop> {
+        let context = &context.named(self.name.as_deref());
+        context.describe(self.mode, &self.input, true, false);
+        self.input.attach(context);
+        match self.input.ordering() {
+            OrderingMode::Ordered => vec![self.monitor_ordered(context)],
+            OrderingMode::Unordered => self.monitor_unordered(context),
         }
     }
 
-    fn monitor_unordered(&mut self) -> MonitorLoop {
-        let queue = self.work_queue.clone();
+    fn monitor_unordered(&mut self, context: &StageContext) -> Vec<MonitorLoop> {
+        let alive_threads = Arc::new(AtomicUsize::new(self.replicas as usize));
+        let flushed = Flushed::new(self.replicas as usize);
+        let counters = context.counters(self.replicas as usize, &self.input);
 
-        let mut handler = (self.handler)();
+        (0..self.replicas as usize)
+            .map(|replica| {
+                let context = context.clone();
+                let counters = counters.clone();
+                let queue = self.input.queue(replica);
+                let output = self.output.clone();
+                let alive_threads = alive_threads.clone();
+                let flushed = flushed.clone();
+                let mut handler = (self.handler)();
+                let thread_name = context.thread_name(replica);
 
-        let arc_collected = self.collected_items.clone();
+                MonitorLoop::new(move || loop {
+                    let waiting = Instant::now();
+                    let item = queue.wait_and_dequeue();
+                    counters.idle_since(replica, waiting, item.1.item);
+                    counters.queue_len(queue.len() + 1);
 
-        MonitorLoop::new(move || {
-            let mut collected_list = arc_collected.lock();
-            loop {
-                let item = queue.wait_and_dequeue();
-                match item {
-                    TimestampedWorkItem(WorkItem::Value(val), order) => {
-                        let collected = handler.process(val, order);
-                        (*collected_list).push(collected);
-                    }
-                    TimestampedWorkItem(WorkItem::Dropped, _order) => (),
-                    TimestampedWorkItem(WorkItem::Stop, _) => {
-                        break;
-                    }
-                };
-            }
-        })
+                    let pending = match item {
+                        TimestampedWorkItem(WorkItem::Value(val), order, ingress) => {
+                            output.push(consume(
+                                &context,
+                                &counters,
+                                replica,
+                                &mut handler,
+                                val,
+                                order,
+                                ingress,
+                            ))
+                        }
+                        TimestampedWorkItem(WorkItem::Dropped, order, ingress) => {
+                            output.push(TimestampedWorkItem(WorkItem::Dropped, order, ingress))
+                        }
+                        TimestampedWorkItem(WorkItem::Failed(failure), order, ingress) => output
+                            .push(TimestampedWorkItem(
+                                WorkItem::Failed(failure),
+                                order,
+                                ingress,
+                            )),
+                        TimestampedWorkItem(WorkItem::Stop, order, _) => {
+                            let items = context.guard(replica, || handler.flush());
+                            let items = items.unwrap_or_default();
+                            counters.emitted(items.len());
+                            flushed.store(replica, items);
+
+                            //the last replica to stop ends the output
+                            if alive_threads.fetch_sub(1, Ordering::SeqCst) == 1 {
+                                output.finish(order.clone(), flushed.take());
+                                counters.end();
+                            }
+
+                            //other replicas need to see it too
+                            queue.pass_stop(order);
+                            break;
+                        }
+                    };
+                    counters.reorder_buffer(pending);
+                    //the item left the pipeline
+                    context.pipeline().return_token();
+                })
+                .named(thread_name)
+            })
+            .collect()
     }
 
-    pub fn monitor_ordered(&mut self) -> MonitorLoop {
-        let storage = self.ordered_work.clone();
+    pub fn monitor_ordered(&mut self, context: &StageContext) -> MonitorLoop {
+        let context = context.clone();
+        let storage = self.input.ordered_set();
+        let output = self.output.clone();
+        //posts wait instead of replicas, see BlockingOrderedRing
+        let window = storage
+            .window()
+            .map(|window| context.pipeline().add_window(window));
+        let counters = context.counters(1, &self.input);
+        let thread_name = context.thread_name(0);
 
         let mut handler = (self.handler)();
 
-        let arc_collected = self.collected_items.clone();
-
         MonitorLoop::new(move || {
-            let mut next_item = 0;
-            let mut collected_list = arc_collected.lock();
+            let mut next_item = Order::new(0);
             loop {
-                let item = storage.wait_and_remove(next_item);
+                let waiting = Instant::now();
+                let item = storage.wait_and_remove(&next_item);
+                counters.idle_since(0, waiting, item.1.item);
+                next_item = item.1.next();
+                if let Some(window) = window {
+                    if next_item.item > item.1.item && !matches!(item.0, WorkItem::Stop) {
+                        context.pipeline().pass_window(window);
+                    }
+                }
+                //what is left arrived ahead of its turn
+                let ahead = storage.len();
+                counters.queue_len(ahead + 1);
+                counters.reorder_buffer(ahead);
+
                 match item {
-                    TimestampedWorkItem(WorkItem::Value(val), order) => {
-                        debug_assert!(order == next_item);
-                        next_item += 1;
-                        let collected: TCollected = handler.process(val, order);
-                        (*collected_list).push(collected);
+                    TimestampedWorkItem(WorkItem::Value(val), order, ingress) => {
+                        output.push(consume(
+                            &context,
+                            &counters,
+                            0,
+                            &mut handler,
+                            val,
+                            order,
+                            ingress,
+                        ));
                     }
-                    TimestampedWorkItem(WorkItem::Dropped, _order) => {
-                        next_item += 1;
+                    TimestampedWorkItem(WorkItem::Dropped, ..) => {}
+                    TimestampedWorkItem(WorkItem::Failed(failure), order, ingress) => {
+                        output.push(TimestampedWorkItem(
+                            WorkItem::Failed(failure),
+                            order,
+                            ingress,
+                        ));
                     }
-                    TimestampedWorkItem(WorkItem::Stop, _) => {
+                    TimestampedWorkItem(WorkItem::Stop, order, _) => {
+                        let flushed = context.guard(0, || handler.flush()).unwrap_or_default();
+                        counters.emitted(flushed.len());
+                        output.finish(order, flushed);
+                        counters.end();
                         break;
                     }
                 };
+                context.pipeline().return_token();
             }
         })
+        .named(thread_name)
     }
 }
 
@@ -151,17 +254,28 @@ impl<
         TFactory: FnMut() -> THandler,
     > InBlock<TInput, TCollected, TFactory>
 {
-    pub fn new(behavior: BlockMode, factory: TFactory) -> InBlock<TInput, TCollected, TFactory> {
-        match behavior {
-            BlockMode::Parallel(_) => unimplemented!("parallel inblocks not implemented"),
-            BlockMode::Sequential(ordering) => InBlock {
-                work_queue: BlockingQueue::new(),
-                handler: factory,
-                ordering: ordering,
-                ordered_work: BlockingOrderedSet::new(),
-                counter: AtomicUsize::new(0),
-                collected_items: Arc::new(Mutex::new(vec![])),
-            },
+    pub fn new(
+        behavior: impl Into<BlockConfig<TInput>>,
+        factory: TFactory,
+    ) -> InBlock<TInput, TCollected, TFactory> {
+        let mut config = behavior.into();
+        let (mode, name) = (config.mode, config.name.take());
+        let (ordering, replicas, ordered_output) = match config.mode {
+            BlockMode::Sequential(ordering) => (ordering, 1, false),
+            BlockMode::Parallel(replicas) => (OrderingMode::Unordered, replicas, false),
+            BlockMode::OrderedParallel(replicas) => (OrderingMode::Unordered, replicas, true),
+        };
+        let input = match ordering {
+            OrderingMode::Ordered => BlockInput::ordered(config),
+            OrderingMode::Unordered => BlockInput::for_farm(replicas as usize, config),
+        };
+        InBlock {
+            input: Arc::new(input),
+            output: SinkOutput::new(ordered_output),
+            handler: factory,
+            replicas,
+            mode,
+            name,
         }
     }
 }
@@ -1,11 +1,21 @@
 use crate::blocks::*;
 use crate::work_storage::*;
+use std::error::Error;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::Instant;
 use std::{marker::PhantomData, sync::Arc};
 
 // Public API: A Input-Output node; transforms some value into another
 pub trait InOut<TInput, TOutput> {
     fn process(&mut self, input: TInput) -> Option<TOutput>;
+
+    //Called once after the last item, for stages that hold on to data.
+    //What it returns is sent downstream after everything else the stage
+    //produced. Replicas of a farm are flushed in replica order.
+    fn flush(&mut self) -> Vec<TOutput> {
+        vec![]
+    }
 }
 
 impl<TInput, TOutput, F> InOut<TInput, TOutput> for F
@@ -17,85 +27,304 @@ where
     }
 }
 
+//Internals: what the replicas of a middle stage call. Every InOut is one,
+//and so are the Fallible and FlatMap wrappers, whose errors and extra
+//outputs travel downstream. The kind keeps the three implementations
+//apart, since the wrappers could be given an InOut implementation too.
+pub trait Transform<TInput, TOutput, TKind> {
+    fn process_item(&mut self, input: TInput) -> StageOutput<TOutput>;
+    fn flush(&mut self) -> Vec<TOutput>;
+}
+
+//The kinds of Transform
+pub enum SingleOutput {}
+pub enum ManyOutputs {}
+pub enum FallibleOutput {}
+
+impl<TInput, TOutput, TStage> Transform<TInput, TOutput, SingleOutput> for TStage
+where
+    TStage: InOut<TInput, TOutput>,
+{
+    fn process_item(&mut self, input: TInput) -> StageOutput<TOutput> {
+        StageOutput::Single(self.process(input))
+    }
+
+    fn flush(&mut self) -> Vec<TOutput> {
+        InOut::flush(self)
+    }
+}
+
+// Public API: A Input-Output node that can fail. Wrap it with fallible()
+// to use it in a pipeline: errors are not dropped, they travel downstream
+// with the sequence number of the item and end up in collect_with_failures
+pub trait TryInOut<TInput, TOutput> {
+    type Error: Error + Send + Sync + 'static;
+    fn try_process(&mut self, input: TInput) -> Result<Option<TOutput>, Self::Error>;
+
+    fn flush(&mut self) -> Vec<TOutput> {
+        vec![]
+    }
+}
+
+impl<TInput, TOutput, TError, F> TryInOut<TInput, TOutput> for F
+where
+    F: FnMut(TInput) -> Result<Option<TOutput>, TError>,
+    TError: Error + Send + Sync + 'static,
+{
+    type Error = TError;
+
+    fn try_process(&mut self, input: TInput) -> Result<Option<TOutput>, TError> {
+        (*self)(input)
+    }
+}
+
+pub struct Fallible<TStage>(pub TStage);
+
+pub fn fallible<TStage>(stage: TStage) -> Fallible<TStage> {
+    Fallible(stage)
+}
+
+impl<TInput, TOutput, TStage> Transform<TInput, TOutput, FallibleOutput> for Fallible<TStage>
+where
+    TStage: TryInOut<TInput, TOutput>,
+{
+    fn process_item(&mut self, input: TInput) -> StageOutput<TOutput> {
+        match self.0.try_process(input) {
+            Ok(output) => StageOutput::Single(output),
+            Err(error) => StageOutput::Error(error.into()),
+        }
+    }
+
+    fn flush(&mut self) -> Vec<TOutput> {
+        self.0.flush()
+    }
+}
+
+// Public API: A node that emits zero, one or many values for every input,
+// such as splitting a file into lines. Wrap it with flat_map() to use it in
+// a pipeline. The outputs of an item are kept together and in the order they
+// were returned, and items leave the stage in the order they were posted,
+// so collect_ordered! stays deterministic.
+pub trait InOutMany<TInput, TOutput> {
+    type Output: IntoIterator<Item = TOutput>;
+    fn process(&mut self, input: TInput) -> Self::Output;
+
+    fn flush(&mut self) -> Vec<TOutput> {
+        vec![]
+    }
+}
+
+impl<TInput, TOutput, TIter, F> InOutMany<TInput, TOutput> for F
+where
+    F: FnMut(TInput) -> TIter,
+    TIter: IntoIterator<Item = TOutput>,
+{
+    type Output = TIter;
+
+    fn process(&mut self, input: TInput) -> TIter {
+        (*self)(input)
+    }
+}
+
+pub struct FlatMap<TStage>(pub TStage);
+
+pub fn flat_map<TStage>(stage: TStage) -> FlatMap<TStage> {
+    FlatMap(stage)
+}
+
+impl<TInput, TOutput, TStage> Transform<TInput, TOutput, ManyOutputs> for FlatMap<TStage>
+where
+    TStage: InOutMany<TInput, TOutput>,
+{
+    fn process_item(&mut self, input: TInput) -> StageOutput<TOutput> {
+        StageOutput::Many(self.0.process(input).into_iter().collect())
+    }
+
+    fn flush(&mut self) -> Vec<TOutput> {
+        self.0.flush()
+    }
+}
+
 //Internals: Processing queue for inout blocks in the pipeline
 pub struct InOutBlock<
     TInput,
     TOutput,
     TCollected,
-    TStage: InOut<TInput, TOutput> + Send,
+    TStage: Transform<TInput, TOutput, TKind> + Send,
+    TKind,
     TFactory: FnMut() -> TStage,
     TNextStep: PipelineBlock<TOutput, TCollected> + Send,
 > {
-    work_queue: Arc<BlockingQueue<TInput>>,
+    input: Arc<BlockInput<TInput>>,
     next_step: Arc<TNextStep>,
     transformer_factory: TFactory,
     replicas: i32,
+    ordered_output: bool,
+    mode: BlockMode,
+    name: Option<String>,
     _params: std::marker::PhantomData<(TOutput, TCollected)>,
-}
-
-impl<
-        TInput,
-        TOutput,
-        TCollected,
-        TStage: InOut<TInput, TOutput> + Send,
-        TFactory: FnMut() -> TStage,
-        TNextStep: PipelineBlock<TOutput, TCollected> + Send + Sync,
-    > InOutBlock<TInput, TOutput, TCollected, TStage, TFactory, TNextStep>
-{
-    pub fn send_stop(&self) {
-        (*self.work_queue).enqueue(WorkItem::Stop);
-    }
+    _kind: PhantomData<TKind>,
 }
 
 impl<
         TInput: 'static,
         TOutput: 'static,
         TCollected: 'static,
-        TStage: InOut<TInput, TOutput> + Send + 'static,
+        TStage: Transform<TInput, TOutput, TKind> + Send + 'static,
+        TKind: 'static,
         TFactory: FnMut() -> TStage,
         TNextStep: PipelineBlock<TOutput, TCollected> + Send + Sync,
     > PipelineBlock<TInput, TCollected>
-    for InOutBlock<TInput, TOutput, TCollected, TStage, TFactory, TNextStep>
+    for InOutBlock<TInput, TOutput, TCollected, TStage, TKind, TFactory, TNextStep>
 {
-    //used by the public API. Always unordered
+    //used by the public API
     fn process(&self, input: WorkItem<TInput>) {
-        (*self.work_queue).enqueue(input);
+        self.input.process(input);
     }
 
     //Used internally
     fn process_timestamped(&self, input: TimestampedWorkItem<TInput>) {
-        (*self.work_queue).enqueue_timestamped(input)
+        self.input.process_timestamped(input);
     }
 
-    fn collect(self) -> Vec<TCollected> {
-        match Arc::try_unwrap(self.next_step) {
-            Ok(result) => result.collect(),
-            Err(_) => {
-                panic!("Could not unwrap Arc in call to collect");
-            }
+    fn try_process_timestamped(
+        &self,
+        input: TimestampedWorkItem<TInput>,
+    ) -> Result<(), TimestampedWorkItem<TInput>> {
+        self.input.try_process_timestamped(input)
+    }
+
+    fn poll_space(&self, input: &TimestampedWorkItem<TInput>, cx: &mut Context<'_>) -> Poll<()> {
+        self.input.poll_space_timestamped(input, cx)
+    }
+
+    fn output(&self) -> SinkOutput<TCollected> {
+        self.next_step.output()
+    }
+}
+
+impl<
+        TInput: 'static + Send,
+        TOutput: 'static + Send,
+        TCollected: 'static,
+        TStage: Transform<TInput, TOutput, TKind> + Send + 'static,
+        TKind: 'static,
+        TFactory: FnMut() -> TStage,
+        TNextStep: PipelineBlock<TOutput, TCollected> + Send + Sync + 'static,
+    > StageBlock<TInput>
+    for InOutBlock<TInput, TOutput, TCollected, TStage, TKind, TFactory, TNextStep>
+{
+    fn input(&self) -> Arc<BlockInput<TInput>> {
+        self.input.clone()
+    }
+
+    fn monitor_posts(&mut self, context: &StageContext) -> Vec<MonitorLoop> {
+        let context = &context.named(self.name.as_deref());
+        context.describe(self.mode, &self.input, false, false);
+        self.input.attach(context);
+        match self.input.ordering() {
+            OrderingMode::Ordered => vec![self.monitor_ordered(context)],
+            OrderingMode::Unordered => self.monitor_unordered(context),
         }
     }
 }
 
 impl<
         TInput: 'static + Send,
-        TOutput: 'static,
+        TOutput: 'static + Send,
+        TCollected: 'static,
+        TStage: Transform<TInput, TOutput, TKind> + Send + 'static,
+        TKind: 'static,
+        TFactory: FnMut() -> TStage,
+        TNextStep: PipelineBlock<TOutput, TCollected> + Send + Sync + 'static,
+    > IntoBlock<TInput, TOutput, TCollected, TFactory, TNextStep, TKind> for BlockConfig<TInput>
+{
+    type Block = InOutBlock<TInput, TOutput, TCollected, TStage, TKind, TFactory, TNextStep>;
+
+    fn into_block(self, next_step: TNextStep, factory: TFactory) -> Self::Block {
+        InOutBlock::new(next_step, self, factory)
+    }
+}
+
+impl<
+        TInput: 'static + Send,
+        TOutput: 'static + Send,
+        TCollected: 'static,
+        TStage: Transform<TInput, TOutput, TKind> + Send + 'static,
+        TKind: 'static,
+        TFactory: FnMut() -> TStage,
+        TNextStep: PipelineBlock<TOutput, TCollected> + Send + Sync + 'static,
+    > IntoBlock<TInput, TOutput, TCollected, TFactory, TNextStep, TKind> for BlockMode
+{
+    type Block = InOutBlock<TInput, TOutput, TCollected, TStage, TKind, TFactory, TNextStep>;
+
+    fn into_block(self, next_step: TNextStep, factory: TFactory) -> Self::Block {
+        InOutBlock::new(next_step, self, factory)
+    }
+}
+
+//Runs the stage on a value, keeping the timestamp of the input.
+//A panicking stage drops the item so later stages don't wait for it.
+//Errors of fallible stages are tagged with the stage and sent along.
+fn transform<TInput, TOutput, TKind, TStage: Transform<TInput, TOutput, TKind>>(
+    context: &StageContext,
+    counters: &StageCounters,
+    replica: usize,
+    transformer: &mut TStage,
+    value: TInput,
+    order: Order,
+    ingress: Option<Instant>,
+) -> TimestampedWorkItem<Outputs<TOutput>> {
+    let started = Instant::now();
+    let output = context.guard(replica, || transformer.process_item(value));
+    counters.busy_since(replica, started, order.item);
+    let work_item = match output {
+        Some(StageOutput::Single(Some(output))) => WorkItem::Value(Outputs::One(output)),
+        Some(StageOutput::Single(None)) | None => WorkItem::Dropped,
+        Some(StageOutput::Many(outputs)) => WorkItem::Value(Outputs::Many(outputs)),
+        Some(StageOutput::Error(error)) => WorkItem::Failed(ItemFailure {
+            stage: context.index(),
+            stage_name: context.name().map(str::to_string),
+            order: order.clone(),
+            error,
+        }),
+    };
+    counters.processed(&work_item);
+    TimestampedWorkItem(work_item, order, ingress)
+}
+
+impl<
+        TInput: 'static + Send,
+        TOutput: 'static + Send,
         TCollected: 'static,
-        TStage: InOut<TInput, TOutput> + Send + 'static,
+        TStage: Transform<TInput, TOutput, TKind> + Send + 'static,
+        TKind: 'static,
         TFactory: FnMut() -> TStage,
         TNextStep: PipelineBlock<TOutput, TCollected> + Send + Sync + 'static,
-    > InOutBlock<TInput, TOutput, TCollected, TStage, TFactory, TNextStep>
+    > InOutBlock<TInput, TOutput, TCollected, TStage, TKind, TFactory, TNextStep>
 {
     pub fn new(
         next_step: TNextStep,
-        transformer: BlockMode,
+        transformer: impl Into<BlockConfig<TInput>>,
         transformer_factory: TFactory,
-    ) -> InOutBlock<TInput, TOutput, TCollected, TStage, TFactory, TNextStep> {
-        match transformer {
-            BlockMode::Parallel(replicas) => {
-                InOutBlock::new_block(next_step, transformer_factory, replicas)
-            }
-            BlockMode::Sequential(_) => InOutBlock::new_block(next_step, transformer_factory, 1),
+    ) -> InOutBlock<TInput, TOutput, TCollected, TStage, TKind, TFactory, TNextStep> {
+        let mut config = transformer.into();
+        let (mode, name) = (config.mode, config.name.take());
+        let (ordering, replicas, ordered_output) = match config.mode {
+            BlockMode::Sequential(ordering) => (ordering, 1, false),
+            BlockMode::Parallel(replicas) => (OrderingMode::Unordered, replicas, false),
+            BlockMode::OrderedParallel(replicas) => (OrderingMode::Unordered, replicas, true),
+        };
+        let input = match ordering {
+            OrderingMode::Ordered => BlockInput::ordered(config),
+            OrderingMode::Unordered => BlockInput::for_farm(replicas as usize, config),
+        };
+        InOutBlock {
+            input: Arc::new(input),
+            ordered_output,
+            mode,
+            name,
+            ..InOutBlock::new_block(next_step, transformer_factory, replicas, None)
         }
     }
 
@@ -103,76 +332,173 @@ impl<
         next_step: TNextStep,
         transformer: TFactory,
         replicas: i32,
-    ) -> InOutBlock<TInput, TOutput, TCollected, TStage, TFactory, TNextStep> {
+        capacity: Option<usize>,
+    ) -> InOutBlock<TInput, TOutput, TCollected, TStage, TKind, TFactory, TNextStep> {
         InOutBlock {
-            work_queue: BlockingQueue::new(),
+            input: Arc::new(BlockInput::new(OrderingMode::Unordered, capacity)),
             next_step: Arc::new(next_step),
             transformer_factory: transformer,
             replicas,
+            ordered_output: false,
+            mode: BlockMode::Parallel(replicas),
+            name: None,
             _params: PhantomData,
+            _kind: PhantomData,
         }
     }
 
-    pub fn monitor_posts(&mut self) -> Vec<MonitorLoop> {
+    fn emitter(
+        &self,
+        context: &StageContext,
+        counters: &Arc<StageCounters>,
+    ) -> Emitter<TOutput, TCollected, TNextStep> {
+        Emitter::new(
+            self.next_step.clone(),
+            context.pipeline().clone(),
+            counters.clone(),
+            self.ordered_output,
+        )
+    }
+
+    //Sequential stage that processes items in timestamp order
+    fn monitor_ordered(&mut self, context: &StageContext) -> MonitorLoop {
+        let context = context.clone();
+        let thread_name = context.thread_name(0);
+        let storage = self.input.ordered_set();
+        let counters = context.counters(1, &self.input);
+        //posts wait instead of replicas, see BlockingOrderedRing
+        let window = storage
+            .window()
+            .map(|window| context.pipeline().add_window(window));
+        let emitter = self.emitter(&context, &counters);
+        let mut transformer = (self.transformer_factory)();
+
+        MonitorLoop::new(move || {
+            let mut next_item = Order::new(0);
+            loop {
+                let waiting = Instant::now();
+                let item = storage.wait_and_remove(&next_item);
+                counters.idle_since(0, waiting, item.1.item);
+                next_item = item.1.next();
+                if let Some(window) = window {
+                    if next_item.item > item.1.item && !matches!(item.0, WorkItem::Stop) {
+                        context.pipeline().pass_window(window);
+                    }
+                }
+                //what is left arrived ahead of its turn
+                let ahead = storage.len();
+                counters.queue_len(ahead + 1);
+                counters.reorder_buffer(ahead);
+
+                match item {
+                    TimestampedWorkItem(WorkItem::Value(val), order, ingress) => {
+                        emitter.emit(transform(
+                            &context,
+                            &counters,
+                            0,
+                            &mut transformer,
+                            val,
+                            order,
+                            ingress,
+                        ));
+                    }
+                    TimestampedWorkItem(WorkItem::Dropped, order, ingress) => {
+                        emitter.emit(TimestampedWorkItem(WorkItem::Dropped, order, ingress));
+                    }
+                    TimestampedWorkItem(WorkItem::Failed(failure), order, ingress) => {
+                        emitter.emit(TimestampedWorkItem(
+                            WorkItem::Failed(failure),
+                            order,
+                            ingress,
+                        ));
+                    }
+                    TimestampedWorkItem(WorkItem::Stop, order, _) => {
+                        let flushed = context.guard(0, || transformer.flush());
+                        emitter.emit_end(order, flushed.unwrap_or_default());
+                        break;
+                    }
+                }
+            }
+        })
+        .named(thread_name)
+    }
+
+    //Replicas share one queue, unless the farm is keyed or balanced. For
+    //ordered farms their output goes through a reorder buffer before
+    //reaching the next stage
+    fn monitor_unordered(&mut self, context: &StageContext) -> Vec<MonitorLoop> {
         let mut monitors: Vec<MonitorLoop> = vec![];
         let alive_threads = Arc::new(AtomicUsize::new(self.replicas as usize));
+        let flushed = Flushed::new(self.replicas as usize);
+        let counters = context.counters(self.replicas as usize, &self.input);
+        let emitter = self.emitter(context, &counters);
 
-        for _ in 0..self.replicas {
-            let queue = self.work_queue.clone();
+        for replica in 0..self.replicas as usize {
+            let context = context.clone();
+            let counters = counters.clone();
+            let flushed = flushed.clone();
+            let queue = self.input.queue(replica);
             let alive_threads = alive_threads.clone();
-
-            let next_step = self.next_step.clone();
+            let emitter = emitter.clone();
             let mut transformer = (self.transformer_factory)();
+            let thread_name = context.thread_name(replica);
 
             let monitor_loop = MonitorLoop::new(move || {
                 loop {
+                    let waiting = Instant::now();
                     let dequeued = queue.wait_and_dequeue();
+                    counters.idle_since(replica, waiting, dequeued.1.item);
+                    counters.queue_len(queue.len() + 1);
 
                     match dequeued {
-                        TimestampedWorkItem(WorkItem::Value(val), order) => {
-                            let output = transformer.process(val);
-
-                            if let Some(val) = output {
-                                next_step.process_timestamped(TimestampedWorkItem(
-                                    WorkItem::Value(val),
-                                    order,
-                                ));
-                            } else {
-                                next_step.process_timestamped(TimestampedWorkItem(
-                                    WorkItem::Dropped,
-                                    order,
-                                ));
-                            }
+                        TimestampedWorkItem(WorkItem::Value(val), order, ingress) => {
+                            emitter.emit(transform(
+                                &context,
+                                &counters,
+                                replica,
+                                &mut transformer,
+                                val,
+                                order,
+                                ingress,
+                            ));
+                        }
+                        TimestampedWorkItem(WorkItem::Dropped, order, ingress) => {
+                            emitter.emit(TimestampedWorkItem(WorkItem::Dropped, order, ingress));
                         }
-                        TimestampedWorkItem(WorkItem::Dropped, order) => {
-                            next_step
-                                .process_timestamped(TimestampedWorkItem(WorkItem::Dropped, order));
+                        TimestampedWorkItem(WorkItem::Failed(failure), order, ingress) => {
+                            emitter.emit(TimestampedWorkItem(
+                                WorkItem::Failed(failure),
+                                order,
+                                ingress,
+                            ));
                         }
-                        TimestampedWorkItem(WorkItem::Stop, order) => {
-                            let mut threads = alive_threads.load(Ordering::SeqCst);
+                        TimestampedWorkItem(WorkItem::Stop, order, _) => {
+                            let items = context.guard(replica, || transformer.flush());
+                            flushed.store(replica, items.unwrap_or_default());
 
-                            threads -= 1;
+                            //replicas with queues of their own see their
+                            //Stops at the same time, so the count is
+                            //decremented atomically
+                            let threads = alive_threads.fetch_sub(1, Ordering::SeqCst) - 1;
 
+                            //all items went through the emitter by now,
+                            //since every replica has finished its last item
                             if threads == 0 {
-                                next_step.process_timestamped(TimestampedWorkItem(
-                                    WorkItem::Stop,
-                                    order,
-                                ));
+                                emitter.emit_end(order.clone(), flushed.take());
                             }
 
-                            alive_threads.store(threads, Ordering::SeqCst);
-
-                            //reenqueue the same item
-                            queue.enqueue_timestamped(dequeued);
+                            //other replicas need to see it too
+                            queue.pass_stop(order);
 
                             break;
                         }
                     }
                 }
-            });
+            })
+            .named(thread_name);
             monitors.push(monitor_loop);
         }
 
-        return monitors;
+        monitors
     }
 }
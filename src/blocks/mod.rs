@@ -1,7 +1,44 @@
+#[cfg(feature = "tokio")]
+pub mod async_block;
+pub mod block_input;
+#[allow(clippy::module_inception)]
 pub mod blocks;
+pub mod context;
+pub mod emitter;
+pub mod flushed;
 pub mod in_block;
 pub mod inout_block;
+pub mod latency;
+pub mod pipeline_input;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+pub mod sink_output;
+pub mod stats;
+pub mod topology;
+pub mod trace;
 
-pub use blocks::{BlockMode, MonitorLoop, OrderingMode, PipelineBlock};
+#[cfg(feature = "tokio")]
+pub use async_block::{AsyncConfig, AsyncInOut, AsyncInOutBlock};
+pub use block_input::{BlockInput, ReplicaQueue};
+pub use blocks::{
+    BlockConfig, BlockMode, Configure, IntoBlock, KeyHasher, LoadBalancing, MonitorLoop,
+    OrderedStorageFactory, OrderingMode, PipelineBlock, StageBlock, StorageFactory, Worker,
+};
+pub use context::{PipelineContext, PipelineError, StageContext};
+pub use emitter::{Emitter, Outputs, StageOutput};
+pub use flushed::Flushed;
 pub use in_block::{In, InBlock};
-pub use inout_block::{InOut, InOutBlock};
+pub use inout_block::{
+    fallible, flat_map, Fallible, FallibleOutput, FlatMap, InOut, InOutBlock, InOutMany,
+    ManyOutputs, SingleOutput, Transform, TryInOut,
+};
+pub use latency::{Histogram, LatencyRecorder};
+pub use pipeline_input::PipelineInput;
+#[cfg(feature = "prometheus")]
+pub use prometheus::MetricsServer;
+#[cfg(feature = "futures")]
+pub use sink_output::ResultStream;
+pub use sink_output::{Results, SinkOutput};
+pub use stats::{PipelineStats, ReplicaStats, StageCounters, StageStats};
+pub use topology::{StageDescription, Topology};
+pub use trace::{Span, SpanKind, Tracing};
@@ -0,0 +1,152 @@
+use crate::blocks::*;
+use crate::spp::{ItemPostError, PostTimeoutError, TryPostError};
+use crate::work_storage::WorkItem;
+use parking_lot::RwLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+//Internals: the entry of a pipeline, shared by the pipeline and its senders.
+//Posts hold the read lock while they enqueue and end takes the write lock,
+//so the Stop is always queued after every item that was accepted.
+//Every posted item takes its tokens first, and gives them back if the
+//first stage refused it.
+pub struct PipelineInput<TInput> {
+    input: Arc<BlockInput<TInput>>,
+    context: Arc<PipelineContext>,
+    ended: RwLock<bool>,
+    senders: AtomicUsize,
+}
+
+impl<TInput> PipelineInput<TInput> {
+    pub fn new(
+        input: Arc<BlockInput<TInput>>,
+        context: Arc<PipelineContext>,
+    ) -> Arc<PipelineInput<TInput>> {
+        Arc::new(PipelineInput {
+            input,
+            context,
+            ended: RwLock::new(false),
+            senders: AtomicUsize::new(0),
+        })
+    }
+
+    //Idempotent
+    pub fn end(&self) {
+        let mut ended = self.ended.write();
+        if !*ended {
+            *ended = true;
+            self.input.process(WorkItem::Stop);
+        }
+    }
+
+    //Values only get their ingress time when latency is measured
+    pub fn stamp_ingress(&self) {
+        self.input.stamp_ingress();
+    }
+
+    pub fn has_ended(&self) -> bool {
+        *self.ended.read()
+    }
+
+    pub fn add_sender(&self) {
+        self.senders.fetch_add(1, Ordering::SeqCst);
+    }
+
+    //The stream ends when the last sender goes away
+    pub fn remove_sender(&self) {
+        if self.senders.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.end();
+        }
+    }
+
+    //A pipeline that had a stage panic does not accept items anymore
+    fn accepts_items(&self, ended: bool) -> bool {
+        !ended && !self.context.has_failed()
+    }
+
+    pub fn post(&self, item: TInput) -> Result<(), ItemPostError> {
+        let ended = self.ended.read();
+        if !self.accepts_items(*ended) {
+            return Err(ItemPostError::StreamEnded);
+        }
+        self.context.take_token(None);
+        self.input.process(WorkItem::Value(item));
+        Ok(())
+    }
+
+    pub fn try_post(&self, item: TInput) -> Result<(), TryPostError<TInput>> {
+        let ended = self.ended.read();
+        if !self.accepts_items(*ended) {
+            return Err(TryPostError::StreamEnded(item));
+        }
+        if !self.context.try_take_token() {
+            return Err(TryPostError::Full(item));
+        }
+        self.input
+            .try_process(WorkItem::Value(item))
+            .map_err(|work_item| {
+                self.context.cancel_token();
+                TryPostError::Full(unwrap_value(work_item))
+            })
+    }
+
+    pub fn post_timeout(
+        &self,
+        item: TInput,
+        timeout: Duration,
+    ) -> Result<(), PostTimeoutError<TInput>> {
+        let deadline = Instant::now() + timeout;
+        let ended = self.ended.read();
+        if !self.accepts_items(*ended) {
+            return Err(PostTimeoutError::StreamEnded(item));
+        }
+        if !self.context.take_token(Some(deadline)) {
+            return Err(PostTimeoutError::Timeout(item));
+        }
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        self.input
+            .process_timeout(WorkItem::Value(item), timeout)
+            .map_err(|work_item| {
+                self.context.cancel_token();
+                PostTimeoutError::Timeout(unwrap_value(work_item))
+            })
+    }
+
+    //Ready once there is a token for the item and it would fit in the
+    //first stage
+    pub fn poll_space(&self, item: &WorkItem<TInput>, cx: &mut Context<'_>) -> Poll<()> {
+        match self.context.poll_token(cx) {
+            Poll::Ready(()) => self.input.poll_space(item, cx),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<TInput> PipelineInput<TInput> {
+    //Like post, but waits for room in the first stage without blocking
+    //the thread
+    pub async fn post_async(&self, item: TInput) -> Result<(), ItemPostError> {
+        let mut item = item;
+        loop {
+            match self.try_post(item) {
+                Ok(()) => return Ok(()),
+                Err(TryPostError::StreamEnded(_)) => return Err(ItemPostError::StreamEnded),
+                Err(TryPostError::Full(rejected)) => {
+                    let rejected = WorkItem::Value(rejected);
+                    std::future::poll_fn(|cx| self.poll_space(&rejected, cx)).await;
+                    item = unwrap_value(rejected);
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn unwrap_value<T>(item: WorkItem<T>) -> T {
+    match item {
+        WorkItem::Value(value) => value,
+        _ => panic!("Rejected work item was not a value"),
+    }
+}
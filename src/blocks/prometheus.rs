@@ -0,0 +1,288 @@
+use crate::blocks::*;
+use std::fmt::{self, Write as _};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+//How long a client gets to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/*
+ * Serves the stats of a pipeline in the Prometheus text format, on GET
+ * /metrics. A thread of its own accepts the connections and answers each
+ * one from a short-lived thread, so a client that never sends its request
+ * does not hold up the scraper until it times out. Every metric of a stage
+ * has a stage label with its position and a name label with its name,
+ * empty when it has none, and replica times a replica label too. The
+ * server stops when it is dropped.
+ */
+pub struct MetricsServer {
+    address: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    //Port 0 picks a free port, see local_addr
+    pub fn start<A: ToSocketAddrs>(
+        context: Arc<PipelineContext>,
+        address: A,
+    ) -> io::Result<MetricsServer> {
+        let listener = TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stopped = stopped.clone();
+            thread::Builder::new()
+                .name("rust-spp-metrics".to_string())
+                .spawn(move || {
+                    for stream in listener.incoming() {
+                        if stopped.load(Ordering::SeqCst) {
+                            break;
+                        }
+                        //a client that goes away is not our problem
+                        if let Ok(stream) = stream {
+                            let context = context.clone();
+                            let _ = thread::Builder::new()
+                                .name("rust-spp-metrics-client".to_string())
+                                .spawn(move || respond(&context, stream));
+                        }
+                    }
+                })?
+        };
+        Ok(MetricsServer {
+            address,
+            stopped,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        //accept only returns for a connection, so make one
+        let _ = TcpStream::connect(self.address);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn respond(context: &PipelineContext, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    //the headers are not needed, but are read so the client sees a clean close
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(context)),
+        (Some("GET"), _) => ("404 Not Found", "Not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "Only GET is supported\n".to_string(),
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+//The labels every sample of a stage has
+fn stage_labels(stage: &StageStats) -> String {
+    format!(
+        "stage=\"{}\",name=\"{}\"",
+        stage.stage,
+        topology::escape(stage.name.as_deref().unwrap_or(""))
+    )
+}
+
+//A metric with one sample per stage
+fn stage_metric<T: fmt::Display>(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    stats: &PipelineStats,
+    value: impl Fn(&StageStats) -> T,
+) {
+    let _ = writeln!(out, "# HELP rust_spp_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE rust_spp_{} {}", name, kind);
+    for stage in &stats.stages {
+        let _ = writeln!(
+            out,
+            "rust_spp_{}{{{}}} {}",
+            name,
+            stage_labels(stage),
+            value(stage)
+        );
+    }
+}
+
+//A metric with one sample per replica of every stage
+fn replica_metric(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    stats: &PipelineStats,
+    value: impl Fn(&ReplicaStats) -> Duration,
+) {
+    let _ = writeln!(out, "# HELP rust_spp_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE rust_spp_{} counter", name);
+    for stage in &stats.stages {
+        for (replica, stats) in stage.replicas.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "rust_spp_{}{{{},replica=\"{}\"}} {}",
+                name,
+                stage_labels(stage),
+                replica,
+                value(stats).as_secs_f64()
+            );
+        }
+    }
+}
+
+//The text format of the stats of a pipeline
+pub fn render(context: &PipelineContext) -> String {
+    let stats = context.stats();
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "# HELP rust_spp_live_tokens Items inside the pipeline.\n\
+         # TYPE rust_spp_live_tokens gauge\n\
+         rust_spp_live_tokens {}",
+        context.live_tokens()
+    );
+    stage_metric(
+        &mut out,
+        "items_in_total",
+        "counter",
+        "Items the stage processed.",
+        &stats,
+        |stage| stage.items_in,
+    );
+    stage_metric(
+        &mut out,
+        "items_out_total",
+        "counter",
+        "Items the stage emitted.",
+        &stats,
+        |stage| stage.items_out,
+    );
+    stage_metric(
+        &mut out,
+        "items_dropped_total",
+        "counter",
+        "Items the stage dropped.",
+        &stats,
+        |stage| stage.dropped,
+    );
+    stage_metric(
+        &mut out,
+        "items_failed_total",
+        "counter",
+        "Items the stage failed on.",
+        &stats,
+        |stage| stage.failed,
+    );
+    replica_metric(
+        &mut out,
+        "busy_seconds_total",
+        "Time the replica spent processing items.",
+        &stats,
+        |replica| replica.busy,
+    );
+    replica_metric(
+        &mut out,
+        "idle_seconds_total",
+        "Time the replica spent waiting for items.",
+        &stats,
+        |replica| replica.idle,
+    );
+    stage_metric(
+        &mut out,
+        "queue_length",
+        "gauge",
+        "Items waiting in the input of the stage.",
+        &stats,
+        |stage| stage.queue_len,
+    );
+    stage_metric(
+        &mut out,
+        "queue_length_max",
+        "gauge",
+        "Most items seen waiting in the input of the stage.",
+        &stats,
+        |stage| stage.max_queue_len,
+    );
+    stage_metric(
+        &mut out,
+        "reorder_buffer",
+        "gauge",
+        "Items waiting for an earlier one in the stage.",
+        &stats,
+        |stage| stage.reorder_buffer,
+    );
+    stage_metric(
+        &mut out,
+        "reorder_buffer_max",
+        "gauge",
+        "Most items seen waiting for an earlier one in the stage.",
+        &stats,
+        |stage| stage.max_reorder_buffer,
+    );
+    if stats.stages.iter().any(|stage| !stage.latency.is_empty()) {
+        let _ = writeln!(
+            out,
+            "# HELP rust_spp_latency_seconds Time from posting until an item left the stage.\n\
+             # TYPE rust_spp_latency_seconds summary"
+        );
+        for stage in &stats.stages {
+            let labels = stage_labels(stage);
+            let latency = &stage.latency;
+            for (quantile, value) in [
+                ("0.5", latency.p50()),
+                ("0.99", latency.p99()),
+                ("1", latency.max()),
+            ] {
+                let _ = writeln!(
+                    out,
+                    "rust_spp_latency_seconds{{{},quantile=\"{}\"}} {}",
+                    labels,
+                    quantile,
+                    value.as_secs_f64()
+                );
+            }
+            let _ = writeln!(
+                out,
+                "rust_spp_latency_seconds_sum{{{}}} {}\n\
+                 rust_spp_latency_seconds_count{{{}}} {}",
+                labels,
+                latency.sum().as_secs_f64(),
+                labels,
+                latency.count()
+            );
+        }
+    }
+    out
+}
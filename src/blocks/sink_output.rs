@@ -0,0 +1,159 @@
+use crate::work_storage::*;
+use parking_lot::Mutex;
+#[cfg(feature = "futures")]
+use std::pin::Pin;
+use std::sync::Arc;
+#[cfg(feature = "futures")]
+use std::task::{Context, Poll};
+
+//Internals: where the sink of a pipeline puts what it collected. Results go
+//into a queue that can be read while the pipeline runs, and the queue gets a
+//Stop once the sink is done. Ordered farms put their results back in
+//sequence before they reach the queue. Failures of fallible stages are kept
+//apart until the pipeline is collected.
+pub struct SinkOutput<T> {
+    results: Arc<BlockingQueue<T>>,
+    failures: Arc<Mutex<Vec<ItemFailure>>>,
+    reorder_buffer: Option<Arc<ReorderBuffer<T>>>,
+}
+
+impl<T> Clone for SinkOutput<T> {
+    fn clone(&self) -> Self {
+        SinkOutput {
+            results: self.results.clone(),
+            failures: self.failures.clone(),
+            reorder_buffer: self.reorder_buffer.clone(),
+        }
+    }
+}
+
+impl<T> SinkOutput<T> {
+    pub fn new(ordered: bool) -> SinkOutput<T> {
+        SinkOutput {
+            results: BlockingQueue::new(),
+            failures: Arc::new(Mutex::new(vec![])),
+            reorder_buffer: if ordered {
+                Some(ReorderBuffer::new())
+            } else {
+                None
+            },
+        }
+    }
+
+    //Every item that reaches the sink has to be pushed, dropped ones
+    //included, otherwise the reorder buffer would wait for them forever.
+    //Returns how many results are waiting in the reorder buffer.
+    pub fn push(&self, item: TimestampedWorkItem<T>) -> usize {
+        match &self.reorder_buffer {
+            Some(buffer) => buffer.push(item, |item| self.store(item)),
+            None => {
+                self.store(item);
+                0
+            }
+        }
+    }
+
+    //Only called once every item was pushed. What the sink flushed is
+    //numbered from the order of the Stop on
+    pub fn finish(&self, order: Order, flushed: Vec<T>) {
+        let mut order = order.item;
+        for value in flushed {
+            self.push(TimestampedWorkItem(
+                WorkItem::Value(value),
+                Order::new(order),
+                None,
+            ));
+            order += 1;
+        }
+        self.store(TimestampedWorkItem::stop(Order::new(order)));
+    }
+
+    fn store(&self, item: TimestampedWorkItem<T>) {
+        match item {
+            TimestampedWorkItem(WorkItem::Dropped, ..) => {}
+            TimestampedWorkItem(WorkItem::Failed(failure), ..) => {
+                self.failures.lock().push(failure)
+            }
+            item => self.results.enqueue_timestamped(item),
+        }
+    }
+
+    pub fn results(&self) -> Results<T> {
+        Results {
+            results: self.results.clone(),
+            finished: false,
+        }
+    }
+
+    #[cfg(feature = "futures")]
+    pub fn stream(&self) -> ResultStream<T> {
+        ResultStream {
+            results: self.results.clone(),
+            finished: false,
+        }
+    }
+
+    //Sorted by the sequence number of the failed item
+    pub fn take_failures(&self) -> Vec<ItemFailure> {
+        let mut failures = std::mem::take(&mut *self.failures.lock());
+        failures.sort_by(|a, b| a.order.cmp(&b.order));
+        failures
+    }
+}
+
+//Blocking iterator over the results of a pipeline, in the order the sink
+//produced them. Ends once the pipeline has ended and every result was read.
+//Each result is handed to only one reader.
+pub struct Results<T> {
+    results: Arc<BlockingQueue<T>>,
+    finished: bool,
+}
+
+impl<T> Iterator for Results<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.finished {
+            return None;
+        }
+        match self.results.wait_and_dequeue() {
+            TimestampedWorkItem(WorkItem::Value(value), ..) => Some(value),
+            stop => {
+                //leave it there for the other readers
+                self.results.enqueue_timestamped(stop);
+                self.finished = true;
+                None
+            }
+        }
+    }
+}
+
+//Same as Results, as a futures Stream for async code
+#[cfg(feature = "futures")]
+pub struct ResultStream<T> {
+    results: Arc<BlockingQueue<T>>,
+    finished: bool,
+}
+
+#[cfg(feature = "futures")]
+impl<T> futures_core::Stream for ResultStream<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        if self.finished {
+            return Poll::Ready(None);
+        }
+        match self.results.poll_dequeue(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(TimestampedWorkItem(WorkItem::Value(value), ..)) => {
+                Poll::Ready(Some(value))
+            }
+            Poll::Ready(stop) => {
+                //leave it there for the other readers
+                self.results.enqueue_timestamped(stop);
+                self.finished = true;
+                Poll::Ready(None)
+            }
+        }
+    }
+}
@@ -0,0 +1,369 @@
+use crate::blocks::latency::{Histogram, LatencyRecorder};
+use crate::blocks::topology;
+use crate::blocks::trace::{Span, SpanKind, Tracing};
+use crate::blocks::Outputs;
+use crate::work_storage::*;
+use parking_lot::Mutex;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+//Internals: what the replicas of a stage did so far. Updated with relaxed
+//atomics as items go through, read by Pipeline::stats at any time.
+//When the pipeline is traced, replicas also keep every span they time.
+pub struct StageCounters {
+    index: usize,
+    name: Option<String>,
+    tracing: Arc<Tracing>,
+    //reads the current length of the stage's input
+    queue: Box<dyn Fn() -> usize + Send + Sync>,
+    started: Instant,
+    //nanoseconds from started to the Stop, 0 while the stage runs
+    ended: AtomicU64,
+    items_in: AtomicU64,
+    items_out: AtomicU64,
+    dropped: AtomicU64,
+    failed: AtomicU64,
+    replicas: Vec<ReplicaCounters>,
+    max_queue_len: AtomicUsize,
+    reorder_buffer: AtomicUsize,
+    max_reorder_buffer: AtomicUsize,
+    latency: LatencyRecorder,
+}
+
+struct ReplicaCounters {
+    busy: AtomicU64,
+    idle: AtomicU64,
+    //only the replica pushes, so the lock is only contended by exports
+    spans: Mutex<Vec<Span>>,
+}
+
+impl StageCounters {
+    pub fn new(
+        index: usize,
+        name: Option<String>,
+        replicas: usize,
+        tracing: Arc<Tracing>,
+        queue: Box<dyn Fn() -> usize + Send + Sync>,
+    ) -> StageCounters {
+        StageCounters {
+            index,
+            name,
+            tracing,
+            queue,
+            started: Instant::now(),
+            ended: AtomicU64::new(0),
+            items_in: AtomicU64::new(0),
+            items_out: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            replicas: (0..replicas)
+                .map(|_| ReplicaCounters {
+                    busy: AtomicU64::new(0),
+                    idle: AtomicU64::new(0),
+                    spans: Mutex::new(vec![]),
+                })
+                .collect(),
+            max_queue_len: AtomicUsize::new(0),
+            reorder_buffer: AtomicUsize::new(0),
+            max_reorder_buffer: AtomicUsize::new(0),
+            latency: LatencyRecorder::new(),
+        }
+    }
+
+    //Time a replica spent waiting for an item since the given instant,
+    //order being the item it got
+    pub fn idle_since(&self, replica: usize, since: Instant, order: u64) {
+        let replica = &self.replicas[replica];
+        self.record(replica, &replica.idle, SpanKind::Wait, since, order);
+    }
+
+    //Time a replica spent processing an item since the given instant
+    pub fn busy_since(&self, replica: usize, since: Instant, order: u64) {
+        let replica = &self.replicas[replica];
+        self.record(replica, &replica.busy, SpanKind::Process, since, order);
+    }
+
+    fn record(
+        &self,
+        replica: &ReplicaCounters,
+        counter: &AtomicU64,
+        kind: SpanKind,
+        since: Instant,
+        order: u64,
+    ) {
+        let elapsed = since.elapsed();
+        counter.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        if self.tracing.is_enabled() {
+            let span = self.tracing.span(kind, since, elapsed, order);
+            replica.spans.lock().push(span);
+        }
+    }
+
+    //Items that were waiting in the queue when a replica took one
+    pub fn queue_len(&self, len: usize) {
+        self.max_queue_len.fetch_max(len, Ordering::Relaxed);
+    }
+
+    pub fn reorder_buffer(&self, len: usize) {
+        self.reorder_buffer.store(len, Ordering::Relaxed);
+        self.max_reorder_buffer.fetch_max(len, Ordering::Relaxed);
+    }
+
+    //Counts an item the stage processed, by what came out of it
+    pub fn processed<T>(&self, output: &WorkItem<Outputs<T>>) {
+        self.items_in.fetch_add(1, Ordering::Relaxed);
+        match output {
+            WorkItem::Value(Outputs::One(_)) => self.emitted(1),
+            WorkItem::Value(Outputs::Many(values)) => self.emitted(values.len()),
+            WorkItem::Dropped => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            WorkItem::Failed(_) => {
+                self.failed.fetch_add(1, Ordering::Relaxed);
+            }
+            WorkItem::Stop => {}
+        }
+    }
+
+    //Sinks have no outputs, so only what they collected counts
+    pub fn consumed<T>(&self, collected: &WorkItem<T>) {
+        self.items_in.fetch_add(1, Ordering::Relaxed);
+        match collected {
+            WorkItem::Value(_) => self.emitted(1),
+            _ => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    //Items flushed at the end count as outputs too
+    pub fn emitted(&self, count: usize) {
+        self.items_out.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    //A value left the stage, for the next one or for good. Only values
+    //that were posted have an ingress time, flushed ones do not count.
+    pub fn left(&self, ingress: Option<Instant>) {
+        if let Some(ingress) = ingress {
+            if self.tracing.measures_latency() {
+                self.latency.record(ingress.elapsed());
+            }
+        }
+    }
+
+    //The stage handled its Stop, so its clock stops too
+    pub fn end(&self) {
+        let elapsed = self.started.elapsed().as_nanos() as u64;
+        self.ended.store(elapsed.max(1), Ordering::Relaxed);
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    //The name of the stage, or its position when it has none
+    pub fn label(&self) -> String {
+        topology::stage_label(self.index, self.name.as_deref())
+    }
+
+    //What each replica recorded so far, for the trace
+    pub fn spans(&self) -> Vec<Vec<Span>> {
+        self.replicas
+            .iter()
+            .map(|replica| replica.spans.lock().clone())
+            .collect()
+    }
+
+    pub fn snapshot(&self) -> StageStats {
+        let nanos = |counter: &AtomicU64| Duration::from_nanos(counter.load(Ordering::Relaxed));
+        //once the stage ended only Stop tokens are left in its queues
+        let (elapsed, queue_len) = match self.ended.load(Ordering::Relaxed) {
+            0 => (self.started.elapsed(), (self.queue)()),
+            ended => (Duration::from_nanos(ended), 0),
+        };
+        StageStats {
+            stage: self.index,
+            name: self.name.clone(),
+            elapsed,
+            items_in: self.items_in.load(Ordering::Relaxed),
+            items_out: self.items_out.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            replicas: self
+                .replicas
+                .iter()
+                .map(|replica| ReplicaStats {
+                    busy: nanos(&replica.busy),
+                    idle: nanos(&replica.idle),
+                })
+                .collect(),
+            queue_len,
+            max_queue_len: self.max_queue_len.load(Ordering::Relaxed),
+            reorder_buffer: self.reorder_buffer.load(Ordering::Relaxed),
+            max_reorder_buffer: self.max_reorder_buffer.load(Ordering::Relaxed),
+            latency: self.latency.snapshot(),
+        }
+    }
+}
+
+//What a stage did so far, from the moment the pipeline started it until
+//its Stop, or until now while it runs
+#[derive(Clone, Debug)]
+pub struct StageStats {
+    pub stage: usize,
+    //the name the stage was given, if any
+    pub name: Option<String>,
+    pub elapsed: Duration,
+    //only the items the stage processed itself, the ones dropped or failed
+    //before it just go through
+    pub items_in: u64,
+    pub items_out: u64,
+    pub dropped: u64,
+    pub failed: u64,
+    pub replicas: Vec<ReplicaStats>,
+    //read when the snapshot is taken
+    pub queue_len: usize,
+    //sampled whenever a replica takes an item
+    pub max_queue_len: usize,
+    //where ordered farms put their outputs back in sequence, and where
+    //ordered sequential stages keep the items that arrived ahead of their
+    //turn
+    pub reorder_buffer: usize,
+    pub max_reorder_buffer: usize,
+    //time from when an item was posted until it left the stage, only
+    //recorded for pipelines built with with_latency. For the sink, that is
+    //the end-to-end latency.
+    pub latency: Histogram,
+}
+
+//The time one replica spent blocked on the next stage counts as neither
+#[derive(Clone, Copy, Debug)]
+pub struct ReplicaStats {
+    //processing an item
+    pub busy: Duration,
+    //waiting for one
+    pub idle: Duration,
+}
+
+impl StageStats {
+    pub fn busy(&self) -> Duration {
+        self.replicas.iter().map(|replica| replica.busy).sum()
+    }
+
+    pub fn idle(&self) -> Duration {
+        self.replicas.iter().map(|replica| replica.idle).sum()
+    }
+
+    //Items processed per second
+    pub fn throughput(&self) -> f64 {
+        let elapsed = self.elapsed.as_secs_f64();
+        if elapsed == 0.0 {
+            0.0
+        } else {
+            self.items_in as f64 / elapsed
+        }
+    }
+
+    //Average time one replica took to process an item
+    pub fn service_time(&self) -> Duration {
+        match self.items_in {
+            0 => Duration::ZERO,
+            items => Duration::from_nanos((self.busy().as_nanos() / items as u128) as u64),
+        }
+    }
+
+    //Share of the time its replicas spent processing, out of the time
+    //they were busy or idle. The bottleneck is the stage closest to 1.
+    pub fn utilization(&self) -> f64 {
+        let busy = self.busy().as_secs_f64();
+        let total = busy + self.idle().as_secs_f64();
+        if total == 0.0 {
+            0.0
+        } else {
+            busy / total
+        }
+    }
+}
+
+//A snapshot of every stage of a pipeline, from Pipeline::stats. Printing
+//it gives a table with one line per stage.
+#[derive(Clone, Debug)]
+pub struct PipelineStats {
+    pub stages: Vec<StageStats>,
+}
+
+impl PipelineStats {
+    //From posting to the sink, empty unless latency is measured
+    pub fn latency(&self) -> Option<&Histogram> {
+        self.stages.last().map(|stage| &stage.latency)
+    }
+}
+
+impl fmt::Display for PipelineStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        //latency columns only when it was measured
+        let latency = self.stages.iter().any(|stage| !stage.latency.is_empty());
+        let names = self
+            .stages
+            .iter()
+            .map(|stage| stage.name.as_deref().map_or(1, |name| name.chars().count()))
+            .max()
+            .unwrap_or(0)
+            .max("name".len());
+        write!(
+            f,
+            "{:>5} {:<names$} {:>8} {:>10} {:>10} {:>8} {:>8} {:>12} {:>12} {:>12} {:>12} {:>6} {:>9} {:>11}",
+            "stage",
+            "name",
+            "replicas",
+            "in",
+            "out",
+            "dropped",
+            "failed",
+            "items/s",
+            "service",
+            "busy",
+            "idle",
+            "util",
+            "max queue",
+            "max reorder"
+        )?;
+        if latency {
+            write!(f, " {:>12} {:>12} {:>12}", "p50", "p99", "max")?;
+        }
+        writeln!(f)?;
+        for stage in &self.stages {
+            write!(
+                f,
+                "{:>5} {:<names$} {:>8} {:>10} {:>10} {:>8} {:>8} {:>12.1} {:>12} {:>12} {:>12} {:>5.0}% {:>9} {:>11}",
+                stage.stage,
+                stage.name.as_deref().unwrap_or("-"),
+                stage.replicas.len(),
+                stage.items_in,
+                stage.items_out,
+                stage.dropped,
+                stage.failed,
+                stage.throughput(),
+                format!("{:.3?}", stage.service_time()),
+                format!("{:.3?}", stage.busy()),
+                format!("{:.3?}", stage.idle()),
+                stage.utilization() * 100.0,
+                stage.max_queue_len,
+                stage.max_reorder_buffer
+            )?;
+            if latency {
+                write!(
+                    f,
+                    " {:>12} {:>12} {:>12}",
+                    format!("{:.3?}", stage.latency.p50()),
+                    format!("{:.3?}", stage.latency.p99()),
+                    format!("{:.3?}", stage.latency.max())
+                )?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
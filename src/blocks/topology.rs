@@ -0,0 +1,123 @@
+use crate::blocks::{BlockMode, OrderingMode};
+use std::fmt::{self, Write as _};
+
+//How a stage was built: how it runs, how many replicas it has and what it
+//takes its items from. Sinks are the last stage, the one that collects.
+#[derive(Clone, Debug)]
+pub struct StageDescription {
+    pub index: usize,
+    pub name: Option<String>,
+    pub mode: BlockMode,
+    pub replicas: usize,
+    pub sink: bool,
+    //runs as tasks on a tokio runtime instead of threads
+    pub on_runtime: bool,
+    pub queue: String,
+    pub capacity: Option<usize>,
+}
+
+impl StageDescription {
+    //The name, or the position of the stage when it has none
+    pub fn label(&self) -> String {
+        stage_label(self.index, self.name.as_deref())
+    }
+
+    pub fn mode_name(&self) -> &'static str {
+        match self.mode {
+            BlockMode::Sequential(OrderingMode::Unordered) => "sequential",
+            BlockMode::Sequential(OrderingMode::Ordered) => "sequential ordered",
+            BlockMode::Parallel(_) => "parallel",
+            BlockMode::OrderedParallel(_) => "parallel ordered",
+        }
+    }
+}
+
+impl fmt::Display for StageDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.label(), self.mode_name())?;
+        if self.replicas > 1 {
+            write!(f, " x{}", self.replicas)?;
+        }
+        if self.sink {
+            write!(f, " sink")?;
+        }
+        if self.on_runtime {
+            write!(f, " on a tokio runtime")?;
+        }
+        write!(f, ", {}", self.queue)?;
+        if let Some(capacity) = self.capacity {
+            write!(f, ", bounded to {}", capacity)?;
+        }
+        Ok(())
+    }
+}
+
+//The stages of a pipeline in order, each one feeding the next
+#[derive(Clone, Debug)]
+pub struct Topology {
+    pub stages: Vec<StageDescription>,
+}
+
+impl Topology {
+    //The pipeline as a Graphviz graph, one box per stage from left to right.
+    //Render it with `dot -Tsvg`.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph pipeline {\n    rankdir=LR;\n    node [shape=box];\n");
+        for stage in &self.stages {
+            let mut label = format!("{}\n{}", stage.label(), stage.mode_name());
+            if stage.replicas > 1 {
+                let _ = write!(label, " x{}", stage.replicas);
+            }
+            let _ = write!(label, "\n{}", stage.queue);
+            if let Some(capacity) = stage.capacity {
+                let _ = write!(label, ", bounded to {}", capacity);
+            }
+            let _ = writeln!(
+                dot,
+                "    stage{} [label=\"{}\"{}];",
+                stage.index,
+                escape(&label),
+                if stage.sink { ", peripheries=2" } else { "" }
+            );
+        }
+        for pair in self.stages.windows(2) {
+            let _ = writeln!(dot, "    stage{} -> stage{};", pair[0].index, pair[1].index);
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+impl fmt::Display for Topology {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for stage in &self.stages {
+            writeln!(f, "{}", stage)?;
+        }
+        Ok(())
+    }
+}
+
+//Internals: how stages are called in stats, traces and errors
+pub fn stage_label(index: usize, name: Option<&str>) -> String {
+    match name {
+        Some(name) => name.to_string(),
+        None => format!("stage {}", index),
+    }
+}
+
+//Internals: text for a double quoted string of a trace, a metric label
+//or a graph, which all take the same escapes. Other control characters
+//become spaces.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c if c.is_control() => escaped.push(' '),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,130 @@
+use crate::blocks::topology;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+//Internals: whether the pipeline records what its replicas do and how
+//long items take, and the instant the timestamps of the trace count from
+pub struct Tracing {
+    enabled: AtomicBool,
+    latency: AtomicBool,
+    epoch: Instant,
+}
+
+impl Tracing {
+    pub fn new() -> Tracing {
+        Tracing {
+            enabled: AtomicBool::new(false),
+            latency: AtomicBool::new(false),
+            epoch: Instant::now(),
+        }
+    }
+
+    pub fn enable(&self) {
+        self.enabled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn enable_latency(&self) {
+        self.latency.store(true, Ordering::Relaxed);
+    }
+
+    pub fn measures_latency(&self) -> bool {
+        self.latency.load(Ordering::Relaxed)
+    }
+
+    pub fn span(&self, kind: SpanKind, since: Instant, duration: Duration, order: u64) -> Span {
+        Span {
+            kind,
+            start: since.saturating_duration_since(self.epoch),
+            duration,
+            order,
+        }
+    }
+}
+
+impl Default for Tracing {
+    fn default() -> Tracing {
+        Tracing::new()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpanKind {
+    //the replica ran the stage on an item
+    Process,
+    //the replica waited for an item, in wait_and_dequeue or wait_and_remove
+    Wait,
+}
+
+//Internals: something a replica did, with the item it was about
+#[derive(Clone, Copy, Debug)]
+pub struct Span {
+    pub kind: SpanKind,
+    pub start: Duration,
+    pub duration: Duration,
+    pub order: u64,
+}
+
+fn micros(duration: Duration) -> f64 {
+    duration.as_nanos() as f64 / 1000.0
+}
+
+/*
+ * Writes the spans in the Chrome Trace Event format, which chrome://tracing
+ * and Perfetto open. Each replica gets a track of its own, named after its
+ * stage and sorted in pipeline order, with one complete event per span.
+ * Events carry the sequence number of their item, so a reorder stall shows
+ * as an ordered stage waiting on one number while later ones pile up.
+ * Stages are given as (name or position, spans of each replica).
+ */
+pub fn write_chrome_trace<W: Write>(
+    writer: &mut W,
+    stages: &[(String, Vec<Vec<Span>>)],
+) -> io::Result<()> {
+    write!(
+        writer,
+        "{{\"displayTimeUnit\":\"ns\",\"traceEvents\":[\n\
+         {{\"name\":\"process_name\",\"ph\":\"M\",\"pid\":0,\"args\":{{\"name\":\"pipeline\"}}}}"
+    )?;
+    let mut track = 0;
+    for (stage, replicas) in stages {
+        let stage = topology::escape(stage);
+        for (replica, spans) in replicas.iter().enumerate() {
+            write!(
+                writer,
+                ",\n{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":0,\"tid\":{},\
+                 \"args\":{{\"name\":\"{} replica {}\"}}}}",
+                track, stage, replica
+            )?;
+            write!(
+                writer,
+                ",\n{{\"name\":\"thread_sort_index\",\"ph\":\"M\",\"pid\":0,\"tid\":{},\
+                 \"args\":{{\"sort_index\":{}}}}}",
+                track, track
+            )?;
+            for span in spans {
+                let name = match span.kind {
+                    SpanKind::Process => "process",
+                    SpanKind::Wait => "wait",
+                };
+                write!(
+                    writer,
+                    ",\n{{\"name\":\"{}\",\"cat\":\"{}\",\"ph\":\"X\",\"pid\":0,\"tid\":{},\
+                     \"ts\":{:.3},\"dur\":{:.3},\"args\":{{\"item\":{}}}}}",
+                    name,
+                    stage,
+                    track,
+                    micros(span.start),
+                    micros(span.duration),
+                    span.order
+                )?;
+            }
+            track += 1;
+        }
+    }
+    writeln!(writer, "\n]}}")
+}
@@ -1,5 +1,3 @@
-#![feature(async_closure)]
-
 use rust_spp::*;
 
 fn leak_test() {
@@ -25,6 +23,7 @@ fn leak_test() {
     }
 }
 
+#[allow(dead_code)]
 struct SyncNotNeeded {
     test: std::rc::Rc<i64>,
 }
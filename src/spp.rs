@@ -1,143 +1,569 @@
+#[cfg(feature = "futures")]
+use crate::blocks::pipeline_input::unwrap_value;
 use crate::blocks::*;
+use crate::work_storage::ItemFailure;
+#[cfg(feature = "futures")]
 use crate::work_storage::WorkItem;
-use std::marker::PhantomData;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+#[cfg(feature = "futures")]
+use std::pin::Pin;
+use std::sync::Arc;
+#[cfg(feature = "futures")]
+use std::task::{Context, Poll};
 use std::thread;
-use std::thread::JoinHandle;
-
-pub struct Pipeline<
-    TInput: 'static,
-    TOutput: 'static,
-    TCollected: 'static,
-    TStage: InOut<TInput, TOutput> + Send + 'static,
-    TFactory: FnMut() -> TStage,
-    TNextStep: PipelineBlock<TOutput, TCollected> + Send + Sync,
-> {
-    signaled_end: bool,
-    initial_block: Option<InOutBlock<TInput, TOutput, TCollected, TStage, TFactory, TNextStep>>,
+use std::time::Duration;
+
+pub struct Pipeline<TInput: 'static, TCollected: 'static> {
+    input: Arc<PipelineInput<TInput>>,
+    output: SinkOutput<TCollected>,
+    context: Arc<PipelineContext>,
     monitors: Vec<MonitorLoop>,
-    threads: Vec<JoinHandle<()>>,
-    _params: std::marker::PhantomData<(TOutput, TCollected)>,
-}
-
-impl<
-        TInput: 'static,
-        TOutput: 'static,
-        TCollected: 'static,
-        TStage: InOut<TInput, TOutput> + Send + 'static,
-        TFactory: FnMut() -> TStage,
-        TNextStep: PipelineBlock<TOutput, TCollected> + Send + Sync,
-    > Pipeline<TInput, TOutput, TCollected, TStage, TFactory, TNextStep>
-{
-    pub fn new(
-        initial_block: InOutBlock<TInput, TOutput, TCollected, TStage, TFactory, TNextStep>,
+    workers: Vec<Worker>,
+    source: Option<Worker>,
+}
+
+impl<TInput: 'static, TCollected: 'static> Pipeline<TInput, TCollected> {
+    //The blocks are owned by their monitors from here on, the pipeline
+    //only keeps the ends it posts to and collects from
+    pub fn new<TBlock: StageBlock<TInput> + PipelineBlock<TInput, TCollected>>(
+        initial_block: TBlock,
         monitors: Vec<MonitorLoop>,
-    ) -> Pipeline<TInput, TOutput, TCollected, TStage, TFactory, TNextStep> {
+        context: Arc<PipelineContext>,
+    ) -> Pipeline<TInput, TCollected> {
         Pipeline {
-            initial_block: Some(initial_block),
-            monitors: monitors,
-            threads: vec![],
-            signaled_end: false,
-            _params: PhantomData,
+            input: PipelineInput::new(initial_block.input(), context.clone()),
+            output: initial_block.output(),
+            context,
+            monitors,
+            workers: vec![],
+            source: None,
         }
     }
 
-    fn end(&mut self) {
-        self.signaled_end = true;
-        match &self.initial_block {
-            Some(block) => block.send_stop(),
-            None => {}
-        }
+    //Tells the pipeline no more items are coming, without waiting for it.
+    //Readers of results() see the end once the last result was read.
+    //Senders cannot post anymore after this.
+    pub fn end(&mut self) {
+        self.input.end();
+    }
+
+    //A handle other threads can post through. Once every sender created
+    //here was dropped the pipeline ends, as if end was called.
+    pub fn sender(&self) -> PipelineSender<TInput> {
+        PipelineSender::new(self.input.clone())
     }
 
-    pub fn end_and_wait(&mut self) {
+    //Once a stage panics the remaining items are dropped without being
+    //processed, and the first panic is reported here
+    //With a source, waits for it to run out first
+    pub fn end_and_wait(&mut self) -> Result<(), PipelineError> {
+        if let Some(source) = self.source.take() {
+            if let Err(payload) = source.join() {
+                std::panic::resume_unwind(payload);
+            }
+        }
         self.end();
-        let all_threads = std::mem::replace(&mut self.threads, vec![]);
-        for thread in all_threads {
-            thread.join().unwrap();
+        let workers = std::mem::take(&mut self.workers);
+        for worker in workers {
+            if let Err(payload) = worker.join() {
+                std::panic::resume_unwind(payload);
+            }
+        }
+        match self.context.take_failure() {
+            Some(error) => Err(error),
+            None => Ok(()),
         }
     }
 
     pub fn post(&self, item: TInput) -> Result<(), ItemPostError> {
-        if self.signaled_end {
-            return Err(ItemPostError::StreamEnded);
+        self.input.post(item)
+    }
+
+    //Like post, but fails with the item instead of blocking when the
+    //first stage's queue is full
+    pub fn try_post(&self, item: TInput) -> Result<(), TryPostError<TInput>> {
+        self.input.try_post(item)
+    }
+
+    //Like post, but only blocks for at most timeout waiting for room
+    pub fn post_timeout(
+        &self,
+        item: TInput,
+        timeout: Duration,
+    ) -> Result<(), PostTimeoutError<TInput>> {
+        self.input.post_timeout(item, timeout)
+    }
+
+    //Like end_and_wait, for async code: waits for the stages on the
+    //blocking thread pool of the current tokio runtime
+    #[cfg(feature = "tokio")]
+    pub async fn end_and_wait_async(&mut self) -> Result<(), PipelineError> {
+        let source = self.source.take();
+        join_async(source.into_iter().collect()).await;
+        self.end();
+        join_async(std::mem::take(&mut self.workers)).await;
+        match self.context.take_failure() {
+            Some(error) => Err(error),
+            None => Ok(()),
         }
-        match &self.initial_block {
-            Some(block) => {
-                block.process(WorkItem::Value(item));
-                Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    pub async fn post_async(&self, item: TInput) -> Result<(), ItemPostError> {
+        self.input.post_async(item).await
+    }
+
+    #[cfg(feature = "tokio")]
+    pub async fn collect_async(self) -> Result<Vec<TCollected>, PipelineError> {
+        self.collect_with_failures_async()
+            .await
+            .map(|(results, _)| results)
+    }
+
+    #[cfg(feature = "tokio")]
+    pub async fn collect_with_failures_async(
+        mut self,
+    ) -> Result<(Vec<TCollected>, Vec<ItemFailure>), PipelineError> {
+        self.end_and_wait_async().await?;
+        Ok((self.output.results().collect(), self.output.take_failures()))
+    }
+
+    pub fn collect(self) -> Result<Vec<TCollected>, PipelineError> {
+        self.collect_with_failures().map(|(results, _)| results)
+    }
+
+    //Like collect, but also returns the errors of fallible stages
+    pub fn collect_with_failures(
+        mut self,
+    ) -> Result<(Vec<TCollected>, Vec<ItemFailure>), PipelineError> {
+        self.end_and_wait()?;
+        Ok((self.output.results().collect(), self.output.take_failures()))
+    }
+
+    //Bounds the items inside the pipeline, in every stage at once. Posts
+    //wait while there are that many, and try_post and post_timeout hand
+    //the item back. Items a stage emits on top of the one it got count
+    //too, but never wait.
+    pub fn with_max_live_tokens(self, limit: usize) -> Pipeline<TInput, TCollected> {
+        self.context.set_max_live_tokens(limit);
+        self
+    }
+
+    //Items posted that did not reach the sink yet
+    pub fn live_tokens(&self) -> usize {
+        self.context.live_tokens()
+    }
+
+    //What every stage did so far. Can be read while the pipeline runs, and
+    //after end_and_wait for the final numbers.
+    pub fn stats(&self) -> PipelineStats {
+        self.context.stats()
+    }
+
+    //How the stages were built, in pipeline order. Topology::to_dot draws it.
+    pub fn describe(&self) -> Topology {
+        self.context.describe()
+    }
+
+    //Records when every replica processes an item and waits for one, from
+    //here on. With a source, call it before with_source.
+    pub fn with_trace(self) -> Pipeline<TInput, TCollected> {
+        self.context.enable_tracing();
+        self
+    }
+
+    //Builds latency histograms from here on: how long after it was posted
+    //each value left each stage, and reached the sink. They come with
+    //stats().
+    pub fn with_latency(self) -> Pipeline<TInput, TCollected> {
+        self.context.enable_latency();
+        self.input.stamp_ingress();
+        self
+    }
+
+    //Serves stats() in the Prometheus text format on the given address,
+    //until the returned server is dropped
+    #[cfg(feature = "prometheus")]
+    pub fn serve_metrics<A: std::net::ToSocketAddrs>(
+        &self,
+        address: A,
+    ) -> io::Result<MetricsServer> {
+        MetricsServer::start(self.context.clone(), address)
+    }
+
+    //Writes what was recorded so far as a Chrome trace, which
+    //chrome://tracing and Perfetto open. Usually called after end_and_wait.
+    pub fn write_trace<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.context.write_trace(writer)
+    }
+
+    pub fn save_trace<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        self.write_trace(&mut file)?;
+        file.flush()
+    }
+
+    //Starts a thread that posts every item, and ends the pipeline once
+    //they run out. Used by source! in pipeline!
+    pub fn with_source<I>(mut self, items: I) -> Pipeline<TInput, TCollected>
+    where
+        I: IntoIterator<Item = TInput> + Send + 'static,
+        TInput: Send,
+    {
+        let sender = self.sender();
+        self.source = Some(Worker::Thread(thread::spawn(move || feed(sender, items))));
+        self
+    }
+
+    //Posts every item and collects the results. The sink collects on its
+    //own thread meanwhile, so the items are posted from the calling one.
+    pub fn run_iter<I>(self, items: I) -> Result<Vec<TCollected>, PipelineError>
+    where
+        I: IntoIterator<Item = TInput>,
+    {
+        for item in items {
+            if self.post(item).is_err() {
+                break;
             }
-            None => Err(ItemPostError::UnknownError),
         }
+        self.collect()
     }
 
-    pub fn collect(mut self) -> Vec<TCollected> {
-        self.end_and_wait();
-
-        let current_block = std::mem::replace(&mut self.initial_block, None);
-        match current_block {
-            Some(block) => Box::new(block).collect(),
-            None => vec![],
+    //Like run_iter, but returns the results lazily, as the sink produces them
+    pub fn feed<I>(self, items: I) -> PipelineIter<TInput, TCollected>
+    where
+        I: IntoIterator<Item = TInput> + Send + 'static,
+        TInput: Send,
+    {
+        let sender = self.sender();
+        PipelineIter {
+            results: self.results(),
+            source: Some(thread::spawn(move || feed(sender, items))),
+            pipeline: self,
         }
     }
 
+    //Results as soon as the sink produces them, while items are still
+    //being posted. Results read here are not returned by collect.
+    pub fn results(&self) -> Results<TCollected> {
+        self.output.results()
+    }
+
+    //Same as results, as a futures Stream
+    #[cfg(feature = "futures")]
+    pub fn stream(&self) -> ResultStream<TCollected> {
+        self.output.stream()
+    }
+
+    //A futures Sink that posts into the pipeline. It is a sender, so
+    //closing it ends the pipeline once no other sender is left.
+    #[cfg(feature = "futures")]
+    pub fn sink(&self) -> PipelineSink<TInput> {
+        PipelineSink::new(self.sender())
+    }
+
     pub fn start(&mut self) {
-        let monitors = std::mem::replace(&mut self.monitors, vec![]);
+        let monitors = std::mem::take(&mut self.monitors);
 
         for monitor in monitors {
-            self.threads.push(thread::spawn(move || {
-                monitor.run();
-            }))
+            self.workers.push(monitor.start());
+        }
+    }
+}
+
+impl<TInput: 'static, TCollected: 'static> Drop for Pipeline<TInput, TCollected> {
+    fn drop(&mut self) {
+        //the source stops at its next post
+        self.input.end();
+        if let Some(source) = self.source.take() {
+            let _ = source.join();
+        }
+
+        //errors were either reported by end_and_wait already or
+        //nobody asked for them, and panicking in drop could abort
+        let workers = std::mem::take(&mut self.workers);
+        for worker in workers {
+            let _ = worker.join();
+        }
+    }
+}
+
+//Waits for workers without blocking the runtime, panics like end_and_wait
+#[cfg(feature = "tokio")]
+async fn join_async(workers: Vec<Worker>) {
+    let joined = tokio::task::spawn_blocking(move || {
+        for worker in workers {
+            worker.join()?;
+        }
+        Ok(())
+    })
+    .await;
+    match joined {
+        Ok(Ok(())) => {}
+        Ok(Err(payload)) => std::panic::resume_unwind(payload),
+        Err(error) => std::panic::resume_unwind(error.into_panic()),
+    }
+}
+
+//Source stage of feed and source!. Dropping the sender at the end ends the
+//pipeline
+fn feed<TInput, I: IntoIterator<Item = TInput>>(sender: PipelineSender<TInput>, items: I) {
+    for item in items {
+        if sender.post(item).is_err() {
+            break;
+        }
+    }
+}
+
+//Results of a pipeline fed by an iterator, see Pipeline::feed. Ends with
+//the error if a stage panicked.
+pub struct PipelineIter<TInput: 'static, TCollected: 'static> {
+    pipeline: Pipeline<TInput, TCollected>,
+    results: Results<TCollected>,
+    source: Option<thread::JoinHandle<()>>,
+}
+
+impl<TInput: 'static, TCollected: 'static> Iterator for PipelineIter<TInput, TCollected> {
+    type Item = Result<TCollected, PipelineError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(result) = self.results.next() {
+            return Some(Ok(result));
+        }
+        let source = self.source.take()?;
+        if let Err(payload) = source.join() {
+            std::panic::resume_unwind(payload);
         }
+        self.pipeline.end_and_wait().err().map(Err)
     }
 }
 
-impl<
-        TInput: 'static,
-        TOutput: 'static,
-        TCollected: 'static,
-        TStage: InOut<TInput, TOutput> + Send + 'static,
-        TFactory: FnMut() -> TStage,
-        TNextStep: PipelineBlock<TOutput, TCollected> + Send + Sync,
-    > Drop for Pipeline<TInput, TOutput, TCollected, TStage, TFactory, TNextStep>
+//Runs a pipeline over the items of an iterator:
+//(0..size).into_ssp_pipeline(pipeline![...]) gives the results lazily
+pub trait IntoSspPipeline: IntoIterator + Sized {
+    fn into_ssp_pipeline<TCollected>(
+        self,
+        pipeline: Pipeline<Self::Item, TCollected>,
+    ) -> PipelineIter<Self::Item, TCollected>;
+}
+
+impl<I> IntoSspPipeline for I
+where
+    I: IntoIterator + Send + 'static,
+    I::Item: Send + 'static,
 {
+    fn into_ssp_pipeline<TCollected>(
+        self,
+        pipeline: Pipeline<I::Item, TCollected>,
+    ) -> PipelineIter<I::Item, TCollected> {
+        pipeline.feed(self)
+    }
+}
+
+//Posts items into a pipeline from any thread. Clones share the same
+//pipeline, and the pipeline ends once the last of them is dropped.
+pub struct PipelineSender<TInput> {
+    input: Arc<PipelineInput<TInput>>,
+}
+
+impl<TInput> PipelineSender<TInput> {
+    fn new(input: Arc<PipelineInput<TInput>>) -> PipelineSender<TInput> {
+        input.add_sender();
+        PipelineSender { input }
+    }
+
+    pub fn post(&self, item: TInput) -> Result<(), ItemPostError> {
+        self.input.post(item)
+    }
+
+    pub fn try_post(&self, item: TInput) -> Result<(), TryPostError<TInput>> {
+        self.input.try_post(item)
+    }
+
+    pub fn post_timeout(
+        &self,
+        item: TInput,
+        timeout: Duration,
+    ) -> Result<(), PostTimeoutError<TInput>> {
+        self.input.post_timeout(item, timeout)
+    }
+
+    #[cfg(feature = "tokio")]
+    pub async fn post_async(&self, item: TInput) -> Result<(), ItemPostError> {
+        self.input.post_async(item).await
+    }
+
+    //Whether the pipeline stopped taking items
+    pub fn is_ended(&self) -> bool {
+        self.input.has_ended()
+    }
+}
+
+impl<TInput> Clone for PipelineSender<TInput> {
+    fn clone(&self) -> Self {
+        PipelineSender::new(self.input.clone())
+    }
+}
+
+impl<TInput> Drop for PipelineSender<TInput> {
     fn drop(&mut self) {
-        let block = std::mem::replace(&mut self.initial_block, None);
+        self.input.remove_sender();
+    }
+}
 
-        if !self.signaled_end {
-            block.unwrap().send_stop();
+//Posts into a pipeline from async code. An item given to start_send is
+//held until there is room for it in the first stage, so poll_ready and
+//poll_flush wait while the pipeline is full. Closing the sink drops its
+//sender.
+#[cfg(feature = "futures")]
+pub struct PipelineSink<TInput> {
+    sender: Option<PipelineSender<TInput>>,
+    pending: Option<TInput>,
+}
+
+//The pending item is never pinned
+#[cfg(feature = "futures")]
+impl<TInput> Unpin for PipelineSink<TInput> {}
+
+#[cfg(feature = "futures")]
+impl<TInput> PipelineSink<TInput> {
+    fn new(sender: PipelineSender<TInput>) -> PipelineSink<TInput> {
+        PipelineSink {
+            sender: Some(sender),
+            pending: None,
         }
+    }
 
-        let all_threads = std::mem::replace(&mut self.threads, vec![]);
-        for thread in all_threads {
-            thread.join().unwrap();
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ItemPostError>> {
+        let item = match self.pending.take() {
+            Some(item) => item,
+            None => return Poll::Ready(Ok(())),
+        };
+        let sender = match &self.sender {
+            Some(sender) => sender,
+            None => return Poll::Ready(Err(ItemPostError::StreamEnded)),
+        };
+        let mut item = item;
+        loop {
+            match sender.try_post(item) {
+                Ok(()) => return Poll::Ready(Ok(())),
+                Err(TryPostError::StreamEnded(_)) => {
+                    return Poll::Ready(Err(ItemPostError::StreamEnded))
+                }
+                Err(TryPostError::Full(rejected)) => {
+                    //room may have been made before the waker was registered
+                    let rejected = WorkItem::Value(rejected);
+                    let space = sender.input.poll_space(&rejected, cx);
+                    item = unwrap_value(rejected);
+                    if space.is_pending() {
+                        self.pending = Some(item);
+                        return Poll::Pending;
+                    }
+                }
+            }
         }
     }
 }
 
+#[cfg(feature = "futures")]
+impl<TInput> futures_sink::Sink<TInput> for PipelineSink<TInput> {
+    type Error = ItemPostError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), ItemPostError>> {
+        let sink = self.get_mut();
+        if sink.sender.is_none() {
+            return Poll::Ready(Err(ItemPostError::StreamEnded));
+        }
+        sink.poll_pending(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: TInput) -> Result<(), ItemPostError> {
+        let sink = self.get_mut();
+        if sink.sender.is_none() {
+            return Err(ItemPostError::StreamEnded);
+        }
+        debug_assert!(
+            sink.pending.is_none(),
+            "start_send called before poll_ready"
+        );
+        sink.pending = Some(item);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), ItemPostError>> {
+        self.get_mut().poll_pending(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), ItemPostError>> {
+        let sink = self.get_mut();
+        let flushed = sink.poll_pending(cx);
+        if flushed.is_ready() {
+            sink.sender = None;
+        }
+        flushed
+    }
+}
+
 #[derive(Debug)]
 pub enum ItemPostError {
     StreamEnded,
     UnknownError,
 }
 
+//Returned by try_post, hands the item back to the caller
+#[derive(Debug)]
+pub enum TryPostError<T> {
+    Full(T),
+    StreamEnded(T),
+}
+
+impl<T> TryPostError<T> {
+    pub fn into_inner(self) -> T {
+        match self {
+            TryPostError::Full(item) | TryPostError::StreamEnded(item) => item,
+        }
+    }
+}
+
+//Returned by post_timeout, hands the item back to the caller
+#[derive(Debug)]
+pub enum PostTimeoutError<T> {
+    Timeout(T),
+    StreamEnded(T),
+}
+
+impl<T> PostTimeoutError<T> {
+    pub fn into_inner(self) -> T {
+        match self {
+            PostTimeoutError::Timeout(item) | PostTimeoutError::StreamEnded(item) => item,
+        }
+    }
+}
+
 #[macro_export]
 macro_rules! pipeline_propagate {
-    ($threads:expr, $s1:expr) => {
+    ($threads:expr, $context:expr, $index:expr, $producers:expr, $s1:expr) => {
         {
             let (mode, factory) = $s1;
-            let mut block = InBlock::new(mode, factory);
-            $threads.push(block.monitor_posts());
+            let config = mode.configure(|config| config.with_producers($producers));
+            let mut block = InBlock::new(config, factory);
+            $threads.extend(block.monitor_posts(&$context.stage($index)));
             block
         }
     };
 
-    ($threads:expr, $s1:expr $(, $tail:expr)*) => {
+    ($threads:expr, $context:expr, $index:expr, $producers:expr, $s1:expr $(, $tail:expr)*) => {
         {
             let (mode, factory) = $s1;
-            let mut block = InOutBlock::new(
-                pipeline_propagate!($threads, $($tail),*),
-                mode, factory);
-            $threads.extend(block.monitor_posts());
+            let config = mode.configure(|config| config.with_producers($producers));
+            let producers = config.replicas();
+            let mut block = IntoBlock::into_block(
+                config,
+                pipeline_propagate!($threads, $context, $index + 1, producers, $($tail),*),
+                factory);
+            $threads.extend(block.monitor_posts(&$context.stage($index)));
             block
         }
     };
@@ -145,25 +571,58 @@ macro_rules! pipeline_propagate {
 
 #[macro_export]
 macro_rules! pipeline {
+    (capacity: $capacity:expr; source!($source:expr), $($stage:expr),+ $(,)?) => {
+        {
+            let capacity: usize = $capacity;
+            pipeline![source!($source), $(with_default_capacity!($stage, capacity)),+]
+        }
+    };
+
+    (capacity: $capacity:expr; $($stage:expr),+ $(,)?) => {
+        {
+            let capacity: usize = $capacity;
+            pipeline![$(with_default_capacity!($stage, capacity)),+]
+        }
+    };
+
+    (source!($source:expr), $($stage:expr),+ $(,)?) => {
+        pipeline![$($stage),+].with_source($source)
+    };
+
     ($s1:expr $(, $tail:expr)*) => {
         {
             let mut monitors = Vec::<MonitorLoop>::new();
+            let context = PipelineContext::new();
             let (mode, factory) = $s1;
-            let mut block = InOutBlock::new(
-                pipeline_propagate!(monitors, $($tail),*),
+            let producers = mode.replicas();
+            let mut block = IntoBlock::into_block(
                 mode,
+                pipeline_propagate!(monitors, context, 1, producers, $($tail),*),
                 factory);
-            monitors.extend(block.monitor_posts());
+            monitors.extend(block.monitor_posts(&context.stage(0)));
 
-            let mut pipeline = Pipeline::new(block, monitors);
+            let mut pipeline = Pipeline::new(block, monitors, context);
             pipeline.start();
             pipeline
         }
     };
 }
 
+//Only valid as the first stage of pipeline!: posts the items of an
+//iterator from a thread of its own and ends the stream when they run out
+#[macro_export]
+macro_rules! source {
+    ($source:expr) => {
+        compile_error!("source! can only be the first stage of pipeline!")
+    };
+}
+
 #[macro_export]
 macro_rules! parallel {
+    ($name:literal, $block:expr, $threads:expr) => {
+        named!(parallel!($block, $threads), $name)
+    };
+
     ($block:expr, $threads:expr) => {{
         let mode = BlockMode::Parallel($threads);
         let factory = move || $block;
@@ -171,8 +630,25 @@ macro_rules! parallel {
     }};
 }
 
+#[macro_export]
+macro_rules! parallel_ordered {
+    ($name:literal, $block:expr, $threads:expr) => {
+        named!(parallel_ordered!($block, $threads), $name)
+    };
+
+    ($block:expr, $threads:expr) => {{
+        let mode = BlockMode::OrderedParallel($threads);
+        let factory = move || $block;
+        (mode, factory)
+    }};
+}
+
 #[macro_export]
 macro_rules! sequential {
+    ($name:literal, $block:expr) => {
+        named!(sequential!($block), $name)
+    };
+
     ($block:expr) => {{
         let mode = BlockMode::Sequential(OrderingMode::Unordered);
         let factory = move || $block;
@@ -182,6 +658,10 @@ macro_rules! sequential {
 
 #[macro_export]
 macro_rules! sequential_ordered {
+    ($name:literal, $block:expr) => {
+        named!(sequential_ordered!($block), $name)
+    };
+
     ($block:expr) => {{
         let mode = BlockMode::Sequential(OrderingMode::Ordered);
         let factory = move || $block;
@@ -189,6 +669,138 @@ macro_rules! sequential_ordered {
     }};
 }
 
+//Names a stage. The name shows up in thread names, stats, traces, metrics,
+//errors and Pipeline::describe. parallel!, parallel_ordered!, sequential!
+//and sequential_ordered! also take it as their first argument.
+#[macro_export]
+macro_rules! named {
+    ($stage:expr, $name:expr) => {{
+        let (config, factory) = $stage;
+        (config.configure(|config| config.with_name($name)), factory)
+    }};
+}
+
+//Bounds the input queue of a stage: posting into a full stage blocks
+#[macro_export]
+macro_rules! bounded {
+    ($stage:expr, $capacity:expr) => {{
+        let (config, factory) = $stage;
+        (
+            config.configure(|config| config.with_capacity($capacity)),
+            factory,
+        )
+    }};
+}
+
+//Routes every item of a farm to a fixed replica, picked by the hash of
+//the key the function extracts from it
+#[macro_export]
+macro_rules! keyed {
+    ($stage:expr, $key:expr) => {{
+        let (config, factory) = $stage;
+        (config.configure(|config| config.with_key($key)), factory)
+    }};
+}
+
+//Picks how a farm hands its items out to the replicas, see LoadBalancing
+#[macro_export]
+macro_rules! balanced {
+    ($stage:expr, $balancing:expr) => {{
+        let (config, factory) = $stage;
+        (
+            config.configure(|config| config.with_balancing($balancing)),
+            factory,
+        )
+    }};
+}
+
+//Makes a stage take its items from a lock-free queue
+#[macro_export]
+macro_rules! lock_free {
+    ($stage:expr) => {{
+        let (config, factory) = $stage;
+        (
+            config.configure(|config| config.with_queue(QueueKind::LockFree)),
+            factory,
+        )
+    }};
+}
+
+//Makes a stage take its items from the blocking queue, even where it
+//would get a lock-free one
+#[macro_export]
+macro_rules! blocking {
+    ($stage:expr) => {{
+        let (config, factory) = $stage;
+        (
+            config.configure(|config| config.with_queue(QueueKind::Blocking)),
+            factory,
+        )
+    }};
+}
+
+//Makes a stage keep its items in a storage of its own, built by the
+//function out of the capacity of the stage, see WorkStorage
+#[macro_export]
+macro_rules! stored {
+    ($stage:expr, $storage:expr) => {{
+        let (config, factory) = $stage;
+        (
+            config.configure(|config| config.with_storage($storage)),
+            factory,
+        )
+    }};
+}
+
+//Same for ordered sequential stages, see OrderedWorkStorage
+#[macro_export]
+macro_rules! stored_ordered {
+    ($stage:expr, $storage:expr) => {{
+        let (config, factory) = $stage;
+        (
+            config.configure(|config| config.with_ordered_storage($storage)),
+            factory,
+        )
+    }};
+}
+
+//Bounds how far ahead of its turn an item can wait in an ordered
+//sequential stage. Posts wait while that many posted items have not
+//gone past the stage yet.
+#[macro_export]
+macro_rules! windowed {
+    ($stage:expr, $window:expr) => {{
+        let (config, factory) = $stage;
+        (
+            config.configure(|config| config.with_reorder_window($window)),
+            factory,
+        )
+    }};
+}
+
+//Runs the replicas of a stage that implements AsyncInOut as tasks on
+//the given tokio runtime
+#[cfg(feature = "tokio")]
+#[macro_export]
+macro_rules! on_runtime {
+    ($stage:expr, $runtime:expr) => {{
+        let (config, factory) = $stage;
+        (AsyncConfig::new(config, $runtime), factory)
+    }};
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! with_default_capacity {
+    ($stage:expr, $capacity:expr) => {{
+        let (config, factory) = $stage;
+        (
+            config.configure(|config| config.with_default_capacity($capacity)),
+            factory,
+        )
+    }};
+}
+
 #[macro_export]
 macro_rules! collect {
     () => {{
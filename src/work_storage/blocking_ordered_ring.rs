@@ -0,0 +1,108 @@
+use crate::work_storage::*;
+use parking_lot::{Condvar, Mutex};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/*
+ * Bounded alternative to BlockingOrderedSet. The items inside a window of
+ * posted items, starting at the one the stage waits for, go in a ring with
+ * one slot per posted item of the window, so finding the next item is an
+ * index instead of a lookup. Values a flat-map stage emitted for the same
+ * input share a slot.
+ * The ring never makes a producer wait, since the replica posting an item
+ * ahead of the window may be the one holding the item the stage waits for.
+ * Windowed stages give posts tokens of their own instead (see
+ * PipelineContext::add_window), so posts wait until what they post fits.
+ * Items that come in further ahead anyway, as when the ring is given to
+ * stored_ordered!, wait in a map next to it.
+ * The Stop token is kept apart, like in BlockingQueue.
+ */
+pub struct BlockingOrderedRing<T> {
+    state: Mutex<RingState<T>>,
+    new_item_notifier: Condvar,
+}
+
+struct RingState<T> {
+    slots: Vec<Vec<TimestampedWorkItem<T>>>,
+    //items that did not fit in the window when they came in
+    ahead: BTreeMap<Order, TimestampedWorkItem<T>>,
+    //the order the stage waits for
+    next_item: Order,
+    stop: Option<Order>,
+}
+
+impl<T> BlockingOrderedRing<T> {
+    pub fn new(window: usize) -> Arc<BlockingOrderedRing<T>> {
+        assert!(window != 0, "reorder window must be at least 1");
+        Arc::new(BlockingOrderedRing {
+            state: Mutex::new(RingState {
+                slots: (0..window).map(|_| vec![]).collect(),
+                ahead: BTreeMap::new(),
+                next_item: Order::new(0),
+                stop: None,
+            }),
+            new_item_notifier: Condvar::new(),
+        })
+    }
+
+    pub fn window(&self) -> usize {
+        self.state.lock().slots.len()
+    }
+}
+
+impl<T> RingState<T> {
+    fn slot(&self, order: &Order) -> usize {
+        (order.item % self.slots.len() as u64) as usize
+    }
+
+    fn fits(&self, order: &Order) -> bool {
+        order.item < self.next_item.item + self.slots.len() as u64
+    }
+
+    fn take(&mut self, order: &Order) -> Option<TimestampedWorkItem<T>> {
+        let slot = self.slot(order);
+        match self.slots[slot].iter().position(|item| item.1 == *order) {
+            Some(position) => Some(self.slots[slot].swap_remove(position)),
+            None => self.ahead.remove(order),
+        }
+    }
+}
+
+impl<T> OrderedWorkStorage<T> for BlockingOrderedRing<T> {
+    fn len(&self) -> usize {
+        let state = self.state.lock();
+        state.slots.iter().map(Vec::len).sum::<usize>()
+            + state.ahead.len()
+            + state.stop.iter().count()
+    }
+
+    fn enqueue(&self, item: TimestampedWorkItem<T>) {
+        let mut state = self.state.lock();
+        match item {
+            TimestampedWorkItem(WorkItem::Stop, order, _) => state.stop = Some(order),
+            TimestampedWorkItem(_, ref order, _) if !state.fits(order) => {
+                state.ahead.insert(order.clone(), item);
+            }
+            TimestampedWorkItem(_, ref order, _) => {
+                let slot = state.slot(order);
+                state.slots[slot].push(item);
+            }
+        }
+        //the stage only wakes up for the item it waits for
+        self.new_item_notifier.notify_one();
+    }
+
+    fn wait_and_remove(&self, order: &Order) -> TimestampedWorkItem<T> {
+        let mut state = self.state.lock();
+        loop {
+            if let Some(item) = state.take(order) {
+                state.next_item = item.1.next();
+                return item;
+            }
+            if state.stop.as_ref() == Some(order) {
+                return TimestampedWorkItem::stop(order.clone());
+            }
+            self.new_item_notifier.wait(&mut state);
+        }
+    }
+}
@@ -4,35 +4,41 @@ use std::collections::BTreeMap;
 use std::sync::Arc;
 
 pub struct BlockingOrderedSet<T> {
-    storage: Mutex<BTreeMap<u64, TimestampedWorkItem<T>>>,
+    storage: Mutex<BTreeMap<Order, TimestampedWorkItem<T>>>,
     new_item_notifier: Condvar,
 }
 
 impl<T> BlockingOrderedSet<T> {
     pub fn new() -> Arc<BlockingOrderedSet<T>> {
         Arc::new(BlockingOrderedSet {
-            storage: Mutex::new(BTreeMap::<u64, TimestampedWorkItem<T>>::new()),
+            storage: Mutex::new(BTreeMap::<Order, TimestampedWorkItem<T>>::new()),
             new_item_notifier: Condvar::new(),
         })
     }
+}
+
+impl<T> OrderedWorkStorage<T> for BlockingOrderedSet<T> {
+    fn len(&self) -> usize {
+        self.storage.lock().len()
+    }
 
-    pub fn enqueue(&self, item: TimestampedWorkItem<T>) {
+    fn enqueue(&self, item: TimestampedWorkItem<T>) {
         let mut queue = self.storage.lock();
         match item {
-            TimestampedWorkItem(_, order) => queue.insert(order, item),
+            TimestampedWorkItem(_, ref order, _) => queue.insert(order.clone(), item),
         };
         self.new_item_notifier.notify_one();
     }
 
-    pub fn wait_and_remove(&self, item: u64) -> TimestampedWorkItem<T> {
+    fn wait_and_remove(&self, item: &Order) -> TimestampedWorkItem<T> {
         let mut storage = self.storage.lock();
-        while (*storage).is_empty() || !(*storage).contains_key(&item) {
+        while (*storage).is_empty() || !(*storage).contains_key(item) {
             self.new_item_notifier.wait(&mut storage);
         }
-        let removed_item = storage.remove(&item);
+        let removed_item = storage.remove(item);
 
         match removed_item {
-            Some(value) => return value,
+            Some(value) => value,
             None => {
                 panic!("Condition variable waited until item was found, but removal failed")
             }
@@ -1,61 +1,252 @@
 use crate::work_storage::*;
-use parking_lot::{Condvar, Mutex};
+use parking_lot::{Condvar, Mutex, MutexGuard};
 use std::collections::VecDeque;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
 
 /*
  * Thread-safe queue for storing work items. Each enqueued item gets a timestamp
- * tag.
+ * tag. The queue can optionally be bounded: producers block (or fail, for the
+ * try_/timeout variants) while it is full. Stop tokens are never blocked, so a
+ * full queue can always be shut down.
+ * Async tasks can wait on the queue too, through the poll_ methods: they are
+ * woken up by the same events that wake blocked threads.
  */
 pub struct BlockingQueue<T> {
-    queue: (Mutex<VecDeque<TimestampedWorkItem<T>>>, Condvar),
-    number_of_inserts: AtomicUsize,
+    queue: (Mutex<QueueState<T>>, Condvar),
+    space_notifier: Condvar,
+    capacity: Option<usize>,
+    //whether enqueue stamps values with their ingress time
+    stamps: AtomicBool,
+}
+
+struct QueueState<T> {
+    items: VecDeque<TimestampedWorkItem<T>>,
+    number_of_inserts: u64,
+    //tasks waiting for an item and for room in the queue
+    readers: Vec<Waker>,
+    writers: Vec<Waker>,
 }
 
 impl<T> BlockingQueue<T> {
     pub fn new() -> Arc<BlockingQueue<T>> {
+        BlockingQueue::with_capacity(None)
+    }
+
+    pub fn bounded(capacity: usize) -> Arc<BlockingQueue<T>> {
+        BlockingQueue::with_capacity(Some(capacity))
+    }
+
+    pub fn with_capacity(capacity: Option<usize>) -> Arc<BlockingQueue<T>> {
+        assert!(capacity != Some(0), "queue capacity must be at least 1");
         Arc::new(BlockingQueue {
             queue: (
-                Mutex::new(VecDeque::<TimestampedWorkItem<T>>::new()),
+                Mutex::new(QueueState {
+                    items: VecDeque::new(),
+                    number_of_inserts: 0,
+                    readers: vec![],
+                    writers: vec![],
+                }),
                 Condvar::new(),
             ),
-            number_of_inserts: AtomicUsize::new(0),
+            space_notifier: Condvar::new(),
+            capacity,
+            stamps: AtomicBool::new(false),
         })
     }
 
-    pub fn enqueue(&self, item: WorkItem<T>) -> u64 {
-        let (mutex, cvar) = &self.queue;
-        let mut queue = mutex.lock();
-        let current = self.number_of_inserts.load(Ordering::SeqCst);
+    //Makes enqueue stamp values with their ingress time, for pipelines
+    //that measure latency
+    pub(crate) fn stamp_ingress(&self) {
+        self.stamps.store(true, Ordering::Relaxed);
+    }
 
-        queue.push_back(TimestampedWorkItem(item, current as u64));
+    fn has_space(&self, state: &QueueState<T>, item: &WorkItem<T>) -> bool {
+        match (item, self.capacity) {
+            (WorkItem::Stop, _) | (_, None) => true,
+            (_, Some(capacity)) => state.items.len() < capacity,
+        }
+    }
 
-        self.number_of_inserts.store(current + 1, Ordering::SeqCst);
+    //Blocks until the item fits. Returns false if the deadline passed first.
+    fn wait_for_space(
+        &self,
+        state: &mut MutexGuard<QueueState<T>>,
+        item: &WorkItem<T>,
+        deadline: Option<Instant>,
+    ) -> bool {
+        while !self.has_space(state, item) {
+            match deadline {
+                Some(deadline) => {
+                    if self.space_notifier.wait_until(state, deadline).timed_out() {
+                        return self.has_space(state, item);
+                    }
+                }
+                None => self.space_notifier.wait(state),
+            }
+        }
+        true
+    }
 
-        cvar.notify_one();
-        return current as u64;
+    //Blocks until there is room for a value, without queueing one.
+    //Returns false if the deadline passed first.
+    pub(crate) fn wait_for_room(&self, deadline: Instant) -> bool {
+        let mut state = self.queue.0.lock();
+        //any item but a Stop needs room
+        self.wait_for_space(&mut state, &WorkItem::Dropped, Some(deadline))
     }
 
-    pub fn enqueue_timestamped(&self, item: TimestampedWorkItem<T>) {
-        let (mutex, cvar) = &self.queue;
-        mutex.lock().push_back(item);
-        cvar.notify_one();
+    fn push(&self, state: &mut QueueState<T>, item: TimestampedWorkItem<T>) {
+        state.items.push_back(item);
+        self.queue.1.notify_one();
+        //a woken task that finds nothing just registers again
+        for reader in state.readers.drain(..) {
+            reader.wake();
+        }
+    }
+
+    fn push_back(&self, state: &mut QueueState<T>, item: WorkItem<T>) -> u64 {
+        let current = state.number_of_inserts;
+        let stamp = self.stamps.load(Ordering::Relaxed);
+        self.push(state, TimestampedWorkItem::numbered(item, current, stamp));
+        state.number_of_inserts += 1;
+        current
     }
 
-    pub fn wait_and_dequeue(&self) -> TimestampedWorkItem<T> {
-        let &(ref mutex, ref cvar) = &self.queue;
-        let mut queue = mutex.lock();
-        while queue.is_empty() {
-            cvar.wait(&mut queue);
+    fn pop_front(&self, state: &mut QueueState<T>) -> Option<TimestampedWorkItem<T>> {
+        let popped = state.items.pop_front();
+        if popped.is_some() && self.capacity.is_some() {
+            self.space_notifier.notify_one();
+            for writer in state.writers.drain(..) {
+                writer.wake();
+            }
         }
+        popped
+    }
+}
 
-        debug_assert!(queue.is_empty() == false);
+impl<T> WorkStorage<T> for BlockingQueue<T> {
+    fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
 
-        let popped = queue.pop_front();
+    fn len(&self) -> usize {
+        self.queue.0.lock().items.len()
+    }
 
-        debug_assert!(popped.is_some());
+    fn enqueue(&self, item: WorkItem<T>) -> u64 {
+        let mut state = self.queue.0.lock();
+        self.wait_for_space(&mut state, &item, None);
+        self.push_back(&mut state, item)
+    }
+
+    fn try_enqueue(&self, item: WorkItem<T>) -> Result<u64, WorkItem<T>> {
+        let mut state = self.queue.0.lock();
+        if !self.has_space(&state, &item) {
+            return Err(item);
+        }
+        Ok(self.push_back(&mut state, item))
+    }
+
+    fn enqueue_timeout(&self, item: WorkItem<T>, timeout: Duration) -> Result<u64, WorkItem<T>> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.queue.0.lock();
+        if !self.wait_for_space(&mut state, &item, Some(deadline)) {
+            return Err(item);
+        }
+        Ok(self.push_back(&mut state, item))
+    }
+
+    fn enqueue_timestamped(&self, item: TimestampedWorkItem<T>) {
+        let mut state = self.queue.0.lock();
+        self.wait_for_space(&mut state, &item.0, None);
+        self.push(&mut state, item);
+    }
+
+    //For items that already have their timestamp, gives them back if the
+    //queue is full
+    fn try_enqueue_timestamped(
+        &self,
+        item: TimestampedWorkItem<T>,
+    ) -> Result<(), TimestampedWorkItem<T>> {
+        let mut state = self.queue.0.lock();
+        if !self.has_space(&state, &item.0) {
+            return Err(item);
+        }
+        self.push(&mut state, item);
+        Ok(())
+    }
+
+    fn enqueue_timestamped_timeout(
+        &self,
+        item: TimestampedWorkItem<T>,
+        timeout: Duration,
+    ) -> Result<(), TimestampedWorkItem<T>> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.queue.0.lock();
+        if !self.wait_for_space(&mut state, &item.0, Some(deadline)) {
+            return Err(item);
+        }
+        self.push(&mut state, item);
+        Ok(())
+    }
+
+    fn wait_and_dequeue(&self) -> TimestampedWorkItem<T> {
+        let (mutex, cvar) = &self.queue;
+        let mut state = mutex.lock();
+        loop {
+            if let Some(item) = self.pop_front(&mut state) {
+                return item;
+            }
+            cvar.wait(&mut state);
+        }
+    }
+
+    fn try_dequeue(&self) -> Option<TimestampedWorkItem<T>> {
+        self.pop_front(&mut self.queue.0.lock())
+    }
+
+    //Takes the next item unless it is a Stop, which is left in place.
+    //Lets consumers look at other queues before they stop.
+    fn steal(&self) -> Option<TimestampedWorkItem<T>> {
+        let mut state = self.queue.0.lock();
+        match state.items.front() {
+            Some(TimestampedWorkItem(WorkItem::Stop, ..)) | None => None,
+            Some(_) => self.pop_front(&mut state),
+        }
+    }
+
+    //Non-blocking dequeue for async tasks: registers the task to be woken
+    //up by the next insert when the queue is empty
+    fn poll_dequeue(&self, cx: &mut Context<'_>) -> Poll<TimestampedWorkItem<T>> {
+        let mut state = self.queue.0.lock();
+        match self.pop_front(&mut state) {
+            Some(item) => Poll::Ready(item),
+            None => {
+                register(&mut state.readers, cx);
+                Poll::Pending
+            }
+        }
+    }
+
+    //Ready once there is room for one more item. Another producer can
+    //still take that room first, so the enqueue itself has to be tried.
+    fn poll_space(&self, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.queue.0.lock();
+        match self.capacity {
+            Some(capacity) if state.items.len() >= capacity => {
+                register(&mut state.writers, cx);
+                Poll::Pending
+            }
+            _ => Poll::Ready(()),
+        }
+    }
+}
 
-        popped.unwrap()
+fn register(wakers: &mut Vec<Waker>, cx: &Context<'_>) {
+    if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+        wakers.push(cx.waker().clone());
     }
 }
@@ -0,0 +1,343 @@
+use crate::work_storage::mpsc_list::MpscList;
+use crate::work_storage::parking::Parking;
+use crate::work_storage::spmc_list::SpmcList;
+use crate::work_storage::spsc_ring::SpscRing;
+use crate::work_storage::*;
+use crossbeam_queue::SegQueue;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/*
+ * Lock-free alternative to BlockingQueue, with the same behaviour. Items go
+ * through a lock-free MPMC queue and take their timestamp from an atomic
+ * counter. A bounded queue reserves a slot before the timestamp, so refused
+ * items never take one. Stop tokens do not take a slot.
+ * Consumers and producers only take a lock when they have to park: they back
+ * off for a while first, then sleep until the other side unparks them. The other
+ * side only takes the lock when someone is parked.
+ * Links between stages that know how many threads push and pop get a queue
+ * made for them instead: see for_link.
+ */
+pub struct LockFreeQueue<T> {
+    items: Items<TimestampedWorkItem<T>>,
+    capacity: Option<usize>,
+    //items in the queue, Stop tokens included
+    len: AtomicUsize,
+    //slots taken by values, only kept for bounded queues
+    slots: AtomicUsize,
+    number_of_inserts: AtomicU64,
+    //whether enqueue stamps values with their ingress time
+    stamps: AtomicBool,
+    readers: Parking,
+    writers: Parking,
+}
+
+enum Items<T> {
+    Mpmc(Box<SegQueue<T>>),
+    Spsc(SpscRing<T>),
+    Mpsc(MpscList<T>),
+    Spmc(SpmcList<T>),
+}
+
+impl<T> Items<T> {
+    fn push(&self, item: T) {
+        match self {
+            Items::Mpmc(items) => items.push(item),
+            Items::Spsc(items) => items.push(item),
+            Items::Mpsc(items) => items.push(item),
+            Items::Spmc(items) => items.push(item),
+        }
+    }
+
+    fn pop(&self) -> Option<T> {
+        match self {
+            Items::Mpmc(items) => items.pop(),
+            Items::Spsc(items) => items.pop(),
+            Items::Mpsc(items) => items.pop(),
+            Items::Spmc(items) => items.pop(),
+        }
+    }
+}
+
+//An idle thread checks the queue again SPIN_LIMIT times in a busy loop,
+//then YIELD_LIMIT times giving up its time slice, and then parks
+const SPIN_LIMIT: u32 = 16;
+const YIELD_LIMIT: u32 = 16;
+
+//Returns false once it is time to park
+fn backoff(attempts: &mut u32) -> bool {
+    *attempts += 1;
+    if *attempts <= SPIN_LIMIT {
+        std::hint::spin_loop();
+        true
+    } else if *attempts <= SPIN_LIMIT + YIELD_LIMIT {
+        std::thread::yield_now();
+        true
+    } else {
+        false
+    }
+}
+
+impl<T> LockFreeQueue<T> {
+    pub fn new() -> Arc<LockFreeQueue<T>> {
+        LockFreeQueue::with_capacity(None)
+    }
+
+    pub fn bounded(capacity: usize) -> Arc<LockFreeQueue<T>> {
+        LockFreeQueue::with_capacity(Some(capacity))
+    }
+
+    pub fn with_capacity(capacity: Option<usize>) -> Arc<LockFreeQueue<T>> {
+        LockFreeQueue::with_items(Items::Mpmc(Box::default()), capacity)
+    }
+
+    //Queue for a link with the given number of producers and consumers.
+    //Using it from more threads than that panics.
+    pub fn for_link(
+        producers: usize,
+        consumers: usize,
+        capacity: Option<usize>,
+    ) -> Arc<LockFreeQueue<T>> {
+        let items = match (producers, consumers) {
+            (1, 1) => Items::Spsc(SpscRing::new()),
+            (1, _) => Items::Spmc(SpmcList::new()),
+            (_, 1) => Items::Mpsc(MpscList::new()),
+            _ => Items::Mpmc(Box::default()),
+        };
+        LockFreeQueue::with_items(items, capacity)
+    }
+
+    fn with_items(
+        items: Items<TimestampedWorkItem<T>>,
+        capacity: Option<usize>,
+    ) -> Arc<LockFreeQueue<T>> {
+        assert!(capacity != Some(0), "queue capacity must be at least 1");
+        Arc::new(LockFreeQueue {
+            items,
+            capacity,
+            len: AtomicUsize::new(0),
+            slots: AtomicUsize::new(0),
+            number_of_inserts: AtomicU64::new(0),
+            stamps: AtomicBool::new(false),
+            readers: Parking::new(),
+            writers: Parking::new(),
+        })
+    }
+
+    //Makes enqueue stamp values with their ingress time, for pipelines
+    //that measure latency
+    pub(crate) fn stamp_ingress(&self) {
+        self.stamps.store(true, Ordering::Relaxed);
+    }
+
+    //Which side of the link can have more than one thread
+    pub fn kind(&self) -> &'static str {
+        match self.items {
+            Items::Mpmc(_) => "mpmc",
+            Items::Spsc(_) => "spsc",
+            Items::Mpsc(_) => "mpsc",
+            Items::Spmc(_) => "spmc",
+        }
+    }
+
+    fn takes_slot(&self, item: &WorkItem<T>) -> bool {
+        self.capacity.is_some() && !matches!(item, WorkItem::Stop)
+    }
+
+    //Takes a slot for the item if there is room. Always succeeds for
+    //unbounded queues and Stop tokens.
+    fn reserve(&self, item: &WorkItem<T>) -> bool {
+        let capacity = match self.capacity {
+            Some(capacity) if self.takes_slot(item) => capacity,
+            _ => return true,
+        };
+        self.slots
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |slots| {
+                (slots < capacity).then_some(slots + 1)
+            })
+            .is_ok()
+    }
+
+    //Parks until the item fits. Returns false if the deadline passed first.
+    fn wait_for_space(&self, item: &WorkItem<T>, deadline: Option<Instant>) -> bool {
+        let mut attempts = 0;
+        loop {
+            if self.reserve(item) {
+                return true;
+            }
+            if backoff(&mut attempts) {
+                continue;
+            }
+            let parked = self.writers.park(deadline, || self.has_space(item));
+            if !parked {
+                return self.reserve(item);
+            }
+        }
+    }
+
+    //Parks until there is room for a value, without taking it. Returns
+    //false if the deadline passed first.
+    pub(crate) fn wait_for_room(&self, deadline: Instant) -> bool {
+        self.writers
+            .park(Some(deadline), || self.has_space(&WorkItem::Dropped))
+    }
+
+    fn has_space(&self, item: &WorkItem<T>) -> bool {
+        match self.capacity {
+            Some(capacity) if self.takes_slot(item) => self.slots.load(Ordering::SeqCst) < capacity,
+            _ => true,
+        }
+    }
+
+    //The item already has its slot. Counted first, so the length never
+    //goes below the number of items in the queue.
+    fn push(&self, item: TimestampedWorkItem<T>) {
+        self.len.fetch_add(1, Ordering::SeqCst);
+        self.items.push(item);
+        self.readers.unpark();
+    }
+
+    fn push_back(&self, item: WorkItem<T>) -> u64 {
+        let current = self.number_of_inserts.fetch_add(1, Ordering::SeqCst);
+        let stamp = self.stamps.load(Ordering::Relaxed);
+        self.push(TimestampedWorkItem::numbered(item, current, stamp));
+        current
+    }
+
+    fn pop_front(&self) -> Option<TimestampedWorkItem<T>> {
+        let popped = self.items.pop()?;
+        self.len.fetch_sub(1, Ordering::SeqCst);
+        if self.takes_slot(&popped.0) {
+            self.slots.fetch_sub(1, Ordering::SeqCst);
+            self.writers.unpark();
+        }
+        Some(popped)
+    }
+}
+
+impl<T> WorkStorage<T> for LockFreeQueue<T> {
+    fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+
+    fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    fn enqueue(&self, item: WorkItem<T>) -> u64 {
+        self.wait_for_space(&item, None);
+        self.push_back(item)
+    }
+
+    fn try_enqueue(&self, item: WorkItem<T>) -> Result<u64, WorkItem<T>> {
+        if !self.reserve(&item) {
+            return Err(item);
+        }
+        Ok(self.push_back(item))
+    }
+
+    fn enqueue_timeout(&self, item: WorkItem<T>, timeout: Duration) -> Result<u64, WorkItem<T>> {
+        if !self.wait_for_space(&item, Some(Instant::now() + timeout)) {
+            return Err(item);
+        }
+        Ok(self.push_back(item))
+    }
+
+    fn enqueue_timestamped(&self, item: TimestampedWorkItem<T>) {
+        self.wait_for_space(&item.0, None);
+        self.push(item);
+    }
+
+    fn try_enqueue_timestamped(
+        &self,
+        item: TimestampedWorkItem<T>,
+    ) -> Result<(), TimestampedWorkItem<T>> {
+        if !self.reserve(&item.0) {
+            return Err(item);
+        }
+        self.push(item);
+        Ok(())
+    }
+
+    fn enqueue_timestamped_timeout(
+        &self,
+        item: TimestampedWorkItem<T>,
+        timeout: Duration,
+    ) -> Result<(), TimestampedWorkItem<T>> {
+        if !self.wait_for_space(&item.0, Some(Instant::now() + timeout)) {
+            return Err(item);
+        }
+        self.push(item);
+        Ok(())
+    }
+
+    fn wait_and_dequeue(&self) -> TimestampedWorkItem<T> {
+        let mut attempts = 0;
+        loop {
+            if let Some(item) = self.pop_front() {
+                return item;
+            }
+            if backoff(&mut attempts) {
+                continue;
+            }
+            self.readers.park(None, || !self.is_empty());
+        }
+    }
+
+    fn try_dequeue(&self) -> Option<TimestampedWorkItem<T>> {
+        self.pop_front()
+    }
+
+    //Only the consumer may pop from a single consumer queue, and a Stop
+    //taken by mistake would be pushed back by a second producer. Work
+    //stealing farms give their queues as many consumers as replicas. The
+    //Stop is the last push of a single producer, so putting it back into a
+    //single producer queue is fine.
+    fn steal(&self) -> Option<TimestampedWorkItem<T>> {
+        if !matches!(self.items, Items::Mpmc(_) | Items::Spmc(_)) {
+            return None;
+        }
+        match self.pop_front()? {
+            TimestampedWorkItem(WorkItem::Stop, order, _) => {
+                self.push(TimestampedWorkItem::stop(order));
+                None
+            }
+            item => Some(item),
+        }
+    }
+
+    fn poll_dequeue(&self, cx: &mut Context<'_>) -> Poll<TimestampedWorkItem<T>> {
+        if let Some(item) = self.pop_front() {
+            return Poll::Ready(item);
+        }
+        self.readers.register(cx);
+        //an item pushed before the registration would not wake the task
+        match self.pop_front() {
+            Some(item) => Poll::Ready(item),
+            None => {
+                if !self.is_empty() {
+                    cx.waker().wake_by_ref();
+                }
+                Poll::Pending
+            }
+        }
+    }
+
+    fn poll_space(&self, cx: &mut Context<'_>) -> Poll<()> {
+        let fits = |queue: &LockFreeQueue<T>| match queue.capacity {
+            Some(capacity) => queue.slots.load(Ordering::SeqCst) < capacity,
+            None => true,
+        };
+        if fits(self) {
+            return Poll::Ready(());
+        }
+        self.writers.register(cx);
+        if fits(self) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
@@ -1,7 +1,21 @@
+pub mod blocking_ordered_ring;
 pub mod blocking_ordered_set;
 pub mod blocking_queue;
+pub mod lock_free_queue;
+pub(crate) mod mpsc_list;
+pub(crate) mod parking;
+pub mod reorder_buffer;
+pub(crate) mod spmc_list;
+pub(crate) mod spsc_ring;
+pub mod storage;
 pub mod work_item;
+pub mod work_queue;
 
+pub use blocking_ordered_ring::BlockingOrderedRing;
 pub use blocking_ordered_set::BlockingOrderedSet;
 pub use blocking_queue::BlockingQueue;
-pub use work_item::{TimestampedWorkItem, WorkItem};
+pub use lock_free_queue::LockFreeQueue;
+pub use reorder_buffer::ReorderBuffer;
+pub use storage::{OrderedWorkStorage, SharedOrderedStorage, SharedStorage, WorkStorage};
+pub use work_item::{ItemFailure, Order, TimestampedWorkItem, WorkItem};
+pub use work_queue::{OrderedWorkQueue, QueueKind, WorkQueue};
@@ -0,0 +1,77 @@
+use crate::work_storage::spsc_ring::Claim;
+use std::cell::UnsafeCell;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+/*
+ * Multi-producer single-consumer queue, for links where the replicas of a
+ * farm feed a stage with one replica. Producers never wait: they swap
+ * themselves in as the newest node and then link the one before to it. The
+ * consumer walks the list from the oldest node, without atomic read-modify-
+ * writes. While a producer is between the two steps, the consumer sees the
+ * list end early, and the caller has to look again.
+ * Only one thread may pop at a time, see SpscRing.
+ */
+pub(crate) struct MpscList<T> {
+    newest: AtomicPtr<Node<T>>,
+    //only touched by the consumer. Its value was already taken.
+    oldest: UnsafeCell<*mut Node<T>>,
+    popping: AtomicBool,
+}
+
+struct Node<T> {
+    next: AtomicPtr<Node<T>>,
+    value: Option<T>,
+}
+
+unsafe impl<T: Send> Send for MpscList<T> {}
+unsafe impl<T: Send> Sync for MpscList<T> {}
+
+impl<T> Node<T> {
+    fn allocate(value: Option<T>) -> *mut Node<T> {
+        Box::into_raw(Box::new(Node {
+            next: AtomicPtr::new(ptr::null_mut()),
+            value,
+        }))
+    }
+}
+
+impl<T> MpscList<T> {
+    pub(crate) fn new() -> MpscList<T> {
+        let stub = Node::allocate(None);
+        MpscList {
+            newest: AtomicPtr::new(stub),
+            oldest: UnsafeCell::new(stub),
+            popping: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn push(&self, item: T) {
+        let node = Node::allocate(Some(item));
+        let previous = self.newest.swap(node, Ordering::AcqRel);
+        unsafe { (*previous).next.store(node, Ordering::Release) };
+    }
+
+    pub(crate) fn pop(&self) -> Option<T> {
+        let _claim = Claim::new(&self.popping, "pop");
+        unsafe {
+            let oldest = &mut *self.oldest.get();
+            let next = (**oldest).next.load(Ordering::Acquire);
+            if next.is_null() {
+                return None;
+            }
+            drop(Box::from_raw(*oldest));
+            *oldest = next;
+            (*next).value.take()
+        }
+    }
+}
+
+impl<T> Drop for MpscList<T> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+        unsafe {
+            drop(Box::from_raw(*self.oldest.get()));
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use parking_lot::{Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Waker};
+use std::time::Instant;
+
+//Threads and tasks waiting for something that other threads signal
+//without a lock, such as one side of a lock-free queue. The waiting count
+//is raised before the waiter checks its condition one last time, and the
+//other side checks it after changing what the condition looks at, so one
+//of the two always sees the other.
+pub(crate) struct Parking {
+    waiting: AtomicUsize,
+    wakers: Mutex<Vec<Waker>>,
+    condvar: Condvar,
+}
+
+impl Parking {
+    pub(crate) fn new() -> Parking {
+        Parking {
+            waiting: AtomicUsize::new(0),
+            wakers: Mutex::new(vec![]),
+            condvar: Condvar::new(),
+        }
+    }
+
+    //Sleeps unless ready() says there is no need to. Returns false if the
+    //deadline passed.
+    pub(crate) fn park(&self, deadline: Option<Instant>, ready: impl Fn() -> bool) -> bool {
+        let mut wakers = self.wakers.lock();
+        self.waiting.fetch_add(1, Ordering::SeqCst);
+        let mut in_time = true;
+        if !ready() {
+            in_time = match deadline {
+                Some(deadline) => !self.condvar.wait_until(&mut wakers, deadline).timed_out(),
+                None => {
+                    self.condvar.wait(&mut wakers);
+                    true
+                }
+            };
+        }
+        self.waiting.fetch_sub(1, Ordering::SeqCst);
+        in_time
+    }
+
+    pub(crate) fn register(&self, cx: &Context<'_>) {
+        let mut wakers = self.wakers.lock();
+        if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            wakers.push(cx.waker().clone());
+            self.waiting.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    pub(crate) fn unpark(&self) {
+        if self.waiting.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        let mut wakers = self.wakers.lock();
+        self.condvar.notify_one();
+        self.waiting.fetch_sub(wakers.len(), Ordering::SeqCst);
+        for waker in wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
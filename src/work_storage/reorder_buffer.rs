@@ -0,0 +1,97 @@
+use crate::work_storage::*;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/*
+ * Puts the output of a farm back in sequence. Replicas push their results as
+ * they finish and the buffer hands them to the emit callback strictly in
+ * order, see Order. Emission happens while holding the lock, so the receiving
+ * stage sees the items in order even with many replicas pushing concurrently.
+ * Async replicas cannot wait for the next stage while holding the lock, so
+ * one of them at a time takes the items that are ready and sends them, see
+ * push_ready.
+ */
+pub struct ReorderBuffer<T> {
+    state: Mutex<ReorderState<T>>,
+}
+
+struct ReorderState<T> {
+    next_item: Order,
+    pending: BTreeMap<Order, TimestampedWorkItem<T>>,
+    //an async replica is sending the items it took
+    sending: bool,
+}
+
+impl<T> ReorderBuffer<T> {
+    pub fn new() -> Arc<ReorderBuffer<T>> {
+        Arc::new(ReorderBuffer {
+            state: Mutex::new(ReorderState {
+                next_item: Order::new(0),
+                pending: BTreeMap::new(),
+                sending: false,
+            }),
+        })
+    }
+
+    //Returns how many items are still waiting for an earlier one
+    pub fn push<F>(&self, item: TimestampedWorkItem<T>, mut emit: F) -> usize
+    where
+        F: FnMut(TimestampedWorkItem<T>),
+    {
+        let mut state = self.state.lock();
+        state.pending.insert(item.1.clone(), item);
+
+        loop {
+            let next_item = state.next_item.clone();
+            match state.pending.remove(&next_item) {
+                Some(item) => {
+                    state.next_item = item.1.next();
+                    emit(item);
+                }
+                None => break,
+            }
+        }
+        state.pending.len()
+    }
+
+    //Returns the items that can be sent now, unless another replica is
+    //sending. The replica that got any sends them and then calls
+    //take_ready, until it gets none.
+    pub fn push_ready(&self, item: TimestampedWorkItem<T>) -> Vec<TimestampedWorkItem<T>> {
+        let mut state = self.state.lock();
+        state.pending.insert(item.1.clone(), item);
+        if state.sending {
+            return vec![];
+        }
+        let ready = state.take_ready();
+        state.sending = !ready.is_empty();
+        ready
+    }
+
+    pub fn take_ready(&self) -> Vec<TimestampedWorkItem<T>> {
+        let mut state = self.state.lock();
+        let ready = state.take_ready();
+        state.sending = !ready.is_empty();
+        ready
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> ReorderState<T> {
+    fn take_ready(&mut self) -> Vec<TimestampedWorkItem<T>> {
+        let mut ready = vec![];
+        while let Some(item) = self.pending.remove(&self.next_item) {
+            self.next_item = item.1.next();
+            ready.push(item);
+        }
+        ready
+    }
+}
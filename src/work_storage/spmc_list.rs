@@ -0,0 +1,240 @@
+use crate::work_storage::spsc_ring::Claim;
+use std::cell::UnsafeCell;
+use std::hint::spin_loop;
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+/*
+ * Single-producer multi-consumer queue, for links where a stage with one
+ * replica feeds a farm. Items go in blocks of slots that are chained as the
+ * producer fills them. The producer moves the tail with plain stores, and
+ * consumers claim a slot by moving the head with a compare-and-swap. Whoever
+ * reads the last slot of a block frees it, unless a slower consumer is still
+ * reading another slot of it, in which case that one frees it when done.
+ * Indices count slots from the start, with one index per block left unused,
+ * and the lowest bit of the head tells whether the tail is in a later block.
+ * The layout follows the segmented queue of crossbeam.
+ * Only one thread may push at a time, see SpscRing.
+ */
+pub(crate) struct SpmcList<T> {
+    head: Position<T>,
+    tail: Position<T>,
+    pushing: AtomicBool,
+}
+
+//the state bits of a slot
+const WRITE: usize = 1;
+const READ: usize = 2;
+const DESTROY: usize = 4;
+
+//indices per block, the last one has no slot
+const LAP: usize = 32;
+const BLOCK_CAP: usize = LAP - 1;
+const SHIFT: usize = 1;
+const HAS_NEXT: usize = 1;
+
+struct Slot<T> {
+    value: UnsafeCell<MaybeUninit<T>>,
+    state: AtomicUsize,
+}
+
+struct Block<T> {
+    next: AtomicPtr<Block<T>>,
+    slots: [Slot<T>; BLOCK_CAP],
+}
+
+struct Position<T> {
+    index: AtomicUsize,
+    block: AtomicPtr<Block<T>>,
+}
+
+unsafe impl<T: Send> Send for SpmcList<T> {}
+unsafe impl<T: Send> Sync for SpmcList<T> {}
+
+//Waits for another thread to finish a step, giving up the time slice
+//after a while in case that thread got preempted
+fn snooze(attempts: &mut u32) {
+    *attempts += 1;
+    if *attempts <= 16 {
+        spin_loop();
+    } else {
+        std::thread::yield_now();
+    }
+}
+
+impl<T> Slot<T> {
+    //The producer moves the tail before it writes the slot
+    fn wait_write(&self) {
+        let mut attempts = 0;
+        while self.state.load(Ordering::Acquire) & WRITE == 0 {
+            snooze(&mut attempts);
+        }
+    }
+}
+
+impl<T> Block<T> {
+    fn allocate() -> *mut Block<T> {
+        Box::into_raw(Box::new(Block {
+            next: AtomicPtr::new(ptr::null_mut()),
+            slots: std::array::from_fn(|_| Slot {
+                value: UnsafeCell::new(MaybeUninit::uninit()),
+                state: AtomicUsize::new(0),
+            }),
+        }))
+    }
+
+    //The producer links the next block right after it moved the tail to it
+    fn wait_next(&self) -> *mut Block<T> {
+        let mut attempts = 0;
+        loop {
+            let next = self.next.load(Ordering::Acquire);
+            if !next.is_null() {
+                return next;
+            }
+            snooze(&mut attempts);
+        }
+    }
+
+    //Frees the block, unless a consumer is still reading one of its slots
+    //from start on. That consumer calls it again once it is done.
+    unsafe fn destroy(this: *mut Block<T>, start: usize) {
+        let block = &*this;
+        for slot in &block.slots[start..BLOCK_CAP - 1] {
+            if slot.state.load(Ordering::Acquire) & READ == 0
+                && slot.state.fetch_or(DESTROY, Ordering::AcqRel) & READ == 0
+            {
+                return;
+            }
+        }
+        drop(Box::from_raw(this));
+    }
+}
+
+impl<T> SpmcList<T> {
+    pub(crate) fn new() -> SpmcList<T> {
+        let block = Block::allocate();
+        SpmcList {
+            head: Position {
+                index: AtomicUsize::new(0),
+                block: AtomicPtr::new(block),
+            },
+            tail: Position {
+                index: AtomicUsize::new(0),
+                block: AtomicPtr::new(block),
+            },
+            pushing: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn push(&self, item: T) {
+        //The claim only covers moving the tail: the slot is this push's
+        //alone once the tail moved past it. A consumer that puts back a
+        //Stop it took from this slot can only do so once it was written.
+        let (block, offset) = {
+            let _claim = Claim::new(&self.pushing, "push");
+            let tail = self.tail.index.load(Ordering::Relaxed);
+            let block = self.tail.block.load(Ordering::Relaxed);
+            let offset = (tail >> SHIFT) % LAP;
+            let new_tail = tail + (1 << SHIFT);
+            if offset + 1 == BLOCK_CAP {
+                let next = Block::allocate();
+                self.tail.block.store(next, Ordering::Release);
+                //past the index without a slot
+                self.tail
+                    .index
+                    .store(new_tail.wrapping_add(1 << SHIFT), Ordering::SeqCst);
+                unsafe { (*block).next.store(next, Ordering::Release) };
+            } else {
+                self.tail.index.store(new_tail, Ordering::SeqCst);
+            }
+            (block, offset)
+        };
+        unsafe {
+            let slot = &(*block).slots[offset];
+            (*slot.value.get()).write(item);
+            slot.state.fetch_or(WRITE, Ordering::Release);
+        }
+    }
+
+    pub(crate) fn pop(&self) -> Option<T> {
+        let mut head = self.head.index.load(Ordering::Acquire);
+        let mut block = self.head.block.load(Ordering::Acquire);
+        let mut attempts = 0;
+        loop {
+            let offset = (head >> SHIFT) % LAP;
+            //another consumer is moving the head to the next block
+            if offset == BLOCK_CAP {
+                snooze(&mut attempts);
+                head = self.head.index.load(Ordering::Acquire);
+                block = self.head.block.load(Ordering::Acquire);
+                continue;
+            }
+            let mut new_head = head + (1 << SHIFT);
+            if new_head & HAS_NEXT == 0 {
+                fence(Ordering::SeqCst);
+                let tail = self.tail.index.load(Ordering::Relaxed);
+                if head >> SHIFT == tail >> SHIFT {
+                    return None;
+                }
+                if (head >> SHIFT) / LAP != (tail >> SHIFT) / LAP {
+                    new_head |= HAS_NEXT;
+                }
+            }
+            match self.head.index.compare_exchange_weak(
+                head,
+                new_head,
+                Ordering::SeqCst,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => unsafe {
+                    if offset + 1 == BLOCK_CAP {
+                        let next = (*block).wait_next();
+                        let mut next_index = (new_head & !HAS_NEXT).wrapping_add(1 << SHIFT);
+                        if !(*next).next.load(Ordering::Relaxed).is_null() {
+                            next_index |= HAS_NEXT;
+                        }
+                        self.head.block.store(next, Ordering::Release);
+                        self.head.index.store(next_index, Ordering::Release);
+                    }
+                    let slot = &(*block).slots[offset];
+                    slot.wait_write();
+                    let item = (*slot.value.get()).assume_init_read();
+                    if offset + 1 == BLOCK_CAP {
+                        Block::destroy(block, 0);
+                    } else if slot.state.fetch_or(READ, Ordering::AcqRel) & DESTROY != 0 {
+                        Block::destroy(block, offset + 1);
+                    }
+                    return Some(item);
+                },
+                Err(current) => {
+                    head = current;
+                    block = self.head.block.load(Ordering::Acquire);
+                    spin_loop();
+                }
+            }
+        }
+    }
+}
+
+impl<T> Drop for SpmcList<T> {
+    fn drop(&mut self) {
+        let mut head = *self.head.index.get_mut() & !HAS_NEXT;
+        let tail = *self.tail.index.get_mut() & !HAS_NEXT;
+        let mut block = *self.head.block.get_mut();
+        unsafe {
+            while head != tail {
+                let offset = (head >> SHIFT) % LAP;
+                if offset < BLOCK_CAP {
+                    (*(*block).slots[offset].value.get()).assume_init_drop();
+                } else {
+                    let next = *(*block).next.get_mut();
+                    drop(Box::from_raw(block));
+                    block = next;
+                }
+                head = head.wrapping_add(1 << SHIFT);
+            }
+            drop(Box::from_raw(block));
+        }
+    }
+}
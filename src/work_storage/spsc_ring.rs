@@ -0,0 +1,133 @@
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+/*
+ * Wait-free single-producer single-consumer queue, for links between two
+ * stages with one replica each. Items go in fixed-size rings that are chained
+ * as the producer fills them, so the queue is unbounded and neither side ever
+ * waits for the other. The producer publishes how many slots of its ring it
+ * wrote, the consumer frees a ring once it read all of it.
+ * Only one thread may push and only one may pop at a time. Each side claims its
+ * end while it uses it, and panics if it finds it taken, so a link that was
+ * given the wrong number of producers fails instead of losing items.
+ */
+pub(crate) struct SpscRing<T> {
+    //only touched by the producer
+    tail: UnsafeCell<Cursor<T>>,
+    //only touched by the consumer
+    head: UnsafeCell<Cursor<T>>,
+    pushing: AtomicBool,
+    popping: AtomicBool,
+}
+
+const RING_SIZE: usize = 256;
+
+struct Ring<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    written: AtomicUsize,
+    next: AtomicPtr<Ring<T>>,
+}
+
+struct Cursor<T> {
+    ring: *mut Ring<T>,
+    index: usize,
+}
+
+unsafe impl<T: Send> Send for SpscRing<T> {}
+unsafe impl<T: Send> Sync for SpscRing<T> {}
+
+impl<T> Ring<T> {
+    fn allocate() -> *mut Ring<T> {
+        Box::into_raw(Box::new(Ring {
+            slots: (0..RING_SIZE)
+                .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+                .collect(),
+            written: AtomicUsize::new(0),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }))
+    }
+}
+
+impl<T> SpscRing<T> {
+    pub(crate) fn new() -> SpscRing<T> {
+        let ring = Ring::allocate();
+        SpscRing {
+            tail: UnsafeCell::new(Cursor { ring, index: 0 }),
+            head: UnsafeCell::new(Cursor { ring, index: 0 }),
+            pushing: AtomicBool::new(false),
+            popping: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn push(&self, item: T) {
+        let _claim = Claim::new(&self.pushing, "push");
+        unsafe {
+            let tail = &mut *self.tail.get();
+            if tail.index == RING_SIZE {
+                let next = Ring::allocate();
+                (*tail.ring).next.store(next, Ordering::Release);
+                tail.ring = next;
+                tail.index = 0;
+            }
+            let ring = &*tail.ring;
+            (*ring.slots[tail.index].get()).write(item);
+            tail.index += 1;
+            ring.written.store(tail.index, Ordering::Release);
+        }
+    }
+
+    pub(crate) fn pop(&self) -> Option<T> {
+        let _claim = Claim::new(&self.popping, "pop");
+        unsafe {
+            let head = &mut *self.head.get();
+            if head.index == RING_SIZE {
+                let next = (*head.ring).next.load(Ordering::Acquire);
+                if next.is_null() {
+                    return None;
+                }
+                //the producer moved on to the next ring before linking it
+                drop(Box::from_raw(head.ring));
+                head.ring = next;
+                head.index = 0;
+            }
+            let ring = &*head.ring;
+            if head.index == ring.written.load(Ordering::Acquire) {
+                return None;
+            }
+            let item = (*ring.slots[head.index].get()).assume_init_read();
+            head.index += 1;
+            Some(item)
+        }
+    }
+}
+
+impl<T> Drop for SpscRing<T> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+        unsafe {
+            drop(Box::from_raw((*self.head.get()).ring));
+        }
+    }
+}
+
+//One side of a single-producer or single-consumer queue, held while a
+//thread uses it. Also orders whatever the previous holder did before
+//whatever the next one does.
+pub(crate) struct Claim<'a>(&'a AtomicBool);
+
+impl<'a> Claim<'a> {
+    pub(crate) fn new(side: &'a AtomicBool, operation: &str) -> Claim<'a> {
+        if side.swap(true, Ordering::Acquire) {
+            panic!("two threads tried to {operation} at once on a single-threaded link");
+        }
+        Claim(side)
+    }
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
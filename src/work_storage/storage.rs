@@ -0,0 +1,141 @@
+use crate::work_storage::*;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/*
+ * Where an unordered stage keeps the items waiting for its replicas. The
+ * crate implements it with BlockingQueue and LockFreeQueue, and a stage can be
+ * given any other implementation, such as a priority queue or a queue that
+ * spills to disk, through BlockConfig::with_storage.
+ * The storage is closed by enqueueing a Stop token. A Stop has to come out
+ * after every item that went in before it, and when replicas share the
+ * storage, each one that takes it puts it back for the next one, so it must
+ * not be dropped either.
+ * Items posted to the first stage get their timestamp from the storage: the
+ * number of items that went in before them, Stop tokens included, given
+ * with TimestampedWorkItem::posted so they also get their ingress time.
+ * Items coming from another stage already have both and keep them.
+ * Only the required methods have to be written for an unbounded storage.
+ * Bounded storages also override the try_ and _timeout variants, which by
+ * default wait for as long as enqueue does.
+ */
+pub trait WorkStorage<T> {
+    //Items in the storage, Stop tokens included
+    fn len(&self) -> usize;
+
+    //Stamps the item and queues it, waiting for room if needed
+    fn enqueue(&self, item: WorkItem<T>) -> u64;
+
+    fn enqueue_timestamped(&self, item: TimestampedWorkItem<T>);
+
+    //Blocks until there is an item
+    fn wait_and_dequeue(&self) -> TimestampedWorkItem<T>;
+
+    fn try_dequeue(&self) -> Option<TimestampedWorkItem<T>>;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn capacity(&self) -> Option<usize> {
+        None
+    }
+
+    fn try_enqueue(&self, item: WorkItem<T>) -> Result<u64, WorkItem<T>> {
+        Ok(self.enqueue(item))
+    }
+
+    fn enqueue_timeout(&self, item: WorkItem<T>, _timeout: Duration) -> Result<u64, WorkItem<T>> {
+        Ok(self.enqueue(item))
+    }
+
+    fn try_enqueue_timestamped(
+        &self,
+        item: TimestampedWorkItem<T>,
+    ) -> Result<(), TimestampedWorkItem<T>> {
+        self.enqueue_timestamped(item);
+        Ok(())
+    }
+
+    fn enqueue_timestamped_timeout(
+        &self,
+        item: TimestampedWorkItem<T>,
+        _timeout: Duration,
+    ) -> Result<(), TimestampedWorkItem<T>> {
+        self.enqueue_timestamped(item);
+        Ok(())
+    }
+
+    //Takes the next item unless it is a Stop. A Stop that was taken by
+    //mistake is put back, which is fine since nothing comes after it.
+    fn steal(&self) -> Option<TimestampedWorkItem<T>> {
+        match self.try_dequeue()? {
+            TimestampedWorkItem(WorkItem::Stop, order, _) => {
+                self.enqueue_timestamped(TimestampedWorkItem::stop(order));
+                None
+            }
+            item => Some(item),
+        }
+    }
+
+    //For async stages. The default asks to be polled again right away, so
+    //storages used by async stages should wake the task when an item
+    //comes in instead.
+    fn poll_dequeue(&self, cx: &mut Context<'_>) -> Poll<TimestampedWorkItem<T>> {
+        match self.try_dequeue() {
+            Some(item) => Poll::Ready(item),
+            None => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    //Ready once there is room for one more item. Timed posts to farms
+    //with a storage per replica also wait on it.
+    fn poll_space(&self, _cx: &mut Context<'_>) -> Poll<()> {
+        Poll::Ready(())
+    }
+}
+
+/*
+ * Where an ordered sequential stage keeps the items that arrived ahead of
+ * their turn. The stage asks for the items by order, one after the other,
+ * so the storage only has to find the item with the given one. Orders
+ * compare with padding, see Order, so they make good map keys.
+ * BlockingOrderedSet is the default, BlockingOrderedRing the bounded one.
+ * Storages must not make producers wait for the stage to catch up: the
+ * replica posting an item ahead of its turn can be the one that holds the
+ * item the stage waits for.
+ */
+pub trait OrderedWorkStorage<T> {
+    //Items waiting for their turn, the Stop token included
+    fn len(&self) -> usize;
+
+    fn enqueue(&self, item: TimestampedWorkItem<T>);
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn try_enqueue(&self, item: TimestampedWorkItem<T>) -> Result<(), TimestampedWorkItem<T>> {
+        self.enqueue(item);
+        Ok(())
+    }
+
+    fn enqueue_timeout(
+        &self,
+        item: TimestampedWorkItem<T>,
+        _timeout: Duration,
+    ) -> Result<(), TimestampedWorkItem<T>> {
+        self.enqueue(item);
+        Ok(())
+    }
+
+    //Blocks until the item with the given order is in, and takes it
+    fn wait_and_remove(&self, order: &Order) -> TimestampedWorkItem<T>;
+}
+
+pub type SharedStorage<T> = Arc<dyn WorkStorage<T> + Send + Sync>;
+pub type SharedOrderedStorage<T> = Arc<dyn OrderedWorkStorage<T> + Send + Sync>;
@@ -1,7 +1,189 @@
+use std::cmp;
+use std::error::Error;
+use std::fmt;
+use std::time::Instant;
+
 pub enum WorkItem<T> {
     Value(T),
     Dropped,
+    //A fallible stage returned an error for this item
+    Failed(ItemFailure),
     Stop,
 }
 
-pub struct TimestampedWorkItem<T>(pub WorkItem<T>, pub u64);
+/*
+ * Where an item stands in the stream. Posted items are numbered from 0.
+ * A flat-map stage that emits many values for one input gives them a level
+ * of their own below the order of the input: their index among those
+ * values, and whether they are the last one. Everything else a stage emits
+ * keeps the order of its input, which counts as the last of its kind.
+ * Missing levels compare as index 0, so an ordered stage always knows the
+ * order of the item it waits for next, whatever the stages before it did.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Order {
+    pub item: u64,
+    //boxed to keep items small, most orders have no levels
+    sub: Box<[SubOrder]>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct SubOrder {
+    index: u64,
+    last: bool,
+}
+
+impl Order {
+    pub fn new(item: u64) -> Order {
+        Order {
+            item,
+            sub: Box::new([]),
+        }
+    }
+
+    //The order of one of the values a stage emitted for this item
+    pub fn child(&self, index: usize, last: bool) -> Order {
+        let mut sub = self.sub.to_vec();
+        sub.push(SubOrder {
+            index: index as u64,
+            last,
+        });
+        Order {
+            item: self.item,
+            sub: sub.into_boxed_slice(),
+        }
+    }
+
+    //The order of the item that comes right after this one
+    pub fn next(&self) -> Order {
+        let mut sub = self.sub.to_vec();
+        while let Some(level) = sub.pop() {
+            if !level.last {
+                sub.push(SubOrder {
+                    index: level.index + 1,
+                    last: false,
+                });
+                return Order {
+                    item: self.item,
+                    sub: sub.into_boxed_slice(),
+                };
+            }
+        }
+        Order::new(self.item + 1)
+    }
+
+    fn index(&self, level: usize) -> u64 {
+        self.sub.get(level).map_or(0, |sub| sub.index)
+    }
+}
+
+impl From<u64> for Order {
+    fn from(item: u64) -> Order {
+        Order::new(item)
+    }
+}
+
+impl PartialEq for Order {
+    fn eq(&self, other: &Order) -> bool {
+        self.cmp(other) == cmp::Ordering::Equal
+    }
+}
+
+impl Eq for Order {}
+
+impl PartialOrd for Order {
+    fn partial_cmp(&self, other: &Order) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Order {
+    fn cmp(&self, other: &Order) -> cmp::Ordering {
+        let depth = self.sub.len().max(other.sub.len());
+        self.item.cmp(&other.item).then_with(|| {
+            (0..depth)
+                .map(|level| self.index(level).cmp(&other.index(level)))
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or(cmp::Ordering::Equal)
+        })
+    }
+}
+
+//The item, then the index at every level: 5, or 5.2 for the third value
+//a flat-map stage emitted for item 5
+impl fmt::Display for Order {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.item)?;
+        for sub in &self.sub {
+            write!(f, ".{}", sub.index)?;
+        }
+        Ok(())
+    }
+}
+
+//An item with its sequence number and, for values posted to the pipeline,
+//the moment the first stage queued it. Outputs of a stage keep the ingress
+//time of the item they came from, so the sink knows how long it took.
+pub struct TimestampedWorkItem<T>(pub WorkItem<T>, pub Order, pub Option<Instant>);
+
+impl<T> TimestampedWorkItem<T> {
+    //Stamps an item posted to the first stage. Storages use it in enqueue,
+    //where items get their sequence number.
+    pub fn posted(item: WorkItem<T>, order: u64) -> TimestampedWorkItem<T> {
+        let ingress = match item {
+            WorkItem::Value(_) => Some(Instant::now()),
+            _ => None,
+        };
+        TimestampedWorkItem(item, Order::new(order), ingress)
+    }
+
+    //Like posted, but only stamps the item when asked to. Taking the time
+    //is not free, so the crate only does it when latency is measured.
+    pub(crate) fn numbered(item: WorkItem<T>, order: u64, stamp: bool) -> TimestampedWorkItem<T> {
+        match stamp {
+            true => TimestampedWorkItem::posted(item, order),
+            false => TimestampedWorkItem(item, Order::new(order), None),
+        }
+    }
+
+    pub fn stop(order: Order) -> TimestampedWorkItem<T> {
+        TimestampedWorkItem(WorkItem::Stop, order, None)
+    }
+}
+
+//An error returned by a fallible stage, together with the stage that
+//produced it (position in the pipeline, starting at 0, and its name if it
+//has one) and the order of the item that failed
+pub struct ItemFailure {
+    pub stage: usize,
+    pub stage_name: Option<String>,
+    pub order: Order,
+    pub error: Box<dyn Error + Send + Sync>,
+}
+
+impl ItemFailure {
+    pub fn downcast_ref<E: Error + 'static>(&self) -> Option<&E> {
+        self.error.downcast_ref::<E>()
+    }
+}
+
+impl fmt::Debug for ItemFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ItemFailure")
+            .field("stage", &self.stage)
+            .field("stage_name", &self.stage_name)
+            .field("order", &self.order)
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+impl fmt::Display for ItemFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "stage {}", self.stage)?;
+        if let Some(name) = &self.stage_name {
+            write!(f, " \"{}\"", name)?;
+        }
+        write!(f, " failed on item {}: {}", self.order, self.error)
+    }
+}
@@ -0,0 +1,272 @@
+use crate::work_storage::*;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+//Which queue a stage takes its items from. Lock-free queues do better
+//when the items are small and many threads post and take them at once.
+//Auto uses lock-free queues on links with a single replica on one side,
+//picked for how many replicas are on the other, and the blocking queue
+//between two farms and for the first stage, which any thread can post to.
+//LockFree also gives links between two farms a lock-free MPMC queue.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QueueKind {
+    #[default]
+    Auto,
+    Blocking,
+    LockFree,
+}
+
+//Internals: the input queue of a stage, whichever kind it is. The queues
+//of the crate are called directly, other storages through the trait.
+pub enum WorkQueue<T> {
+    Blocking(Arc<BlockingQueue<T>>),
+    LockFree(Arc<LockFreeQueue<T>>),
+    Custom(SharedStorage<T>),
+}
+
+macro_rules! dispatch {
+    ($queue:expr, $inner:ident => $call:expr) => {
+        match $queue {
+            WorkQueue::Blocking($inner) => $call,
+            WorkQueue::LockFree($inner) => $call,
+            WorkQueue::Custom($inner) => $call,
+        }
+    };
+}
+
+impl<T> Clone for WorkQueue<T> {
+    fn clone(&self) -> WorkQueue<T> {
+        match self {
+            WorkQueue::Blocking(queue) => WorkQueue::Blocking(queue.clone()),
+            WorkQueue::LockFree(queue) => WorkQueue::LockFree(queue.clone()),
+            WorkQueue::Custom(queue) => WorkQueue::Custom(queue.clone()),
+        }
+    }
+}
+
+impl<T> WorkQueue<T> {
+    pub fn new(kind: QueueKind, capacity: Option<usize>) -> WorkQueue<T> {
+        match kind {
+            QueueKind::Auto | QueueKind::Blocking => {
+                WorkQueue::Blocking(BlockingQueue::with_capacity(capacity))
+            }
+            QueueKind::LockFree => WorkQueue::LockFree(LockFreeQueue::with_capacity(capacity)),
+        }
+    }
+
+    //Queue for a link between stages. Producers is None when any thread
+    //can push.
+    pub fn for_link(
+        kind: QueueKind,
+        producers: Option<usize>,
+        consumers: usize,
+        capacity: Option<usize>,
+    ) -> WorkQueue<T> {
+        match (kind, producers) {
+            (QueueKind::Blocking, _) | (_, None) => WorkQueue::new(kind, capacity),
+            (QueueKind::Auto, Some(producers)) if producers > 1 && consumers > 1 => {
+                WorkQueue::new(kind, capacity)
+            }
+            (_, Some(producers)) => {
+                WorkQueue::LockFree(LockFreeQueue::for_link(producers, consumers, capacity))
+            }
+        }
+    }
+
+    //Custom storages stamp every value they number with posted
+    pub fn stamp_ingress(&self) {
+        match self {
+            WorkQueue::Blocking(queue) => queue.stamp_ingress(),
+            WorkQueue::LockFree(queue) => queue.stamp_ingress(),
+            WorkQueue::Custom(_) => {}
+        }
+    }
+
+    pub fn capacity(&self) -> Option<usize> {
+        dispatch!(self, queue => queue.capacity())
+    }
+
+    //What kind of queue it is, for Pipeline::describe
+    pub fn describe(&self) -> String {
+        match self {
+            WorkQueue::Blocking(_) => "blocking queue".to_string(),
+            WorkQueue::LockFree(queue) => format!("lock-free {} queue", queue.kind()),
+            WorkQueue::Custom(_) => "custom storage".to_string(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        dispatch!(self, queue => queue.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        dispatch!(self, queue => queue.is_empty())
+    }
+
+    pub fn enqueue(&self, item: WorkItem<T>) -> u64 {
+        dispatch!(self, queue => queue.enqueue(item))
+    }
+
+    pub fn try_enqueue(&self, item: WorkItem<T>) -> Result<u64, WorkItem<T>> {
+        dispatch!(self, queue => queue.try_enqueue(item))
+    }
+
+    pub fn enqueue_timeout(
+        &self,
+        item: WorkItem<T>,
+        timeout: Duration,
+    ) -> Result<u64, WorkItem<T>> {
+        dispatch!(self, queue => queue.enqueue_timeout(item, timeout))
+    }
+
+    pub fn enqueue_timestamped(&self, item: TimestampedWorkItem<T>) {
+        dispatch!(self, queue => queue.enqueue_timestamped(item))
+    }
+
+    pub fn try_enqueue_timestamped(
+        &self,
+        item: TimestampedWorkItem<T>,
+    ) -> Result<(), TimestampedWorkItem<T>> {
+        dispatch!(self, queue => queue.try_enqueue_timestamped(item))
+    }
+
+    pub fn enqueue_timestamped_timeout(
+        &self,
+        item: TimestampedWorkItem<T>,
+        timeout: Duration,
+    ) -> Result<(), TimestampedWorkItem<T>> {
+        dispatch!(self, queue => queue.enqueue_timestamped_timeout(item, timeout))
+    }
+
+    pub fn wait_and_dequeue(&self) -> TimestampedWorkItem<T> {
+        dispatch!(self, queue => queue.wait_and_dequeue())
+    }
+
+    pub fn try_dequeue(&self) -> Option<TimestampedWorkItem<T>> {
+        dispatch!(self, queue => queue.try_dequeue())
+    }
+
+    //Blocks until there is room for a value, without queueing one.
+    //Returns false if the deadline passed first. Custom storages are asked
+    //through poll_space.
+    pub fn wait_for_space(&self, deadline: Instant) -> bool {
+        match self {
+            WorkQueue::Blocking(queue) => queue.wait_for_room(deadline),
+            WorkQueue::LockFree(queue) => queue.wait_for_room(deadline),
+            WorkQueue::Custom(queue) => {
+                let waker = Waker::from(Arc::new(Unparker(thread::current())));
+                match queue.poll_space(&mut Context::from_waker(&waker)) {
+                    //someone else can still take the room first
+                    Poll::Ready(()) => thread::yield_now(),
+                    Poll::Pending => {
+                        thread::park_timeout(deadline.saturating_duration_since(Instant::now()))
+                    }
+                }
+                Instant::now() < deadline
+            }
+        }
+    }
+
+    pub fn steal(&self) -> Option<TimestampedWorkItem<T>> {
+        dispatch!(self, queue => queue.steal())
+    }
+
+    pub fn poll_dequeue(&self, cx: &mut Context<'_>) -> Poll<TimestampedWorkItem<T>> {
+        dispatch!(self, queue => queue.poll_dequeue(cx))
+    }
+
+    pub fn poll_space(&self, cx: &mut Context<'_>) -> Poll<()> {
+        dispatch!(self, queue => queue.poll_space(cx))
+    }
+}
+
+//Wakes up a thread waiting on a custom storage
+struct Unparker(Thread);
+
+impl Wake for Unparker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+//Internals: the input of an ordered sequential stage
+pub enum OrderedWorkQueue<T> {
+    Blocking(Arc<BlockingOrderedSet<T>>),
+    Ring(Arc<BlockingOrderedRing<T>>),
+    Custom(SharedOrderedStorage<T>),
+}
+
+macro_rules! dispatch_ordered {
+    ($set:expr, $inner:ident => $call:expr) => {
+        match $set {
+            OrderedWorkQueue::Blocking($inner) => $call,
+            OrderedWorkQueue::Ring($inner) => $call,
+            OrderedWorkQueue::Custom($inner) => $call,
+        }
+    };
+}
+
+impl<T> Clone for OrderedWorkQueue<T> {
+    fn clone(&self) -> OrderedWorkQueue<T> {
+        match self {
+            OrderedWorkQueue::Blocking(set) => OrderedWorkQueue::Blocking(set.clone()),
+            OrderedWorkQueue::Ring(set) => OrderedWorkQueue::Ring(set.clone()),
+            OrderedWorkQueue::Custom(set) => OrderedWorkQueue::Custom(set.clone()),
+        }
+    }
+}
+
+impl<T> OrderedWorkQueue<T> {
+    //A ring when the stage has a reorder window, a set otherwise
+    pub fn new(window: Option<usize>) -> OrderedWorkQueue<T> {
+        match window {
+            Some(window) => OrderedWorkQueue::Ring(BlockingOrderedRing::new(window)),
+            None => OrderedWorkQueue::Blocking(BlockingOrderedSet::new()),
+        }
+    }
+
+    pub fn window(&self) -> Option<usize> {
+        match self {
+            OrderedWorkQueue::Ring(ring) => Some(ring.window()),
+            _ => None,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            OrderedWorkQueue::Blocking(_) => "ordered set".to_string(),
+            OrderedWorkQueue::Ring(ring) => format!("ordered ring, window {}", ring.window()),
+            OrderedWorkQueue::Custom(_) => "custom ordered storage".to_string(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        dispatch_ordered!(self, set => set.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        dispatch_ordered!(self, set => set.is_empty())
+    }
+
+    pub fn enqueue(&self, item: TimestampedWorkItem<T>) {
+        dispatch_ordered!(self, set => set.enqueue(item))
+    }
+
+    pub fn try_enqueue(&self, item: TimestampedWorkItem<T>) -> Result<(), TimestampedWorkItem<T>> {
+        dispatch_ordered!(self, set => set.try_enqueue(item))
+    }
+
+    pub fn enqueue_timeout(
+        &self,
+        item: TimestampedWorkItem<T>,
+        timeout: Duration,
+    ) -> Result<(), TimestampedWorkItem<T>> {
+        dispatch_ordered!(self, set => set.enqueue_timeout(item, timeout))
+    }
+
+    pub fn wait_and_remove(&self, order: &Order) -> TimestampedWorkItem<T> {
+        dispatch_ordered!(self, set => set.wait_and_remove(order))
+    }
+}
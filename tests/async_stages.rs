@@ -0,0 +1,145 @@
+#![cfg(feature = "tokio")]
+
+use rust_spp::*;
+use std::time::Duration;
+
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_time()
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn async_replicas_run_as_tasks_on_the_runtime() {
+    let runtime = runtime();
+    let pipeline = pipeline![
+        on_runtime!(
+            parallel_ordered!(
+                |item: u64| async move {
+                    tokio::time::sleep(Duration::from_millis(item % 5)).await;
+                    Some(item * 2)
+                },
+                50
+            ),
+            runtime.handle().clone()
+        ),
+        parallel!(|item: u64| Some(item + 1), 2),
+        collect_ordered!()
+    ];
+    for item in 0..300u64 {
+        pipeline.post(item).unwrap();
+    }
+    let collected = pipeline.collect().unwrap();
+    assert_eq!(
+        collected,
+        (0..300).map(|item| item * 2 + 1).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn async_stage_can_feed_a_bounded_stage() {
+    let runtime = runtime();
+    let pipeline = pipeline![
+        on_runtime!(
+            parallel!(|item: u64| async move { Some(item) }, 8),
+            runtime.handle().clone()
+        ),
+        bounded!(
+            sequential!(|item: u64| {
+                std::thread::sleep(Duration::from_micros(50));
+                Some(item)
+            }),
+            2
+        ),
+        collect!()
+    ];
+    for item in 0..200u64 {
+        pipeline.post(item).unwrap();
+    }
+    let mut collected = pipeline.collect().unwrap();
+    collected.sort_unstable();
+    assert_eq!(collected, (0..200).collect::<Vec<_>>());
+}
+
+#[test]
+fn async_stage_waits_for_a_full_async_stage_on_one_worker() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .build()
+        .unwrap();
+    let pipeline = pipeline![
+        on_runtime!(
+            parallel!(|item: u64| async move { Some(item) }, 8),
+            runtime.handle().clone()
+        ),
+        on_runtime!(
+            bounded!(
+                sequential_ordered!(|item: u64| async move {
+                    tokio::task::yield_now().await;
+                    Some(item * 2)
+                }),
+                1
+            ),
+            runtime.handle().clone()
+        ),
+        collect_ordered!()
+    ];
+    for item in 0..200u64 {
+        pipeline.post(item).unwrap();
+    }
+    let collected = pipeline.collect().unwrap();
+    assert_eq!(collected, (0..200).map(|item| item * 2).collect::<Vec<_>>());
+}
+
+#[test]
+#[should_panic(expected = "windowed! and stored_ordered! do not apply to async stages")]
+fn reorder_windows_are_refused() {
+    let runtime = runtime();
+    let _pipeline = pipeline![
+        on_runtime!(
+            windowed!(
+                sequential_ordered!(|item: u64| async move { Some(item) }),
+                8
+            ),
+            runtime.handle().clone()
+        ),
+        collect!()
+    ];
+}
+
+#[test]
+fn pipeline_can_be_driven_from_async_code() {
+    let runtime = runtime();
+    let handle = runtime.handle().clone();
+    let collected = runtime.block_on(async move {
+        let pipeline = pipeline![
+            on_runtime!(
+                sequential_ordered!(|item: u64| async move { Some(item) }),
+                handle
+            ),
+            collect_ordered!()
+        ];
+        for item in 0..100 {
+            pipeline.post_async(item).await.unwrap();
+        }
+        pipeline.collect_async().await.unwrap()
+    });
+    assert_eq!(collected, (0..100).collect::<Vec<_>>());
+}
+
+#[test]
+#[should_panic(expected = "async stages need a multi-threaded tokio runtime")]
+fn current_thread_runtimes_are_refused() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let _pipeline = pipeline![
+        on_runtime!(
+            parallel!(|item: u64| async move { Some(item) }, 4),
+            runtime.handle().clone()
+        ),
+        collect!()
+    ];
+}
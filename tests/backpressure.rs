@@ -0,0 +1,101 @@
+mod common;
+
+use common::Gate;
+use rust_spp::*;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn try_post_hands_the_item_back_when_the_first_stage_is_full() {
+    let gate = Gate::new();
+    let stage_gate = gate.clone();
+    let pipeline = pipeline![
+        bounded!(sequential!(stage_gate.stage::<u32>()), 2),
+        collect!()
+    ];
+
+    pipeline.post(0).unwrap();
+    gate.wait_entered(1);
+    pipeline.try_post(1).unwrap();
+    pipeline.try_post(2).unwrap();
+
+    match pipeline.try_post(3) {
+        Err(TryPostError::Full(item)) => assert_eq!(item, 3),
+        other => panic!("expected a full queue, got {:?}", other),
+    }
+    let refused = pipeline.post_timeout(4, Duration::from_millis(20));
+    assert!(matches!(refused, Err(PostTimeoutError::Timeout(4))));
+
+    gate.open();
+    assert_eq!(pipeline.collect().unwrap(), vec![0, 1, 2]);
+}
+
+#[test]
+fn post_blocks_until_the_first_stage_has_room() {
+    let gate = Gate::new();
+    let stage_gate = gate.clone();
+    let pipeline = pipeline![
+        bounded!(sequential!(stage_gate.stage::<u32>()), 1),
+        collect!()
+    ];
+    pipeline.post(0).unwrap();
+    gate.wait_entered(1);
+    pipeline.post(1).unwrap();
+
+    let opener = {
+        let gate = gate.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            gate.open();
+        })
+    };
+    let started = Instant::now();
+    pipeline.post(2).unwrap();
+    let waited = started.elapsed();
+    opener.join().unwrap();
+
+    assert!(waited >= Duration::from_millis(40), "waited {:?}", waited);
+    assert_eq!(pipeline.collect().unwrap(), vec![0, 1, 2]);
+}
+
+#[test]
+fn capacity_applies_to_every_stage_that_sets_none() {
+    let gate = Gate::new();
+    let stage_gate = gate.clone();
+    let pipeline = pipeline![
+        capacity: 2;
+        sequential!(stage_gate.stage::<u32>()),
+        parallel!(|item: u32| Some(item * 2), 4),
+        collect!()
+    ];
+    pipeline.post(0).unwrap();
+    gate.wait_entered(1);
+    pipeline.try_post(1).unwrap();
+    pipeline.try_post(2).unwrap();
+    assert!(matches!(pipeline.try_post(3), Err(TryPostError::Full(3))));
+
+    gate.open();
+    let mut collected = pipeline.collect().unwrap();
+    collected.sort_unstable();
+    assert_eq!(collected, vec![0, 2, 4]);
+}
+
+#[test]
+fn bounded_stages_keep_their_own_capacity() {
+    let gate = Gate::new();
+    let stage_gate = gate.clone();
+    let pipeline = pipeline![
+        capacity: 1;
+        bounded!(sequential!(stage_gate.stage::<u32>()), 3),
+        collect!()
+    ];
+    pipeline.post(0).unwrap();
+    gate.wait_entered(1);
+    for item in 1..4 {
+        pipeline.try_post(item).unwrap();
+    }
+    assert!(matches!(pipeline.try_post(4), Err(TryPostError::Full(4))));
+
+    gate.open();
+    assert_eq!(pipeline.collect().unwrap(), vec![0, 1, 2, 3]);
+}
@@ -0,0 +1,218 @@
+mod common;
+
+use common::{jitter, Gate};
+use rust_spp::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
+const POLICIES: [LoadBalancing; 4] = [
+    LoadBalancing::Shared,
+    LoadBalancing::RoundRobin,
+    LoadBalancing::LeastLoaded,
+    LoadBalancing::WorkStealing,
+];
+
+#[test]
+fn every_policy_processes_every_item() {
+    for policy in POLICIES {
+        let pipeline = pipeline![
+            balanced!(
+                parallel_ordered!(
+                    |item: u64| {
+                        jitter(item);
+                        Some(item * 2)
+                    },
+                    4
+                ),
+                policy
+            ),
+            balanced!(parallel!(|item: u64| Some(item + 1), 3), policy),
+            collect_ordered!()
+        ];
+        let collected = pipeline.run_iter(0..300u64).unwrap();
+        assert_eq!(
+            collected,
+            (0..300).map(|item| item * 2 + 1).collect::<Vec<_>>(),
+            "{:?}",
+            policy
+        );
+    }
+}
+
+#[test]
+fn round_robin_gives_every_replica_the_same_share() {
+    let replicas: Arc<Mutex<HashMap<ThreadId, usize>>> = Default::default();
+    let seen = replicas.clone();
+    let pipeline = pipeline![
+        balanced!(
+            parallel!(
+                {
+                    let seen = seen.clone();
+                    move |item: u64| {
+                        *seen
+                            .lock()
+                            .unwrap()
+                            .entry(thread::current().id())
+                            .or_default() += 1;
+                        Some(item)
+                    }
+                },
+                4
+            ),
+            LoadBalancing::RoundRobin
+        ),
+        collect!()
+    ];
+    pipeline.run_iter(0..400u64).unwrap();
+
+    let counts: Vec<usize> = replicas.lock().unwrap().values().copied().collect();
+    assert_eq!(counts, vec![100; 4]);
+}
+
+#[test]
+fn round_robin_spreads_the_outputs_of_a_flat_map() {
+    let replicas: Arc<Mutex<HashMap<ThreadId, usize>>> = Default::default();
+    let seen = replicas.clone();
+    let pipeline = pipeline![
+        sequential!(flat_map(
+            |item: u64| (0..64).map(move |piece| item * 64 + piece)
+        )),
+        balanced!(
+            parallel!(
+                {
+                    let seen = seen.clone();
+                    move |item: u64| {
+                        *seen
+                            .lock()
+                            .unwrap()
+                            .entry(thread::current().id())
+                            .or_default() += 1;
+                        Some(item)
+                    }
+                },
+                4
+            ),
+            LoadBalancing::RoundRobin
+        ),
+        collect!()
+    ];
+    assert_eq!(pipeline.run_iter(0..3u64).unwrap().len(), 192);
+
+    let counts: Vec<usize> = replicas.lock().unwrap().values().copied().collect();
+    assert_eq!(counts, vec![48; 4]);
+}
+
+#[test]
+fn idle_replicas_steal_from_a_busy_one() {
+    let gate = Gate::new();
+    let stage_gate = gate.clone();
+    let pipeline = pipeline![
+        balanced!(
+            parallel!(stage_gate.hold_first(), 4),
+            LoadBalancing::WorkStealing
+        ),
+        collect!()
+    ];
+    let mut results = pipeline.results();
+    for item in 0..40 {
+        pipeline.post(item).unwrap();
+    }
+    gate.wait_entered(1);
+
+    //a quarter of the items went to the queue of the replica held at
+    //the gate, the others take them
+    let mut done: Vec<u64> = (&mut results).take(39).collect();
+    gate.open();
+    done.sort_unstable();
+    assert_eq!(done, (1..40).collect::<Vec<_>>());
+    assert_eq!(pipeline.collect().unwrap(), vec![0]);
+}
+
+#[test]
+fn full_replica_queue_does_not_hold_up_posts_to_the_others() {
+    let gate = Gate::new();
+    let stage_gate = gate.clone();
+    let pipeline = pipeline![
+        balanced!(
+            bounded!(parallel!(stage_gate.hold_first(), 2), 1),
+            LoadBalancing::RoundRobin
+        ),
+        collect!()
+    ];
+    pipeline.post(0).unwrap();
+    gate.wait_entered(1);
+    //1 and 3 go to the free replica, 2 fills the queue of the held one
+    for item in 1..4 {
+        pipeline.post(item).unwrap();
+    }
+    let sender = pipeline.sender();
+    let blocked = thread::spawn(move || sender.post(4).unwrap());
+    thread::sleep(Duration::from_millis(50));
+
+    let started = Instant::now();
+    let posted = pipeline.post_timeout(5, Duration::from_secs(1));
+    let refused = pipeline.try_post(6);
+    let waited = started.elapsed();
+    gate.open();
+    blocked.join().unwrap();
+
+    assert!(posted.is_ok());
+    assert!(waited < Duration::from_secs(1), "posts waited {:?}", waited);
+    //6 went to the held replica's queue, which 4 was waiting for
+    assert!(matches!(refused, Err(TryPostError::Full(6))));
+    let mut collected = pipeline.collect().unwrap();
+    collected.sort_unstable();
+    assert_eq!(collected, vec![0, 1, 2, 3, 4, 5]);
+}
+
+#[test]
+fn timed_post_goes_in_once_its_queue_has_room() {
+    let gate = Gate::new();
+    let stage_gate = gate.clone();
+    let pipeline = pipeline![
+        keyed!(
+            bounded!(parallel!(stage_gate.hold_first(), 2), 1),
+            |item: &u64| *item % 2
+        ),
+        collect!()
+    ];
+    pipeline.post(0).unwrap();
+    gate.wait_entered(1);
+    //2 fills the queue of the held replica, 4 has to wait for it
+    pipeline.post(2).unwrap();
+    let opener = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        gate.open();
+    });
+
+    let started = Instant::now();
+    let posted = pipeline.post_timeout(4, Duration::from_secs(10));
+    let waited = started.elapsed();
+    opener.join().unwrap();
+
+    assert!(posted.is_ok());
+    assert!(waited >= Duration::from_millis(40), "waited {:?}", waited);
+    assert!(waited < Duration::from_secs(5), "waited {:?}", waited);
+    let mut collected = pipeline.collect().unwrap();
+    collected.sort_unstable();
+    assert_eq!(collected, vec![0, 2, 4]);
+}
+
+#[test]
+fn stealing_replicas_wake_up_for_items_posted_one_at_a_time() {
+    let pipeline = pipeline![
+        balanced!(
+            parallel!(|item: u64| Some(item), 4),
+            LoadBalancing::WorkStealing
+        ),
+        collect!()
+    ];
+    let mut results = pipeline.results();
+    for item in 0..20u64 {
+        pipeline.post(item).unwrap();
+        assert_eq!(results.next(), Some(item));
+    }
+    assert!(pipeline.collect().unwrap().is_empty());
+}
@@ -0,0 +1,88 @@
+#![allow(dead_code)]
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+//Holds the stages that pass it until it is opened, counting how many
+//items reached it
+#[derive(Clone, Default)]
+pub struct Gate {
+    open: Arc<AtomicBool>,
+    entered: Arc<AtomicUsize>,
+}
+
+impl Gate {
+    pub fn new() -> Gate {
+        Gate::default()
+    }
+
+    //Gives up after 10 seconds, so a failed test does not leave the
+    //pipeline waiting forever
+    pub fn pass(&self) {
+        self.entered.fetch_add(1, Ordering::SeqCst);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !self.open.load(Ordering::SeqCst) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    //A stage that passes the gate with every item, then hands it on
+    pub fn stage<T>(&self) -> impl FnMut(T) -> Option<T> {
+        let gate = self.clone();
+        move |item| {
+            gate.pass();
+            Some(item)
+        }
+    }
+
+    //A stage that holds item 0 at the gate and lets every other item through
+    pub fn hold_first(&self) -> impl FnMut(u64) -> Option<u64> {
+        let gate = self.clone();
+        move |item| {
+            if item == 0 {
+                gate.pass();
+            }
+            Some(item)
+        }
+    }
+
+    pub fn open(&self) {
+        self.open.store(true, Ordering::SeqCst);
+    }
+
+    pub fn entered(&self) -> usize {
+        self.entered.load(Ordering::SeqCst)
+    }
+
+    //Waits until that many items reached the gate
+    pub fn wait_entered(&self, count: usize) {
+        wait_until(|| self.entered() >= count);
+    }
+}
+
+//Polls the condition for up to 10 seconds
+pub fn wait_until(mut condition: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out waiting");
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+//Runs the pipeline on another thread, failing instead of hanging if it
+//does not finish
+pub fn run_within<T: Send + 'static>(run: impl FnOnce() -> T + Send + 'static) -> T {
+    let (done, finished) = mpsc::channel();
+    thread::spawn(move || done.send(run()).unwrap());
+    finished
+        .recv_timeout(Duration::from_secs(20))
+        .expect("the pipeline deadlocked")
+}
+
+//Sleeps a little, more for some items than for others, so replicas of a
+//farm finish them out of order
+pub fn jitter(item: u64) {
+    thread::sleep(Duration::from_micros(item.wrapping_mul(7919) % 500));
+}
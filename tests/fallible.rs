@@ -0,0 +1,61 @@
+mod common;
+
+use common::jitter;
+use rust_spp::*;
+use std::fmt;
+
+#[derive(Debug, PartialEq)]
+struct Odd(u64);
+
+impl fmt::Display for Odd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is odd", self.0)
+    }
+}
+
+impl std::error::Error for Odd {}
+
+fn halve(item: u64) -> Result<Option<u64>, Odd> {
+    jitter(item);
+    if item % 2 == 1 {
+        Err(Odd(item))
+    } else {
+        Ok(Some(item / 2))
+    }
+}
+
+#[test]
+fn failures_are_collected_apart_from_results() {
+    let pipeline = pipeline![
+        parallel!(|item: u64| Some(item), 2),
+        named!(parallel!(fallible(halve), 4), "halve"),
+        parallel!(|item: u64| Some(item * 3), 2),
+        collect_ordered!()
+    ];
+    for item in 0..100 {
+        pipeline.post(item).unwrap();
+    }
+    let (results, failures) = pipeline.collect_with_failures().unwrap();
+
+    assert_eq!(results, (0..50).map(|item| item * 3).collect::<Vec<_>>());
+    assert_eq!(failures.len(), 50);
+    for (failure, item) in failures.iter().zip((1..100).step_by(2)) {
+        assert_eq!(failure.stage, 1);
+        assert_eq!(failure.stage_name.as_deref(), Some("halve"));
+        assert_eq!(failure.order, Order::new(item));
+        assert_eq!(failure.downcast_ref::<Odd>(), Some(&Odd(item)));
+    }
+    assert_eq!(
+        failures[0].to_string(),
+        "stage 1 \"halve\" failed on item 1: 1 is odd"
+    );
+}
+
+#[test]
+fn collect_leaves_failures_out() {
+    let pipeline = pipeline![sequential!(fallible(halve)), collect!()];
+    for item in 0..10 {
+        pipeline.post(item).unwrap();
+    }
+    assert_eq!(pipeline.collect().unwrap(), vec![0, 1, 2, 3, 4]);
+}
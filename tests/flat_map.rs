@@ -0,0 +1,101 @@
+use rust_spp::*;
+use std::thread;
+use std::time::Duration;
+
+fn pieces(item: u64) -> Vec<u64> {
+    (0..item % 4).map(|piece| item * 10 + piece).collect()
+}
+
+#[test]
+fn ordered_collection_after_flat_map_farm_is_deterministic() {
+    let pipeline = pipeline![
+        parallel!(
+            flat_map(|item: u64| {
+                thread::sleep(Duration::from_micros((item * 7919) % 300));
+                pieces(item)
+            }),
+            4
+        ),
+        parallel!(|value: u64| Some(value + 1), 3),
+        collect_ordered!()
+    ];
+    for item in 0..200u64 {
+        pipeline.post(item).unwrap();
+    }
+    let collected = pipeline.collect().unwrap();
+
+    let expected: Vec<u64> = (0..200u64)
+        .flat_map(pieces)
+        .map(|value| value + 1)
+        .collect();
+    assert_eq!(collected, expected);
+}
+
+#[test]
+fn flat_map_after_flat_map_keeps_the_outputs_of_each_input_together() {
+    let pipeline = pipeline![
+        parallel!(flat_map(|item: u64| pieces(item)), 4),
+        parallel!(flat_map(|value: u64| vec![value, value]), 4),
+        sequential_ordered!(|value: u64| Some(value)),
+        collect_ordered!()
+    ];
+    for item in 0..100u64 {
+        pipeline.post(item).unwrap();
+    }
+    let collected = pipeline.collect().unwrap();
+
+    let expected: Vec<u64> = (0..100u64)
+        .flat_map(pieces)
+        .flat_map(|value| vec![value, value])
+        .collect();
+    assert_eq!(collected, expected);
+}
+
+#[test]
+fn flushed_outputs_come_after_flat_map_outputs() {
+    struct Splitter;
+    impl InOutMany<u64, u64> for Splitter {
+        type Output = Vec<u64>;
+        fn process(&mut self, item: u64) -> Vec<u64> {
+            pieces(item)
+        }
+        fn flush(&mut self) -> Vec<u64> {
+            vec![1000]
+        }
+    }
+
+    let pipeline = pipeline![sequential!(flat_map(Splitter)), collect_ordered!()];
+    for item in 0..20u64 {
+        pipeline.post(item).unwrap();
+    }
+    let collected = pipeline.collect().unwrap();
+
+    let mut expected: Vec<u64> = (0..20u64).flat_map(pieces).collect();
+    expected.push(1000);
+    assert_eq!(collected, expected);
+}
+
+#[test]
+fn unordered_farm_passes_outputs_on_without_waiting_for_earlier_items() {
+    let pipeline = pipeline![
+        parallel!(
+            flat_map(|item: u64| {
+                if item == 0 {
+                    thread::sleep(Duration::from_millis(300));
+                }
+                vec![item, item]
+            }),
+            4
+        ),
+        collect!()
+    ];
+    for item in 0..8u64 {
+        pipeline.post(item).unwrap();
+    }
+    let mut collected = pipeline.collect().unwrap();
+    assert_ne!(collected[0], 0);
+
+    collected.sort_unstable();
+    let expected: Vec<u64> = (0..8u64).flat_map(|item| vec![item, item]).collect();
+    assert_eq!(collected, expected);
+}
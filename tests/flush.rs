@@ -0,0 +1,92 @@
+use rust_spp::*;
+
+struct Batcher {
+    size: usize,
+    batch: Vec<u64>,
+}
+
+impl InOut<u64, Vec<u64>> for Batcher {
+    fn process(&mut self, item: u64) -> Option<Vec<u64>> {
+        self.batch.push(item);
+        if self.batch.len() == self.size {
+            Some(std::mem::take(&mut self.batch))
+        } else {
+            None
+        }
+    }
+
+    fn flush(&mut self) -> Vec<Vec<u64>> {
+        if self.batch.is_empty() {
+            vec![]
+        } else {
+            vec![std::mem::take(&mut self.batch)]
+        }
+    }
+}
+
+#[test]
+fn flushed_items_go_downstream_before_the_stream_ends() {
+    let pipeline = pipeline![
+        parallel!(|item: u64| Some(item), 4),
+        sequential_ordered!(Batcher {
+            size: 3,
+            batch: vec![]
+        }),
+        parallel!(|batch: Vec<u64>| Some(batch.iter().sum::<u64>()), 2),
+        collect_ordered!()
+    ];
+    for item in 0..11u64 {
+        pipeline.post(item).unwrap();
+    }
+    let collected = pipeline.collect().unwrap();
+    assert_eq!(collected, vec![3, 12, 21, 9 + 10]);
+}
+
+#[test]
+fn farm_replicas_flush_after_every_item() {
+    struct Seen(u64);
+    impl InOut<u64, u64> for Seen {
+        fn process(&mut self, item: u64) -> Option<u64> {
+            self.0 += 1;
+            Some(item)
+        }
+        fn flush(&mut self) -> Vec<u64> {
+            vec![1000 + self.0]
+        }
+    }
+
+    let pipeline = pipeline![parallel_ordered!(Seen(0), 3), collect_ordered!()];
+    for item in 0..100u64 {
+        pipeline.post(item).unwrap();
+    }
+    let collected = pipeline.collect().unwrap();
+
+    assert_eq!(collected[..100], (0..100).collect::<Vec<_>>()[..]);
+    let seen: u64 = collected[100..].iter().map(|flushed| flushed - 1000).sum();
+    assert_eq!(collected.len(), 103);
+    assert_eq!(seen, 100);
+}
+
+#[test]
+fn sink_flush_is_collected_last() {
+    struct Sum(u64);
+    impl In<u64, u64> for Sum {
+        fn process(&mut self, item: u64, _order: u64) -> u64 {
+            self.0 += item;
+            item
+        }
+        fn flush(&mut self) -> Vec<u64> {
+            vec![self.0]
+        }
+    }
+
+    let pipeline = pipeline![
+        parallel!(|item: u64| Some(item), 4),
+        sequential_ordered!(Sum(0))
+    ];
+    for item in 0..10u64 {
+        pipeline.post(item).unwrap();
+    }
+    let collected = pipeline.collect().unwrap();
+    assert_eq!(collected, vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 45]);
+}
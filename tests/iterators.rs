@@ -0,0 +1,56 @@
+use rust_spp::*;
+
+#[test]
+fn run_iter_posts_every_item_and_collects() {
+    let collected = pipeline![
+        parallel!(|item: u64| Some(item * item), 4),
+        collect_ordered!()
+    ]
+    .run_iter(0..100u64)
+    .unwrap();
+    assert_eq!(
+        collected,
+        (0..100).map(|item| item * item).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn run_iter_takes_iterators_that_stay_on_the_calling_thread() {
+    let lines = std::rc::Rc::new(vec![3u64, 1, 2]);
+    let items = (0..lines.len()).map(|index| lines[index]);
+    let mut collected = pipeline![parallel!(|item: u64| Some(item * 2), 2), collect!()]
+        .run_iter(items)
+        .unwrap();
+    collected.sort_unstable();
+    assert_eq!(collected, vec![2, 4, 6]);
+}
+
+#[test]
+fn into_ssp_pipeline_returns_results_lazily() {
+    let mut results = (0..1000u64).into_ssp_pipeline(pipeline![
+        parallel!(|item: u64| Some(item + 1), 4),
+        collect_ordered!()
+    ]);
+    assert_eq!(results.next().unwrap().unwrap(), 1);
+    let rest: Vec<u64> = results.map(Result::unwrap).collect();
+    assert_eq!(rest, (2..=1000).collect::<Vec<_>>());
+}
+
+#[test]
+fn lazy_results_end_with_the_panic_of_a_stage() {
+    let results: Vec<Result<u64, PipelineError>> = (0..10u64)
+        .into_ssp_pipeline(pipeline![
+            sequential!(|item: u64| {
+                if item == 5 {
+                    panic!("five");
+                }
+                Some(item)
+            }),
+            collect!()
+        ])
+        .collect();
+
+    let (last, values) = results.split_last().unwrap();
+    assert_eq!(last.as_ref().unwrap_err().message(), Some("five"));
+    assert!(values.iter().all(Result::is_ok));
+}
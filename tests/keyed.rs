@@ -0,0 +1,158 @@
+mod common;
+
+use common::{jitter, run_within};
+use rust_spp::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, ThreadId};
+
+//Counts the readings of every sensor it sees
+#[derive(Default)]
+struct Count {
+    counts: HashMap<u64, u64>,
+}
+
+impl InOut<(u64, u64), (u64, u64)> for Count {
+    fn process(&mut self, (sensor, reading): (u64, u64)) -> Option<(u64, u64)> {
+        jitter(reading);
+        let count = self.counts.entry(sensor).or_default();
+        *count += 1;
+        Some((sensor, *count))
+    }
+}
+
+fn sensor(&(sensor, _): &(u64, u64)) -> u64 {
+    sensor
+}
+
+fn readings() -> impl Iterator<Item = (u64, u64)> + Send {
+    (0..400u64).map(|reading| (reading * 7 % 13, reading))
+}
+
+#[test]
+fn items_with_the_same_key_reach_the_same_replica() {
+    let replicas: Arc<Mutex<HashMap<u64, ThreadId>>> = Default::default();
+    let seen = replicas.clone();
+    let pipeline = pipeline![
+        keyed!(
+            parallel!(
+                {
+                    let seen = seen.clone();
+                    move |(sensor, reading): (u64, u64)| {
+                        jitter(reading);
+                        let thread = thread::current().id();
+                        let first = *seen.lock().unwrap().entry(sensor).or_insert(thread);
+                        Some(first == thread)
+                    }
+                },
+                4
+            ),
+            sensor
+        ),
+        collect!()
+    ];
+    for reading in readings() {
+        pipeline.post(reading).unwrap();
+    }
+    let collected = pipeline.collect().unwrap();
+    assert_eq!(collected.len(), 400);
+    assert!(collected.into_iter().all(|same_replica| same_replica));
+}
+
+#[test]
+fn replicas_keep_state_for_their_keys() {
+    let pipeline = pipeline![
+        //readings of a sensor must reach its replica in posting order
+        parallel_ordered!(|reading: (u64, u64)| Some(reading), 4),
+        keyed!(parallel_ordered!(Count::default(), 4), sensor),
+        collect_ordered!()
+    ];
+    for reading in readings() {
+        pipeline.post(reading).unwrap();
+    }
+    let collected = pipeline.collect().unwrap();
+
+    let mut counts: HashMap<u64, u64> = HashMap::new();
+    let expected: Vec<(u64, u64)> = readings()
+        .map(|(sensor, _)| {
+            let count = counts.entry(sensor).or_default();
+            *count += 1;
+            (sensor, *count)
+        })
+        .collect();
+    assert_eq!(collected, expected);
+}
+
+#[test]
+fn keyed_sink_keeps_state_for_its_keys() {
+    struct Total {
+        totals: HashMap<u64, u64>,
+    }
+    impl In<(u64, u64), (u64, u64)> for Total {
+        fn process(&mut self, (sensor, reading): (u64, u64), _order: u64) -> (u64, u64) {
+            let total = self.totals.entry(sensor).or_default();
+            *total += reading;
+            (sensor, *total)
+        }
+    }
+
+    let pipeline = pipeline![
+        parallel!(|reading: (u64, u64)| Some(reading), 4),
+        keyed!(
+            parallel!(
+                Total {
+                    totals: HashMap::new()
+                },
+                4
+            ),
+            sensor
+        )
+    ];
+    for reading in readings() {
+        pipeline.post(reading).unwrap();
+    }
+    let mut totals: HashMap<u64, u64> = HashMap::new();
+    for (sensor, total) in pipeline.collect().unwrap() {
+        let highest = totals.entry(sensor).or_default();
+        *highest = (*highest).max(total);
+    }
+    let mut totals: Vec<(u64, u64)> = totals.into_iter().collect();
+    totals.sort_unstable();
+
+    let mut expected: HashMap<u64, u64> = HashMap::new();
+    for (sensor, reading) in readings() {
+        *expected.entry(sensor).or_default() += reading;
+    }
+    let mut expected: Vec<(u64, u64)> = expected.into_iter().collect();
+    expected.sort_unstable();
+    //a sensor split between replicas would end up with a lower total
+    assert_eq!(totals, expected);
+}
+
+#[test]
+fn panicking_key_fails_the_keyed_stage() {
+    let error = run_within(|| {
+        let pipeline = pipeline![
+            parallel!(|reading: (u64, u64)| Some(reading), 4),
+            keyed!(parallel!(Count::default(), 4), |&(sensor, reading): &(
+                u64,
+                u64
+            )| {
+                if reading == 57 {
+                    panic!("no sensor for {}", reading);
+                }
+                sensor
+            }),
+            collect!()
+        ];
+        for reading in readings() {
+            if pipeline.post(reading).is_err() {
+                break;
+            }
+        }
+        pipeline.collect().unwrap_err()
+    });
+
+    assert_eq!(error.stage, 1);
+    assert_eq!(error.message(), Some("no sensor for 57"));
+}
@@ -0,0 +1,181 @@
+use rust_spp::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+//A blocking queue that counts the values that come with an ingress time
+struct StampCounter {
+    queue: Arc<BlockingQueue<u64>>,
+    stamped: AtomicUsize,
+}
+
+impl WorkStorage<u64> for StampCounter {
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    fn enqueue(&self, item: WorkItem<u64>) -> u64 {
+        self.queue.enqueue(item)
+    }
+
+    fn enqueue_timestamped(&self, item: TimestampedWorkItem<u64>) {
+        if item.2.is_some() {
+            self.stamped.fetch_add(1, Ordering::SeqCst);
+        }
+        self.queue.enqueue_timestamped(item);
+    }
+
+    fn wait_and_dequeue(&self) -> TimestampedWorkItem<u64> {
+        self.queue.wait_and_dequeue()
+    }
+
+    fn try_dequeue(&self) -> Option<TimestampedWorkItem<u64>> {
+        self.queue.try_dequeue()
+    }
+}
+
+//Runs the items through a pipeline whose second stage counts the stamps
+fn stamped_items(latency: bool, balancing: LoadBalancing) -> usize {
+    let counter = Arc::new(StampCounter {
+        queue: BlockingQueue::new(),
+        stamped: AtomicUsize::new(0),
+    });
+    let mut pipeline = pipeline![
+        balanced!(parallel!(|item: u64| Some(item), 2), balancing),
+        stored!(sequential!(|item: u64| Some(item)), {
+            let counter = counter.clone();
+            move |_| counter.clone()
+        }),
+        collect!()
+    ];
+    if latency {
+        pipeline = pipeline.with_latency();
+    }
+    for item in 0..20u64 {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.end_and_wait().unwrap();
+    counter.stamped.load(Ordering::SeqCst)
+}
+
+#[test]
+fn percentiles_stay_within_a_sixteenth_of_the_real_value() {
+    let recorder = LatencyRecorder::new();
+    for micros in 1..=1000u64 {
+        recorder.record(Duration::from_micros(micros));
+    }
+    let histogram = recorder.snapshot();
+
+    assert_eq!(histogram.count(), 1000);
+    assert_eq!(histogram.max(), Duration::from_micros(1000));
+    assert_eq!(histogram.mean(), Duration::from_nanos(500_500));
+    for (share, real) in [(0.5, 500u64), (0.99, 990), (0.1, 100)] {
+        let real = Duration::from_micros(real);
+        let percentile = histogram.percentile(share);
+        assert!(percentile >= real, "{share}: {percentile:?} < {real:?}");
+        assert!(percentile <= real + real / 16, "{share}: {percentile:?}");
+    }
+    assert_eq!(histogram.percentile(1.0), histogram.max());
+}
+
+#[test]
+fn empty_histogram_reads_as_zero() {
+    let histogram = LatencyRecorder::new().snapshot();
+    assert!(histogram.is_empty());
+    assert_eq!(histogram.p50(), Duration::ZERO);
+    assert_eq!(histogram.mean(), Duration::ZERO);
+}
+
+#[test]
+fn every_stage_records_how_long_after_posting_items_left_it() {
+    let mut pipeline = pipeline![
+        sequential!(|item: u64| {
+            thread::sleep(Duration::from_millis(1));
+            Some(item)
+        }),
+        parallel!(|item: u64| Some(item), 2),
+        collect!()
+    ]
+    .with_latency();
+    for item in 0..50u64 {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.end_and_wait().unwrap();
+    let stats = pipeline.stats();
+
+    for stage in &stats.stages {
+        assert_eq!(stage.latency.count(), 50);
+        assert!(stage.latency.p50() >= Duration::from_millis(1));
+    }
+    //items only get older on their way through
+    let end_to_end = stats.latency().unwrap();
+    assert!(end_to_end.max() >= stats.stages[0].latency.max());
+    assert!(end_to_end.p99() <= end_to_end.max());
+}
+
+#[test]
+fn a_slow_item_shows_up_in_the_tail() {
+    let mut pipeline = pipeline![
+        parallel!(
+            |item: u64| {
+                if item == 0 {
+                    thread::sleep(Duration::from_millis(100));
+                }
+                Some(item)
+            },
+            4
+        ),
+        collect!()
+    ]
+    .with_latency();
+    for item in 0..200u64 {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.end_and_wait().unwrap();
+    let latency = pipeline.stats().latency().unwrap().clone();
+
+    assert!(latency.max() >= Duration::from_millis(100));
+    assert!(latency.p50() < Duration::from_millis(100));
+}
+
+#[test]
+fn latency_is_not_measured_unless_asked_for() {
+    let mut pipeline = pipeline![parallel!(|item: u64| Some(item), 2), collect!()];
+    for item in 0..20u64 {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.end_and_wait().unwrap();
+
+    assert!(pipeline.stats().latency().unwrap().is_empty());
+}
+
+#[test]
+fn posted_values_are_only_stamped_when_latency_is_measured() {
+    for balancing in [LoadBalancing::Shared, LoadBalancing::RoundRobin] {
+        assert_eq!(stamped_items(false, balancing), 0);
+        assert_eq!(stamped_items(true, balancing), 20);
+    }
+}
+
+#[test]
+fn flushed_values_are_left_out() {
+    struct Batch(Vec<u64>);
+    impl InOut<u64, u64> for Batch {
+        fn process(&mut self, item: u64) -> Option<u64> {
+            self.0.push(item);
+            None
+        }
+        fn flush(&mut self) -> Vec<u64> {
+            vec![self.0.iter().sum()]
+        }
+    }
+
+    let mut pipeline = pipeline![sequential!(Batch(vec![])), collect!()].with_latency();
+    for item in 0..10u64 {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.end_and_wait().unwrap();
+
+    assert_eq!(pipeline.stats().latency().unwrap().count(), 0);
+}
@@ -0,0 +1,85 @@
+mod common;
+
+use common::{wait_until, Gate};
+use rust_spp::*;
+use std::time::Duration;
+
+#[test]
+fn posts_are_refused_while_every_token_is_taken() {
+    let gate = Gate::new();
+    let stage_gate = gate.clone();
+    //the queues are unbounded, only the tokens hold the posts back
+    let pipeline = pipeline![
+        parallel!(stage_gate.stage::<u32>(), 2),
+        sequential!(|item: u32| Some(item)),
+        collect!()
+    ]
+    .with_max_live_tokens(3);
+
+    for item in 0..3 {
+        pipeline.post(item).unwrap();
+    }
+    let refused = pipeline.try_post(3);
+    let timed_out = pipeline.post_timeout(4, Duration::from_millis(20));
+    let live = pipeline.live_tokens();
+    gate.open();
+
+    assert!(matches!(refused, Err(TryPostError::Full(3))));
+    assert!(matches!(timed_out, Err(PostTimeoutError::Timeout(4))));
+    assert_eq!(live, 3);
+    let mut collected = pipeline.collect().unwrap();
+    collected.sort_unstable();
+    assert_eq!(collected, vec![0, 1, 2]);
+}
+
+#[test]
+fn tokens_come_back_once_the_sink_took_the_items() {
+    let pipeline = pipeline![parallel!(|item: u64| Some(item * 2), 4), collect_ordered!()]
+        .with_max_live_tokens(4);
+
+    let mut results = pipeline.results();
+    for item in 0..100u64 {
+        pipeline.post(item).unwrap();
+        assert!(pipeline.live_tokens() <= 4);
+    }
+    let read: Vec<u64> = results.by_ref().take(100).collect();
+    wait_until(|| pipeline.live_tokens() == 0);
+
+    assert_eq!(read, (0..100).map(|item| item * 2).collect::<Vec<_>>());
+    assert!(pipeline.collect().unwrap().is_empty());
+}
+
+#[test]
+fn dropped_items_give_their_token_back() {
+    let pipeline = pipeline![
+        parallel!(|item: u64| (!item.is_multiple_of(2)).then_some(item), 4),
+        sequential_ordered!(|item: u64| Some(item)),
+        collect_ordered!()
+    ]
+    .with_max_live_tokens(2);
+
+    let collected = pipeline.run_iter(0..200u64).unwrap();
+    assert_eq!(
+        collected,
+        (0..200u64)
+            .filter(|item| !item.is_multiple_of(2))
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn flat_map_outputs_do_not_wait_for_tokens() {
+    let pipeline = pipeline![
+        parallel!(flat_map(|item: u64| vec![item; 10]), 2),
+        collect_ordered!()
+    ]
+    .with_max_live_tokens(1);
+
+    let collected = pipeline.run_iter(0..50u64).unwrap();
+    assert_eq!(
+        collected,
+        (0..50u64)
+            .flat_map(|item| vec![item; 10])
+            .collect::<Vec<_>>()
+    );
+}
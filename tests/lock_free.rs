@@ -0,0 +1,180 @@
+mod common;
+
+use common::jitter;
+use rust_spp::*;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+fn value<T>(item: TimestampedWorkItem<T>) -> T {
+    match item.0 {
+        WorkItem::Value(value) => value,
+        _ => panic!("expected a value"),
+    }
+}
+
+#[test]
+fn every_item_comes_out_once_with_many_producers_and_consumers() {
+    let queue: Arc<LockFreeQueue<u64>> = LockFreeQueue::new();
+    let consumers: Vec<_> = (0..4)
+        .map(|_| {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                let mut taken = vec![];
+                loop {
+                    match queue.wait_and_dequeue() {
+                        TimestampedWorkItem(WorkItem::Stop, order, _) => {
+                            queue.enqueue_timestamped(TimestampedWorkItem::stop(order));
+                            return taken;
+                        }
+                        item => taken.push(value(item)),
+                    }
+                }
+            })
+        })
+        .collect();
+    let producers: Vec<_> = (0..4u64)
+        .map(|producer| {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                for item in 0..5000 {
+                    queue.enqueue(WorkItem::Value(producer * 10_000 + item));
+                }
+            })
+        })
+        .collect();
+    for producer in producers {
+        producer.join().unwrap();
+    }
+    queue.enqueue(WorkItem::Stop);
+
+    let mut seen = HashSet::new();
+    for consumer in consumers {
+        let taken = consumer.join().unwrap();
+        //items of one producer come out in the order it queued them
+        for producer in 0..4 {
+            let from_producer: Vec<u64> = taken
+                .iter()
+                .copied()
+                .filter(|item| item / 10_000 == producer)
+                .collect();
+            assert!(from_producer.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+        for item in taken {
+            assert!(seen.insert(item), "{} came out twice", item);
+        }
+    }
+    assert_eq!(seen.len(), 20_000);
+}
+
+#[test]
+fn every_item_comes_out_once_with_one_producer_and_many_consumers() {
+    let queue: Arc<LockFreeQueue<u64>> = LockFreeQueue::for_link(1, 4, None);
+    assert_eq!(queue.kind(), "spmc");
+    let consumers: Vec<_> = (0..4)
+        .map(|_| {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                let mut taken = vec![];
+                loop {
+                    match queue.wait_and_dequeue() {
+                        //the producer is done, so putting it back is fine
+                        TimestampedWorkItem(WorkItem::Stop, order, _) => {
+                            queue.enqueue_timestamped(TimestampedWorkItem::stop(order));
+                            return taken;
+                        }
+                        item => taken.push(value(item)),
+                    }
+                }
+            })
+        })
+        .collect();
+    for item in 0..20_000u64 {
+        queue.enqueue(WorkItem::Value(item));
+    }
+    queue.enqueue(WorkItem::Stop);
+
+    let mut seen = HashSet::new();
+    for consumer in consumers {
+        let taken = consumer.join().unwrap();
+        assert!(taken.windows(2).all(|pair| pair[0] < pair[1]));
+        for item in taken {
+            assert!(seen.insert(item), "{} came out twice", item);
+        }
+    }
+    assert_eq!(seen.len(), 20_000);
+}
+
+#[test]
+fn bounded_queue_refuses_items_when_full() {
+    let queue: Arc<LockFreeQueue<u64>> = LockFreeQueue::bounded(2);
+    assert_eq!(queue.try_enqueue(WorkItem::Value(0)).ok(), Some(0));
+    assert_eq!(queue.try_enqueue(WorkItem::Value(1)).ok(), Some(1));
+    assert!(matches!(
+        queue.try_enqueue(WorkItem::Value(2)),
+        Err(WorkItem::Value(2))
+    ));
+    assert!(queue
+        .enqueue_timeout(WorkItem::Value(2), Duration::from_millis(10))
+        .is_err());
+    //a refused item takes no timestamp, and Stop takes no slot
+    assert_eq!(queue.enqueue(WorkItem::Stop), 2);
+    assert_eq!(queue.len(), 3);
+
+    assert_eq!(value(queue.wait_and_dequeue()), 0);
+    assert_eq!(queue.try_enqueue(WorkItem::Value(3)).ok(), Some(3));
+}
+
+#[test]
+fn parked_consumer_wakes_up_for_a_new_item() {
+    let queue: Arc<LockFreeQueue<u64>> = LockFreeQueue::new();
+    let consumer = {
+        let queue = queue.clone();
+        thread::spawn(move || value(queue.wait_and_dequeue()))
+    };
+    thread::sleep(Duration::from_millis(50));
+    queue.enqueue(WorkItem::Value(7));
+    assert_eq!(consumer.join().unwrap(), 7);
+}
+
+#[test]
+fn pipeline_of_lock_free_stages() {
+    let pipeline = pipeline![
+        lock_free!(parallel!(
+            |item: u64| {
+                jitter(item);
+                Some(item * 2)
+            },
+            8
+        )),
+        lock_free!(bounded!(
+            parallel_ordered!(|item: u64| Some(item + 1), 4),
+            4
+        )),
+        lock_free!(sequential!(|item: u64| Some(item))),
+        collect_ordered!()
+    ];
+    let sender = pipeline.sender();
+    let producers: Vec<_> = (0..4u64)
+        .map(|producer| {
+            let sender = sender.clone();
+            thread::spawn(move || {
+                for item in 0..250 {
+                    sender.post(producer * 250 + item).unwrap();
+                }
+            })
+        })
+        .collect();
+    drop(sender);
+    for producer in producers {
+        producer.join().unwrap();
+    }
+
+    let mut collected = pipeline.collect().unwrap();
+    collected.sort_unstable();
+    assert_eq!(
+        collected,
+        (0..1000).map(|item| item * 2 + 1).collect::<Vec<_>>()
+    );
+}
@@ -0,0 +1,87 @@
+#![cfg(feature = "prometheus")]
+
+use rust_spp::*;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+//Sends the request and returns the whole response
+fn request(address: SocketAddr, request: &str) -> String {
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+fn get(address: SocketAddr, path: &str) -> String {
+    request(
+        address,
+        &format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n"),
+    )
+}
+
+#[test]
+fn metrics_of_every_stage_are_served_on_localhost() {
+    let mut pipeline = pipeline![
+        named!(parallel!(|item: u64| Some(item * 2), 2), "double"),
+        collect!()
+    ];
+    let server = pipeline.serve_metrics("127.0.0.1:0").unwrap();
+    for item in 0..20u64 {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.end_and_wait().unwrap();
+
+    let response = get(server.local_addr(), "/metrics");
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200 OK"));
+    assert!(head.contains("Content-Type: text/plain; version=0.0.4"));
+    assert!(body.contains("# TYPE rust_spp_items_in_total counter"));
+    assert!(body.contains("rust_spp_items_in_total{stage=\"0\",name=\"double\"} 20"));
+    assert!(body.contains("rust_spp_items_in_total{stage=\"1\",name=\"\"} 20"));
+    assert!(body.contains("rust_spp_busy_seconds_total{stage=\"0\",name=\"double\",replica=\"1\"}"));
+    assert!(body.contains("rust_spp_live_tokens 0"));
+}
+
+#[test]
+fn an_idle_client_does_not_hold_up_scrapes() {
+    let pipeline = pipeline![sequential!(|item: u64| Some(item)), collect!()];
+    let server = pipeline.serve_metrics("127.0.0.1:0").unwrap();
+
+    //connects and never sends its request
+    let _idle = TcpStream::connect(server.local_addr()).unwrap();
+    let started = Instant::now();
+    let response = get(server.local_addr(), "/metrics");
+
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(started.elapsed() < Duration::from_secs(2));
+    drop(server);
+    assert!(pipeline.collect().unwrap().is_empty());
+}
+
+#[test]
+fn other_paths_and_methods_are_refused() {
+    let pipeline = pipeline![sequential!(|item: u64| Some(item)), collect!()];
+    let server = pipeline.serve_metrics("127.0.0.1:0").unwrap();
+
+    assert!(get(server.local_addr(), "/").starts_with("HTTP/1.1 404 Not Found"));
+    let posted = request(
+        server.local_addr(),
+        "POST /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n",
+    );
+    assert!(posted.starts_with("HTTP/1.1 405 Method Not Allowed"));
+}
+
+#[test]
+fn the_port_is_closed_once_the_server_is_dropped() {
+    let pipeline = pipeline![sequential!(|item: u64| Some(item)), collect!()];
+    let server = pipeline.serve_metrics("127.0.0.1:0").unwrap();
+    let address = server.local_addr();
+    drop(server);
+
+    assert!(TcpStream::connect(address).is_err());
+}
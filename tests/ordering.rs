@@ -0,0 +1,125 @@
+mod common;
+
+use common::jitter;
+use rust_spp::*;
+
+#[test]
+fn collect_ordered_after_two_farms_returns_items_in_posting_order() {
+    let pipeline = pipeline![
+        parallel!(
+            |item: u64| {
+                jitter(item);
+                Some(item * 2)
+            },
+            8
+        ),
+        parallel!(
+            |item: u64| {
+                jitter(item + 1);
+                Some(item + 1)
+            },
+            4
+        ),
+        collect_ordered!()
+    ];
+    for item in 0..500u64 {
+        pipeline.post(item).unwrap();
+    }
+    let collected = pipeline.collect().unwrap();
+    assert_eq!(
+        collected,
+        (0..500).map(|item| item * 2 + 1).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn ordered_sequential_stage_between_farms_sees_posting_order() {
+    struct Delta {
+        last: Option<u64>,
+    }
+    impl InOut<u64, i64> for Delta {
+        fn process(&mut self, item: u64) -> Option<i64> {
+            let delta = item as i64 - self.last.unwrap_or(0) as i64;
+            self.last = Some(item);
+            Some(delta)
+        }
+    }
+
+    let pipeline = pipeline![
+        parallel!(
+            |item: u64| {
+                jitter(item);
+                Some(item * item)
+            },
+            8
+        ),
+        sequential_ordered!(Delta { last: None }),
+        parallel!(|delta: i64| Some(delta), 4),
+        collect_ordered!()
+    ];
+    for item in 0..300u64 {
+        pipeline.post(item).unwrap();
+    }
+    let collected = pipeline.collect().unwrap();
+
+    let expected: Vec<i64> = (0..300i64)
+        .map(|item| {
+            item * item
+                - if item == 0 {
+                    0
+                } else {
+                    (item - 1) * (item - 1)
+                }
+        })
+        .collect();
+    assert_eq!(collected, expected);
+}
+
+#[test]
+fn ordered_farm_puts_its_output_back_in_sequence() {
+    let pipeline = pipeline![
+        parallel_ordered!(
+            |item: u64| {
+                jitter(item);
+                Some(item)
+            },
+            8
+        ),
+        //an unordered sink keeps the order it receives items in
+        collect!()
+    ];
+    for item in 0..500u64 {
+        pipeline.post(item).unwrap();
+    }
+    let collected = pipeline.collect().unwrap();
+    assert_eq!(collected, (0..500).collect::<Vec<_>>());
+}
+
+#[test]
+fn dropped_items_do_not_stall_ordered_stages() {
+    let pipeline = pipeline![
+        parallel!(
+            |item: u64| {
+                jitter(item);
+                if item.is_multiple_of(3) {
+                    None
+                } else {
+                    Some(item)
+                }
+            },
+            8
+        ),
+        sequential_ordered!(|item: u64| Some(item)),
+        collect_ordered!()
+    ];
+    for item in 0..300u64 {
+        pipeline.post(item).unwrap();
+    }
+    let collected = pipeline.collect().unwrap();
+    assert_eq!(
+        collected,
+        (0..300u64)
+            .filter(|item| !item.is_multiple_of(3))
+            .collect::<Vec<_>>()
+    );
+}
@@ -0,0 +1,66 @@
+mod common;
+
+use common::wait_until;
+use rust_spp::*;
+
+#[test]
+fn panicking_stage_makes_collect_return_an_error() {
+    let pipeline = pipeline![
+        parallel!(|item: u64| Some(item), 4),
+        sequential_ordered!("checker", |item: u64| {
+            if item == 57 {
+                panic!("bad item {}", item);
+            }
+            Some(item)
+        }),
+        parallel!(|item: u64| Some(item), 2),
+        collect_ordered!()
+    ];
+    for item in 0..200u64 {
+        if pipeline.post(item).is_err() {
+            break;
+        }
+    }
+    let error = pipeline.collect().unwrap_err();
+
+    assert_eq!(error.stage, 1);
+    assert_eq!(error.stage_name.as_deref(), Some("checker"));
+    assert_eq!(error.replica, 0);
+    assert_eq!(error.message(), Some("bad item 57"));
+    assert_eq!(
+        error.to_string(),
+        "stage 1 \"checker\" (replica 0) panicked: bad item 57"
+    );
+}
+
+#[test]
+fn panicking_sink_makes_end_and_wait_return_an_error() {
+    let mut pipeline = pipeline![
+        parallel!(|item: u64| Some(item), 4),
+        parallel!(
+            |item: u64| {
+                if item == 3 {
+                    panic!("sink failed");
+                }
+            },
+            2
+        )
+    ];
+    for item in 0..10 {
+        let _ = pipeline.post(item);
+    }
+    let error = pipeline.end_and_wait().unwrap_err();
+    assert_eq!(error.stage, 1);
+    assert_eq!(error.message(), Some("sink failed"));
+}
+
+#[test]
+fn failed_pipeline_refuses_new_items() {
+    let pipeline = pipeline![
+        sequential!(|item: u64| -> Option<u64> { panic!("item {}", item) }),
+        collect!()
+    ];
+    pipeline.post(0).unwrap();
+    wait_until(|| pipeline.post(1).is_err());
+    assert_eq!(pipeline.collect().unwrap_err().message(), Some("item 0"));
+}
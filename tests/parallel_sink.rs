@@ -0,0 +1,63 @@
+mod common;
+
+use common::jitter;
+use rust_spp::*;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[test]
+fn replicated_sink_collects_every_result() {
+    let threads = Arc::new(Mutex::new(HashSet::new()));
+    let seen = threads.clone();
+    let pipeline = pipeline![
+        parallel!(|item: u64| Some(item + 1), 4),
+        parallel!(
+            {
+                let seen = seen.clone();
+                move |item: u64| {
+                    jitter(item);
+                    seen.lock().unwrap().insert(thread::current().id());
+                    item * 10
+                }
+            },
+            4
+        )
+    ];
+    for item in 0..200u64 {
+        pipeline.post(item).unwrap();
+    }
+    let mut collected = pipeline.collect().unwrap();
+    collected.sort_unstable();
+
+    assert_eq!(
+        collected,
+        (1..=200).map(|item| item * 10).collect::<Vec<_>>()
+    );
+    assert!(threads.lock().unwrap().len() > 1);
+}
+
+#[test]
+fn ordered_replicated_sink_returns_results_in_posting_order() {
+    let pipeline = pipeline![
+        parallel!(
+            |item: u64| {
+                jitter(item);
+                Some(item)
+            },
+            4
+        ),
+        parallel_ordered!(
+            |item: u64| {
+                jitter(item + 3);
+                item * 2
+            },
+            4
+        )
+    ];
+    for item in 0..300u64 {
+        pipeline.post(item).unwrap();
+    }
+    let collected = pipeline.collect().unwrap();
+    assert_eq!(collected, (0..300).map(|item| item * 2).collect::<Vec<_>>());
+}
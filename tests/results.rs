@@ -0,0 +1,27 @@
+use rust_spp::*;
+
+#[test]
+fn results_come_out_while_items_are_still_posted() {
+    let mut pipeline = pipeline![parallel!(|item: u64| Some(item * 2), 4), collect_ordered!()];
+    let mut results = pipeline.results();
+    for item in 0..50 {
+        pipeline.post(item).unwrap();
+        assert_eq!(results.next(), Some(item * 2));
+    }
+    pipeline.end();
+    assert_eq!(results.next(), None);
+    assert!(pipeline.collect().unwrap().is_empty());
+}
+
+#[test]
+fn results_not_read_are_left_for_collect() {
+    let mut pipeline = pipeline![sequential!(|item: u64| Some(item)), collect!()];
+    let mut results = pipeline.results();
+    for item in 0..10 {
+        pipeline.post(item).unwrap();
+    }
+    assert_eq!(results.next(), Some(0));
+    assert_eq!(results.next(), Some(1));
+    pipeline.end();
+    assert_eq!(pipeline.collect().unwrap(), (2..10).collect::<Vec<_>>());
+}
@@ -0,0 +1,55 @@
+use rust_spp::*;
+use std::thread;
+
+#[test]
+fn items_posted_from_many_threads_all_get_a_sequence_number() {
+    let pipeline = pipeline![parallel!(|item: u64| Some(item), 4), collect_ordered!()];
+    let sender = pipeline.sender();
+    let producers: Vec<_> = (0..8u64)
+        .map(|producer| {
+            let sender = sender.clone();
+            thread::spawn(move || {
+                for item in 0..250 {
+                    sender.post(producer * 1000 + item).unwrap();
+                }
+            })
+        })
+        .collect();
+    drop(sender);
+    for producer in producers {
+        producer.join().unwrap();
+    }
+
+    let mut collected = pipeline.collect().unwrap();
+    assert_eq!(collected.len(), 2000);
+    collected.sort_unstable();
+    let expected: Vec<u64> = (0..8)
+        .flat_map(|producer| (0..250).map(move |item| producer * 1000 + item))
+        .collect();
+    assert_eq!(collected, expected);
+}
+
+#[test]
+fn pipeline_ends_once_every_sender_is_dropped() {
+    let pipeline = pipeline![sequential!(|item: u64| Some(item)), collect!()];
+    let sender = pipeline.sender();
+    let other = sender.clone();
+    sender.post(1).unwrap();
+    drop(sender);
+    assert!(!other.is_ended());
+    other.post(2).unwrap();
+    drop(other);
+
+    let results: Vec<u64> = pipeline.results().collect();
+    assert_eq!(results, vec![1, 2]);
+}
+
+#[test]
+fn senders_cannot_post_after_end() {
+    let mut pipeline = pipeline![sequential!(|item: u64| Some(item)), collect!()];
+    let sender = pipeline.sender();
+    pipeline.end();
+    assert!(sender.is_ended());
+    assert!(sender.post(1).is_err());
+    assert!(pipeline.collect().unwrap().is_empty());
+}
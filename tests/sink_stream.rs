@@ -0,0 +1,57 @@
+#![cfg(feature = "futures")]
+
+mod common;
+
+use common::Gate;
+use futures::executor::block_on;
+use futures::{future, stream, FutureExt, Sink, SinkExt, StreamExt};
+use rust_spp::*;
+use std::pin::Pin;
+
+#[test]
+fn sink_posts_and_stream_returns_the_results() {
+    let pipeline = pipeline![parallel!(|item: u64| Some(item * 2), 4), collect_ordered!()];
+    let mut sink = pipeline.sink();
+    let results = std::thread::spawn({
+        let results = pipeline.stream();
+        move || block_on(results.collect::<Vec<_>>())
+    });
+
+    block_on(async {
+        sink.send_all(&mut stream::iter((0..100u64).map(Ok)))
+            .await
+            .unwrap();
+        sink.close().await.unwrap();
+    });
+    assert_eq!(
+        results.join().unwrap(),
+        (0..100).map(|item| item * 2).collect::<Vec<_>>()
+    );
+    assert!(pipeline.collect().unwrap().is_empty());
+}
+
+#[test]
+fn sink_is_not_ready_while_the_first_stage_is_full() {
+    let gate = Gate::new();
+    let stage_gate = gate.clone();
+    let pipeline = pipeline![
+        bounded!(sequential!(stage_gate.stage::<u64>()), 1),
+        collect!()
+    ];
+    let mut sink = pipeline.sink();
+    block_on(sink.send(0)).unwrap();
+    gate.wait_entered(1);
+    block_on(sink.send(1)).unwrap();
+
+    //2 is held by the sink until there is room for it
+    assert!(sink.send(2).now_or_never().is_none());
+    let ready = future::poll_fn(|cx| Pin::new(&mut sink).poll_ready(cx));
+    assert!(ready.now_or_never().is_none());
+
+    gate.open();
+    block_on(async {
+        sink.send(3).await.unwrap();
+        sink.close().await.unwrap();
+    });
+    assert_eq!(pipeline.collect().unwrap(), vec![0, 1, 2, 3]);
+}
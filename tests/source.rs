@@ -0,0 +1,41 @@
+mod common;
+
+use common::Gate;
+use rust_spp::*;
+
+#[test]
+fn source_stage_posts_until_it_runs_out() {
+    let mut next = 0u64;
+    let pipeline = pipeline![
+        source!(std::iter::from_fn(move || {
+            next += 1;
+            (next <= 100).then_some(next)
+        })),
+        parallel!(|item: u64| Some(item * 2), 4),
+        collect_ordered!()
+    ];
+    assert_eq!(
+        pipeline.collect().unwrap(),
+        (1..=100).map(|item| item * 2).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn source_waits_while_the_first_stage_is_full() {
+    let gate = Gate::new();
+    let stage_gate = gate.clone();
+    let pipeline = pipeline![
+        capacity: 2;
+        source!(0..1000u64),
+        sequential!(stage_gate.stage::<u64>()),
+        collect!()
+    ];
+    gate.wait_entered(1);
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    let live = pipeline.live_tokens();
+    gate.open();
+
+    //one item in the stage, two in its queue and one the source is posting
+    assert!(live <= 4, "{} items were posted", live);
+    assert_eq!(pipeline.collect().unwrap(), (0..1000).collect::<Vec<_>>());
+}
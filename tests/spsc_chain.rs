@@ -0,0 +1,40 @@
+use rust_spp::*;
+use std::thread;
+
+//Links between single replicas get single-producer single-consumer queues.
+//Replicas must not push into the queue they take from, or they would be
+//a second producer next to the stage before them. Many pipelines run at
+//once so that threads get preempted in the middle of a push.
+#[test]
+fn chains_of_single_replica_stages_end_cleanly() {
+    let runners: Vec<_> = (0..8u64)
+        .map(|runner| {
+            thread::spawn(move || {
+                for round in 0..150u64 {
+                    let pipeline = pipeline![
+                        sequential!(|item: u64| Some(item + 1)),
+                        sequential!(|item: u64| Some(item * 2)),
+                        sequential!(|item: u64| Some(item)),
+                        balanced!(
+                            parallel!(|item: u64| Some(item), 3),
+                            LoadBalancing::RoundRobin
+                        ),
+                        sequential!(|item: u64| Some(item)),
+                        keyed!(parallel!(|item: u64| Some(item), 2), |item: &u64| *item),
+                        sequential!(|item: u64| item)
+                    ];
+                    let items = (runner + round) % 5;
+                    let mut collected = pipeline.run_iter(0..items).unwrap();
+                    collected.sort_unstable();
+                    assert_eq!(
+                        collected,
+                        (0..items).map(|item| (item + 1) * 2).collect::<Vec<_>>()
+                    );
+                }
+            })
+        })
+        .collect();
+    for runner in runners {
+        runner.join().unwrap();
+    }
+}
@@ -0,0 +1,144 @@
+mod common;
+
+use common::{jitter, Gate};
+use rust_spp::*;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+//Fails one item in four
+fn check(item: u64) -> Result<Option<u64>, io::Error> {
+    if item % 4 == 1 {
+        Err(io::Error::other(format!("{item} failed")))
+    } else {
+        Ok(Some(item))
+    }
+}
+
+#[test]
+fn items_in_out_dropped_and_failed_are_counted_per_stage() {
+    let mut pipeline = pipeline![
+        parallel!(
+            |item: u64| {
+                jitter(item);
+                (!item.is_multiple_of(4)).then_some(item)
+            },
+            4
+        ),
+        named!(sequential!(fallible(check)), "check"),
+        collect!()
+    ];
+    for item in 0..400u64 {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.end_and_wait().unwrap();
+    let stats = pipeline.stats();
+
+    let first = &stats.stages[0];
+    assert_eq!(first.stage, 0);
+    assert_eq!(first.name, None);
+    assert_eq!(first.replicas.len(), 4);
+    assert_eq!(
+        (first.items_in, first.items_out, first.dropped, first.failed),
+        (400, 300, 100, 0)
+    );
+
+    //the dropped items go through without being counted in
+    let second = &stats.stages[1];
+    assert_eq!(second.name.as_deref(), Some("check"));
+    assert_eq!(
+        (
+            second.items_in,
+            second.items_out,
+            second.dropped,
+            second.failed
+        ),
+        (300, 200, 0, 100)
+    );
+    assert_eq!(stats.stages[2].items_in, 200);
+}
+
+#[test]
+fn slow_stage_shows_up_as_the_busiest() {
+    let mut pipeline = pipeline![
+        sequential!(|item: u64| Some(item)),
+        sequential!(|item: u64| {
+            thread::sleep(Duration::from_millis(2));
+            Some(item)
+        }),
+        collect!()
+    ];
+    for item in 0..50u64 {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.end_and_wait().unwrap();
+    let stats = pipeline.stats();
+
+    let (fast, slow) = (&stats.stages[0], &stats.stages[1]);
+    assert!(slow.busy() >= Duration::from_millis(100));
+    assert!(slow.service_time() >= Duration::from_millis(2));
+    assert!(slow.utilization() > fast.utilization());
+    assert!(slow.throughput() > 0.0);
+}
+
+#[test]
+fn queue_lengths_can_be_read_while_the_pipeline_runs() {
+    let gate = Gate::new();
+    let stage_gate = gate.clone();
+    let pipeline = pipeline![sequential!(stage_gate.stage::<u64>()), collect!()];
+    for item in 0..10u64 {
+        pipeline.post(item).unwrap();
+    }
+    gate.wait_entered(1);
+    let running = pipeline.stats();
+    gate.open();
+
+    assert_eq!(running.stages[0].queue_len, 9);
+    assert_eq!(running.stages[0].items_in, 0);
+    assert_eq!(pipeline.collect().unwrap().len(), 10);
+}
+
+#[test]
+fn items_waiting_for_a_slow_one_fill_the_reorder_buffer() {
+    let mut pipeline = pipeline![
+        parallel!(
+            |item: u64| {
+                if item == 0 {
+                    thread::sleep(Duration::from_millis(100));
+                }
+                Some(item)
+            },
+            4
+        ),
+        collect_ordered!()
+    ];
+    for item in 0..50u64 {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.end_and_wait().unwrap();
+    let stats = pipeline.stats();
+
+    let sink = &stats.stages[1];
+    assert!(sink.max_reorder_buffer > 0);
+    assert_eq!(sink.reorder_buffer, 0);
+    assert_eq!(sink.queue_len, 0);
+}
+
+#[test]
+fn printed_stats_have_a_line_per_stage() {
+    let mut pipeline = pipeline![
+        named!(parallel!(|item: u64| Some(item), 2), "double"),
+        collect!()
+    ];
+    for item in 0..10u64 {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.end_and_wait().unwrap();
+
+    let table = pipeline.stats().to_string();
+    let lines: Vec<&str> = table.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].contains("items/s"));
+    assert!(!lines[0].contains("p99"));
+    assert!(lines[1].contains("double"));
+}
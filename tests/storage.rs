@@ -0,0 +1,162 @@
+use parking_lot::{Condvar, Mutex};
+use rust_spp::*;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+//An unbounded queue that counts the values that go through it
+#[derive(Default)]
+struct CountingQueue<T> {
+    items: Mutex<(u64, VecDeque<TimestampedWorkItem<T>>)>,
+    ready: Condvar,
+    values: AtomicUsize,
+}
+
+impl<T> CountingQueue<T> {
+    fn push(&self, item: TimestampedWorkItem<T>) {
+        if let WorkItem::Value(_) = item.0 {
+            self.values.fetch_add(1, Ordering::SeqCst);
+        }
+        self.items.lock().1.push_back(item);
+        self.ready.notify_one();
+    }
+}
+
+impl<T> WorkStorage<T> for CountingQueue<T> {
+    fn len(&self) -> usize {
+        self.items.lock().1.len()
+    }
+
+    fn enqueue(&self, item: WorkItem<T>) -> u64 {
+        let order = {
+            let mut items = self.items.lock();
+            items.0 += 1;
+            items.0 - 1
+        };
+        self.push(TimestampedWorkItem::posted(item, order));
+        order
+    }
+
+    fn enqueue_timestamped(&self, item: TimestampedWorkItem<T>) {
+        self.push(item);
+    }
+
+    fn wait_and_dequeue(&self) -> TimestampedWorkItem<T> {
+        let mut items = self.items.lock();
+        loop {
+            if let Some(item) = items.1.pop_front() {
+                return item;
+            }
+            self.ready.wait(&mut items);
+        }
+    }
+
+    fn try_dequeue(&self) -> Option<TimestampedWorkItem<T>> {
+        self.items.lock().1.pop_front()
+    }
+}
+
+//An ordered storage that counts the items that went through it
+struct CountingSet<T> {
+    set: Arc<BlockingOrderedSet<T>>,
+    items: AtomicUsize,
+}
+
+impl<T> OrderedWorkStorage<T> for CountingSet<T> {
+    fn len(&self) -> usize {
+        self.set.len()
+    }
+
+    fn enqueue(&self, item: TimestampedWorkItem<T>) {
+        self.items.fetch_add(1, Ordering::SeqCst);
+        self.set.enqueue(item);
+    }
+
+    fn wait_and_remove(&self, order: &Order) -> TimestampedWorkItem<T> {
+        self.set.wait_and_remove(order)
+    }
+}
+
+#[test]
+fn first_stage_takes_its_items_from_the_given_storage() {
+    let queue = Arc::new(CountingQueue::default());
+    let pipeline = pipeline![
+        stored!(parallel!(|item: u64| Some(item * 2), 4), {
+            let queue = queue.clone();
+            move |_| queue.clone()
+        }),
+        collect!()
+    ];
+    assert!(pipeline.describe().stages[0]
+        .queue
+        .contains("custom storage"));
+    let mut collected = pipeline.run_iter(0..200u64).unwrap();
+    collected.sort_unstable();
+
+    assert_eq!(collected, (0..200).map(|item| item * 2).collect::<Vec<_>>());
+    assert_eq!(queue.values.load(Ordering::SeqCst), 200);
+}
+
+#[test]
+fn inner_stages_get_items_from_the_previous_stage_through_the_storage() {
+    let queue = Arc::new(CountingQueue::default());
+    let pipeline = pipeline![
+        parallel!(|item: u64| Some(item + 1), 4),
+        stored!(sequential!(|item: u64| Some(item)), {
+            let queue = queue.clone();
+            move |_| queue.clone()
+        }),
+        collect_ordered!()
+    ];
+    let collected = pipeline.run_iter(0..200u64).unwrap();
+
+    assert_eq!(collected, (1..201).collect::<Vec<_>>());
+    assert_eq!(queue.values.load(Ordering::SeqCst), 200);
+}
+
+#[test]
+fn farms_with_a_queue_per_replica_build_one_storage_each() {
+    let built = Arc::new(AtomicUsize::new(0));
+    let pipeline = pipeline![
+        stored!(
+            balanced!(
+                parallel!(|item: u64| Some(item), 3),
+                LoadBalancing::RoundRobin
+            ),
+            {
+                let built = built.clone();
+                move |_| {
+                    built.fetch_add(1, Ordering::SeqCst);
+                    Arc::new(CountingQueue::default())
+                }
+            }
+        ),
+        collect!()
+    ];
+    let mut collected = pipeline.run_iter(0..90u64).unwrap();
+    collected.sort_unstable();
+
+    assert_eq!(collected, (0..90).collect::<Vec<_>>());
+    assert_eq!(built.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn ordered_stages_wait_for_their_items_in_the_given_ordered_storage() {
+    let set = Arc::new(CountingSet {
+        set: BlockingOrderedSet::new(),
+        items: AtomicUsize::new(0),
+    });
+    let pipeline = pipeline![
+        parallel!(|item: u64| Some(item), 8),
+        stored_ordered!(sequential_ordered!(|item: u64| Some(item)), {
+            let set = set.clone();
+            move || set.clone()
+        }),
+        collect!()
+    ];
+    let collected = pipeline.run_iter(0..300u64).unwrap();
+
+    assert_eq!(collected, (0..300).collect::<Vec<_>>());
+    //the Stop goes through it too
+    assert_eq!(set.items.load(Ordering::SeqCst), 301);
+}
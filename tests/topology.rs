@@ -0,0 +1,153 @@
+use rust_spp::*;
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[test]
+fn describe_lists_how_every_stage_was_built() {
+    let pipeline = pipeline![
+        parallel!("load", |item: u64| Some(item), 4),
+        sequential!(|item: u64| Some(item)),
+        bounded!(parallel!("resize", |item: u64| Some(item), 2), 16),
+        named!(collect_ordered!(), "collect")
+    ];
+    let stages = pipeline.describe().stages;
+
+    let summary: Vec<_> = stages
+        .iter()
+        .map(|stage| {
+            (
+                stage.index,
+                stage.name.as_deref(),
+                stage.mode,
+                stage.replicas,
+                stage.sink,
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (0, Some("load"), BlockMode::Parallel(4), 4, false),
+            (
+                1,
+                None,
+                BlockMode::Sequential(OrderingMode::Unordered),
+                1,
+                false
+            ),
+            (2, Some("resize"), BlockMode::Parallel(2), 2, false),
+            (
+                3,
+                Some("collect"),
+                BlockMode::Sequential(OrderingMode::Ordered),
+                1,
+                true
+            ),
+        ]
+    );
+    //the first stage can be posted to from any thread
+    assert_eq!(stages[0].queue, "blocking queue");
+    assert_eq!(stages[1].queue, "lock-free mpsc queue");
+    assert_eq!(stages[2].queue, "lock-free spmc queue");
+    assert_eq!(stages[2].capacity, Some(16));
+    assert_eq!(stages[3].queue, "ordered set");
+    assert!(pipeline.collect().unwrap().is_empty());
+}
+
+#[test]
+fn links_between_farms_keep_the_blocking_queue_unless_asked() {
+    let pipeline = pipeline![
+        sequential!(|item: u64| Some(item)),
+        parallel!(|item: u64| Some(item), 2),
+        parallel!(|item: u64| Some(item), 2),
+        lock_free!(parallel!(|item: u64| Some(item), 2)),
+        blocking!(sequential!(|item: u64| Some(item))),
+        collect!()
+    ];
+    let queues: Vec<_> = pipeline
+        .describe()
+        .stages
+        .into_iter()
+        .map(|stage| stage.queue)
+        .collect();
+
+    assert_eq!(
+        queues,
+        vec![
+            "blocking queue",
+            "lock-free spmc queue",
+            "blocking queue",
+            "lock-free mpmc queue",
+            "blocking queue",
+            "lock-free spsc queue",
+        ]
+    );
+    let mut collected = pipeline.run_iter(0..100u64).unwrap();
+    collected.sort_unstable();
+    assert_eq!(collected, (0..100).collect::<Vec<_>>());
+}
+
+#[test]
+fn printed_topology_has_a_line_per_stage() {
+    let pipeline = pipeline![
+        parallel!("load", |item: u64| Some(item), 4),
+        sequential!(|item: u64| Some(item)),
+        collect!()
+    ];
+    let printed = pipeline.describe().to_string();
+
+    assert_eq!(
+        printed.lines().collect::<Vec<_>>(),
+        vec![
+            "load: parallel x4, blocking queue",
+            "stage 1: sequential, lock-free mpsc queue",
+            "stage 2: sequential sink, lock-free spsc queue",
+        ]
+    );
+    assert!(pipeline.collect().unwrap().is_empty());
+}
+
+#[test]
+fn dot_graph_links_the_stages_in_order() {
+    let pipeline = pipeline![
+        parallel!("say \"hi\"", |item: u64| Some(item), 2),
+        collect!()
+    ];
+    let dot = pipeline.describe().to_dot();
+
+    assert!(dot.starts_with("digraph pipeline {\n"));
+    assert!(dot.contains(r#"stage0 [label="say \"hi\"\nparallel x2\nblocking queue"];"#));
+    assert!(dot.contains(
+        "stage1 [label=\"stage 1\\nsequential\\nlock-free mpsc queue\", peripheries=2];"
+    ));
+    assert!(dot.contains("stage0 -> stage1;"));
+    assert!(dot.ends_with("}\n"));
+    assert!(pipeline.collect().unwrap().is_empty());
+}
+
+#[test]
+fn replica_threads_are_named_after_their_stage() {
+    let names = Arc::new(Mutex::new(BTreeSet::new()));
+    let record = |names: &Arc<Mutex<BTreeSet<String>>>| {
+        let names = names.clone();
+        move |item: u64| {
+            let name = thread::current().name().unwrap_or("").to_string();
+            names.lock().unwrap().insert(name);
+            Some(item)
+        }
+    };
+    let (load_names, stage_names) = (names.clone(), names.clone());
+    let pipeline = pipeline![
+        parallel!("load", record(&load_names), 2),
+        sequential!(record(&stage_names)),
+        collect!()
+    ];
+    pipeline.run_iter(0..200u64).unwrap();
+
+    let names = names.lock().unwrap();
+    assert!(names
+        .iter()
+        .all(|name| name.starts_with("load-") || name == "stage1-0"));
+    assert!(names.contains("stage1-0"));
+}
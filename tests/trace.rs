@@ -0,0 +1,138 @@
+mod common;
+
+use common::jitter;
+use rust_spp::*;
+use std::fs;
+use std::thread;
+use std::time::Duration;
+
+//Runs the items through and returns the trace written at the end
+fn trace_of<T: Send + 'static>(
+    mut pipeline: Pipeline<u64, T>,
+    items: std::ops::Range<u64>,
+) -> String {
+    for item in items {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.end_and_wait().unwrap();
+    let mut trace = vec![];
+    pipeline.write_trace(&mut trace).unwrap();
+    String::from_utf8(trace).unwrap()
+}
+
+//The events of the track with the given name
+fn events_of<'a>(trace: &'a str, track: &str) -> Vec<&'a str> {
+    let tid = trace
+        .lines()
+        .find(|line| line.contains(&format!("\"name\":\"{track}\"")))
+        .and_then(|line| line.split("\"tid\":").nth(1))
+        .and_then(|rest| rest.split(',').next())
+        .unwrap_or_else(|| panic!("no track named {track}"))
+        .to_string();
+    trace
+        .lines()
+        .filter(|line| line.contains("\"ph\":\"X\"") && line.contains(&format!("\"tid\":{tid},")))
+        .collect()
+}
+
+#[test]
+fn every_item_processed_shows_up_once_per_stage() {
+    let pipeline = pipeline![
+        named!(
+            parallel!(
+                |item: u64| {
+                    jitter(item);
+                    Some(item)
+                },
+                2
+            ),
+            "jitter"
+        ),
+        named!(sequential_ordered!(|item: u64| Some(item)), "order"),
+        collect!()
+    ]
+    .with_trace();
+    let trace = trace_of(pipeline, 0..40);
+
+    assert!(trace.starts_with("{\"displayTimeUnit\":\"ns\",\"traceEvents\":["));
+    assert!(trace.trim_end().ends_with("]}"));
+    let processed = |track: &str| {
+        events_of(&trace, track)
+            .into_iter()
+            .filter(|event| event.contains("\"name\":\"process\""))
+            .count()
+    };
+    assert_eq!(
+        processed("jitter replica 0") + processed("jitter replica 1"),
+        40
+    );
+    assert_eq!(processed("order replica 0"), 40);
+    //the sink is on a track of its own
+    assert_eq!(processed("stage 2 replica 0"), 40);
+}
+
+#[test]
+fn ordered_stage_waits_on_the_item_it_needs() {
+    let pipeline = pipeline![
+        parallel!(
+            |item: u64| {
+                if item == 0 {
+                    thread::sleep(Duration::from_millis(50));
+                }
+                Some(item)
+            },
+            4
+        ),
+        named!(sequential_ordered!(|item: u64| Some(item)), "order"),
+        collect!()
+    ]
+    .with_trace();
+    let trace = trace_of(pipeline, 0..20);
+
+    let waits: Vec<&str> = events_of(&trace, "order replica 0")
+        .into_iter()
+        .filter(|event| event.contains("\"name\":\"wait\""))
+        .collect();
+    assert!(waits
+        .iter()
+        .any(|event| event.contains("\"args\":{\"item\":0}")));
+}
+
+#[test]
+fn nothing_is_recorded_without_with_trace() {
+    let pipeline = pipeline![parallel!(|item: u64| Some(item), 2), collect!()];
+    let trace = trace_of(pipeline, 0..20);
+
+    assert!(trace.contains("\"name\":\"thread_name\""));
+    assert!(!trace.contains("\"ph\":\"X\""));
+}
+
+#[test]
+fn stage_names_are_escaped() {
+    let pipeline = pipeline![
+        named!(sequential!(|item: u64| Some(item)), "say \"hi\""),
+        collect!()
+    ]
+    .with_trace();
+    let trace = trace_of(pipeline, 0..2);
+
+    assert!(trace.contains(r#""args":{"name":"say \"hi\" replica 0"}"#));
+}
+
+#[test]
+fn save_trace_writes_the_same_trace_to_a_file() {
+    let mut pipeline = pipeline![sequential!(|item: u64| Some(item)), collect!()].with_trace();
+    for item in 0..5 {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.end_and_wait().unwrap();
+
+    let path = std::env::temp_dir().join(format!("rust-spp-trace-{}.json", std::process::id()));
+    pipeline.save_trace(&path).unwrap();
+    let saved = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    let mut written = vec![];
+    pipeline.write_trace(&mut written).unwrap();
+    assert_eq!(saved.as_bytes(), &written[..]);
+}
@@ -0,0 +1,136 @@
+mod common;
+
+use common::{jitter, run_within, Gate};
+use rust_spp::*;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn slow_item_behind_two_farms_does_not_deadlock_the_window() {
+    let collected = run_within(|| {
+        let pipeline = pipeline![
+            parallel!(
+                |item: u64| {
+                    if item == 0 {
+                        thread::sleep(Duration::from_millis(200));
+                    }
+                    Some(item)
+                },
+                4
+            ),
+            parallel!(
+                |item: u64| {
+                    jitter(item);
+                    Some(item * 2)
+                },
+                2
+            ),
+            windowed!(collect_ordered!(), 8)
+        ];
+        pipeline.run_iter(0..500u64).unwrap()
+    });
+    assert_eq!(collected, (0..500).map(|item| item * 2).collect::<Vec<_>>());
+}
+
+#[test]
+fn posts_wait_while_the_window_is_full() {
+    let gate = Gate::new();
+    let stage_gate = gate.clone();
+    let pipeline = pipeline![
+        parallel!(stage_gate.hold_first(), 4),
+        windowed!(collect_ordered!(), 8)
+    ];
+    for item in 0..8u64 {
+        pipeline.try_post(item).unwrap();
+    }
+    let refused = pipeline.try_post(8);
+    let live = pipeline.live_tokens();
+    gate.open();
+
+    assert!(matches!(refused, Err(TryPostError::Full(8))));
+    assert_eq!(live, 8);
+    for item in 8..50u64 {
+        pipeline.post(item).unwrap();
+    }
+    assert_eq!(pipeline.collect().unwrap(), (0..50).collect::<Vec<_>>());
+}
+
+#[test]
+fn every_window_holds_posts_back() {
+    let gate = Gate::new();
+    let stage_gate = gate.clone();
+    let pipeline = pipeline![
+        parallel!(stage_gate.hold_first(), 4),
+        windowed!(sequential_ordered!(|item: u64| Some(item)), 16),
+        windowed!(collect_ordered!(), 4)
+    ]
+    .with_max_live_tokens(32);
+    for item in 0..4u64 {
+        pipeline.try_post(item).unwrap();
+    }
+    let refused = pipeline.try_post(4);
+    gate.open();
+
+    assert!(matches!(refused, Err(TryPostError::Full(4))));
+    for item in 4..40u64 {
+        pipeline.post(item).unwrap();
+    }
+    assert_eq!(pipeline.collect().unwrap(), (0..40).collect::<Vec<_>>());
+}
+
+#[test]
+fn stages_after_the_window_are_not_held_back_by_it() {
+    let gate = Gate::new();
+    let stage_gate = gate.clone();
+    let (live, mut collected) = run_within(move || {
+        let pipeline = pipeline![
+            windowed!(sequential_ordered!(|item: u64| Some(item)), 2),
+            parallel!(stage_gate.stage::<u64>(), 4),
+            collect!()
+        ];
+        for item in 0..8u64 {
+            pipeline.post(item).unwrap();
+        }
+        gate.wait_entered(4);
+        let live = pipeline.live_tokens();
+        gate.open();
+        (live, pipeline.collect().unwrap())
+    });
+
+    assert_eq!(live, 8);
+    collected.sort_unstable();
+    assert_eq!(collected, (0..8).collect::<Vec<_>>());
+}
+
+#[test]
+fn ring_given_to_stored_ordered_keeps_items_past_the_window() {
+    let collected = run_within(|| {
+        let pipeline = pipeline![
+            parallel!(
+                |item: u64| {
+                    if item == 0 {
+                        thread::sleep(Duration::from_millis(100));
+                    }
+                    Some(item)
+                },
+                4
+            ),
+            stored_ordered!(collect_ordered!(), || BlockingOrderedRing::new(2))
+        ];
+        pipeline.run_iter(0..200u64).unwrap()
+    });
+    assert_eq!(collected, (0..200).collect::<Vec<_>>());
+}
+
+#[test]
+fn windowed_stage_is_described_as_a_ring() {
+    let pipeline = pipeline![
+        parallel!(|item: u64| Some(item), 4),
+        windowed!(collect_ordered!(), 8)
+    ];
+    assert_eq!(
+        pipeline.describe().stages[1].queue,
+        "ordered ring, window 8"
+    );
+    assert!(pipeline.collect().unwrap().is_empty());
+}